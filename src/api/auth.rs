@@ -1,27 +1,28 @@
 use super::build_http_client;
+use super::error::TidalError;
 use super::models::*;
-use crate::config::{ApiKey, CONFIG};
-use anyhow::anyhow;
+use crate::config::{base_url, ApiKey, Endpoints};
 use anyhow::Error;
 use reqwest::Client;
-use std::collections::HashMap;
 
 #[derive(Clone)]
 pub struct AuthClient {
     client_id: String,
     client_secret: String,
     auth_base: String,
+    api_base: String,
     http: Client,
 }
 
 impl AuthClient {
-    pub fn new(config: ApiKey) -> Self {
-        Self {
+    pub fn new(config: ApiKey, endpoints: &Endpoints) -> Result<Self, Error> {
+        Ok(Self {
             client_id: config.client_id,
             client_secret: config.client_secret,
-            auth_base: "https://auth.tidal.com/v1/oauth2".to_string(),
-            http: build_http_client(),
-        }
+            auth_base: base_url(&endpoints.auth),
+            api_base: base_url(&endpoints.api),
+            http: build_http_client()?,
+        })
     }
 
     pub async fn get_device_code(&self) -> Result<DeviceAuthResponse, Error> {
@@ -40,7 +41,7 @@ impl AuthClient {
             .await?;
 
         if !req.status().is_success() {
-            return Err(Error::msg("Failed to get device code"));
+            return Err(TidalError::from_response(req).await.into());
         }
 
         let device_key = req.json::<DeviceAuthResponse>().await?;
@@ -50,46 +51,44 @@ impl AuthClient {
     pub async fn verify_access_token(&self, access_token: &str) -> Result<bool, Error> {
         let req = self
             .http
-            .get("https://api.tidal.com/v1/sessions")
+            .get(format!("{}/sessions", &self.api_base))
             .bearer_auth(access_token)
             .send()
             .await?;
         Ok(req.status().is_success())
     }
 
-    pub async fn _login_access_token(
-        &self,
-        access_token: &str,
-        user_id: Option<&str>,
-    ) -> Result<(), Error> {
+    /// The session an access token belongs to
+    pub async fn get_session(&self, access_token: &str) -> Result<SessionResponse, Error> {
         let req = self
             .http
-            .get(format!("{}/sessions", &self.auth_base))
+            .get(format!("{}/sessions", &self.api_base))
             .bearer_auth(access_token)
             .send()
-            .await?
-            .json::<HashMap<String, String>>()
             .await?;
-
-        if let Some(uid) = user_id {
-            if let Some(ruid) = req.get("userId") {
-                if ruid != uid {
-                    return Err(Error::msg("User ID mismatch"));
-                }
-            } else {
-                return Err(Error::msg("User ID missing"));
-            }
+        if !req.status().is_success() {
+            return Err(TidalError::from_response(req).await.into());
         }
+        Ok(req.json::<SessionResponse>().await?)
+    }
 
-        {
-            let mut config = CONFIG.write().await;
-            //config.login_key.user_id = Some(req.get("userId"));
-            config.login_key.country_code = Some(req.get("countryCode").unwrap().to_string());
-            config.login_key.access_token = Some(access_token.to_string());
-            config.save()?;
+    pub async fn get_subscription(
+        &self,
+        access_token: &str,
+        user_id: i64,
+        country_code: &str,
+    ) -> Result<SubscriptionResponse, Error> {
+        let req = self
+            .http
+            .get(format!("{}/users/{user_id}/subscription", &self.api_base))
+            .query(&[("countryCode", country_code)])
+            .bearer_auth(access_token)
+            .send()
+            .await?;
+        if !req.status().is_success() {
+            return Err(TidalError::from_response(req).await.into());
         }
-
-        Ok(())
+        Ok(req.json::<SubscriptionResponse>().await?)
     }
 
     pub async fn refresh_access_token(
@@ -107,7 +106,7 @@ impl AuthClient {
 
         let req = self
             .http
-            .post("https://auth.tidal.com/v1/oauth2/token")
+            .post(format!("{}/token", &self.auth_base))
             .body(body)
             .basic_auth(self.client_id.clone(), Some(self.client_secret.clone()))
             .header("Content-Type", "application/x-www-form-urlencoded")
@@ -117,14 +116,14 @@ impl AuthClient {
             let res = req.json::<RefreshResponse>().await?;
             Ok(res)
         } else {
-            Err(Error::msg("Failed to refresh access token"))
+            Err(TidalError::from_response(req).await.into())
         }
     }
 
     pub async fn logout(&self, auth_token: String) -> Result<(), Error> {
         let req = self
             .http
-            .post("https://api.tidal.com/v1/logout")
+            .post(format!("{}/logout", &self.api_base))
             .bearer_auth(auth_token)
             .send()
             .await?;
@@ -132,7 +131,7 @@ impl AuthClient {
         if req.status() == 200 {
             Ok(())
         } else {
-            Err(anyhow!("Failed to Logout"))
+            Err(TidalError::from_response(req).await.into())
         }
     }
 
@@ -0,0 +1,303 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+
+/// Sub statuses TIDAL uses for content that isn't licensed in the country of the login
+const REGION_SUB_STATUSES: [i64; 2] = [4032, 4035];
+/// Sub status TIDAL uses for content that can't be played, such as unreleased albums
+const NOT_STREAMABLE_SUB_STATUS: i64 = 4005;
+/// The `error` the auth server answers an invalid, expired or revoked refresh token with
+const INVALID_GRANT: &str = "invalid_grant";
+
+/// An error response from TIDAL.
+///
+/// Returned inside an `anyhow::Error`, so it can be recovered with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TidalError {
+    /// The login is missing, expired or was revoked
+    Unauthorized {
+        message: String,
+    },
+    NotFound {
+        message: String,
+    },
+    /// The item exists, but can't be streamed with this login
+    NotStreamable {
+        message: String,
+    },
+    /// The item isn't available in the country of the login
+    RegionRestricted {
+        message: String,
+    },
+    /// Too many requests were made. `retry_after` is how long TIDAL asked to wait, if it said
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    Server {
+        status: u16,
+        message: String,
+    },
+    /// Any other error response
+    Other {
+        status: u16,
+        message: String,
+    },
+}
+
+/// TIDAL's error body. The auth server uses snake case and `error_description` instead.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ErrorResponse {
+    #[serde(alias = "sub_status")]
+    sub_status: Option<i64>,
+    /// The OAuth error code of the auth server, such as `invalid_grant`
+    error: Option<String>,
+    #[serde(alias = "error_description")]
+    user_message: Option<String>,
+}
+
+impl TidalError {
+    /// Reads the error from a response that wasn't successful
+    pub async fn from_response(res: Response) -> Self {
+        let status = res.status();
+        let retry_after = retry_after(res.headers());
+        let body = res.text().await.unwrap_or_default();
+        Self::from_body(status, retry_after, &body)
+    }
+
+    /// Classifies an error response by its status and sub status alone.
+    /// The message is only shown, as its wording can change.
+    pub fn from_body(status: StatusCode, retry_after: Option<Duration>, body: &str) -> Self {
+        let body: ErrorResponse = serde_json::from_str(body).unwrap_or_default();
+        let message = body
+            .user_message
+            .filter(|message| !message.is_empty())
+            .unwrap_or_else(|| status.canonical_reason().unwrap_or_default().to_string());
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Self::RateLimited { retry_after };
+        }
+        if status.is_server_error() {
+            return Self::Server {
+                status: status.as_u16(),
+                message,
+            };
+        }
+        let region = body
+            .sub_status
+            .is_some_and(|sub| REGION_SUB_STATUSES.contains(&sub));
+        let not_streamable = body.sub_status == Some(NOT_STREAMABLE_SUB_STATUS);
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN if region => {
+                Self::RegionRestricted { message }
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN if not_streamable => {
+                Self::NotStreamable { message }
+            }
+            StatusCode::UNAUTHORIZED => Self::Unauthorized { message },
+            StatusCode::NOT_FOUND => Self::NotFound { message },
+            // the auth server answers an invalid or revoked refresh token with a bad request
+            StatusCode::BAD_REQUEST if body.error.as_deref() == Some(INVALID_GRANT) => {
+                Self::Unauthorized { message }
+            }
+            _ => Self::Other {
+                status: status.as_u16(),
+                message,
+            },
+        }
+    }
+
+    /// A short name for the kind of error, such as `not_found`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TidalError::Unauthorized { .. } => "unauthorized",
+            TidalError::NotFound { .. } => "not_found",
+            TidalError::NotStreamable { .. } => "not_streamable",
+            TidalError::RegionRestricted { .. } => "region_restricted",
+            TidalError::RateLimited { .. } => "rate_limited",
+            TidalError::Server { .. } => "server",
+            TidalError::Other { .. } => "api",
+        }
+    }
+}
+
+impl fmt::Display for TidalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TidalError::Unauthorized { message } => write!(
+                f,
+                "TIDAL rejected the login: {message}. Run `tdl login` to login again"
+            ),
+            TidalError::NotFound { message } => {
+                write!(f, "Not found on TIDAL: {message}. Check the URL or ID")
+            }
+            TidalError::NotStreamable { message } => write!(
+                f,
+                "Can't be streamed: {message}. It may not be released yet, or need a different subscription"
+            ),
+            TidalError::RegionRestricted { message } => write!(
+                f,
+                "Not available in the country of this login: {message}"
+            ),
+            TidalError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "TIDAL is limiting requests. Try again in {} seconds",
+                retry_after.as_secs()
+            ),
+            TidalError::RateLimited { retry_after: None } => {
+                write!(f, "TIDAL is limiting requests. Try again later")
+            }
+            TidalError::Server { status, message } => write!(
+                f,
+                "TIDAL had a server error ({status}): {message}. Try again later"
+            ),
+            TidalError::Other { status, message } => {
+                write!(f, "TIDAL returned an error ({status}): {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TidalError {}
+
+/// How long a response asks to wait before the next request, from its `Retry-After` header
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, Utc::now())
+}
+
+/// Reads a `Retry-After` value, either a number of seconds or an HTTP date.
+/// A date in the past means no wait
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(status: u16, body: &str) -> TidalError {
+        TidalError::from_body(StatusCode::from_u16(status).unwrap(), None, body)
+    }
+
+    #[test]
+    fn maps_sub_statuses() {
+        let region = r#"{"status":401,"subStatus":4032,"userMessage":"Not allowed"}"#;
+        assert!(matches!(
+            error(401, region),
+            TidalError::RegionRestricted { .. }
+        ));
+        let region = r#"{"status":403,"subStatus":4035,"userMessage":"Not allowed"}"#;
+        assert!(matches!(
+            error(403, region),
+            TidalError::RegionRestricted { .. }
+        ));
+        let not_streamable = r#"{"status":401,"subStatus":4005,"userMessage":"Not allowed"}"#;
+        assert!(matches!(
+            error(401, not_streamable),
+            TidalError::NotStreamable { .. }
+        ));
+        let expired = r#"{"status":401,"subStatus":11002,"userMessage":"Token is invalid"}"#;
+        assert_eq!(
+            error(401, expired),
+            TidalError::Unauthorized {
+                message: "Token is invalid".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn maps_statuses_without_a_body() {
+        assert_eq!(
+            error(404, ""),
+            TidalError::NotFound {
+                message: "Not Found".to_string()
+            }
+        );
+        assert!(matches!(
+            error(503, ""),
+            TidalError::Server { status: 503, .. }
+        ));
+        assert!(matches!(
+            error(418, ""),
+            TidalError::Other { status: 418, .. }
+        ));
+    }
+
+    #[test]
+    fn maps_auth_server_errors() {
+        let revoked = r#"{"status":400,"error":"invalid_grant","sub_status":11101,"error_description":"Refresh token is invalid"}"#;
+        assert!(matches!(
+            error(400, revoked),
+            TidalError::Unauthorized { .. }
+        ));
+        let pending = r#"{"status":400,"error":"authorization_pending","sub_status":1002,"error_description":"Device Authorization code is not authorized yet"}"#;
+        assert!(matches!(
+            error(400, pending),
+            TidalError::Other { status: 400, .. }
+        ));
+    }
+
+    #[test]
+    fn ignores_the_wording_of_messages() {
+        let region =
+            r#"{"status":401,"subStatus":11002,"userMessage":"Not available in your country"}"#;
+        assert!(matches!(
+            error(401, region),
+            TidalError::Unauthorized { .. }
+        ));
+        let token = r#"{"status":400,"subStatus":1001,"userMessage":"Invalid token parameter"}"#;
+        assert!(matches!(
+            error(400, token),
+            TidalError::Other { status: 400, .. }
+        ));
+        let forbidden = r#"{"status":403,"subStatus":1000,"userMessage":"Not ready for playback"}"#;
+        assert!(matches!(
+            error(403, forbidden),
+            TidalError::Other { status: 403, .. }
+        ));
+    }
+
+    #[test]
+    fn keeps_the_wait_of_rate_limits() {
+        let wait = Some(Duration::from_secs(7));
+        assert_eq!(
+            TidalError::from_body(StatusCode::TOO_MANY_REQUESTS, wait, ""),
+            TidalError::RateLimited { retry_after: wait }
+        );
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
@@ -1,9 +1,10 @@
-use super::{models::*, ApiClient};
+use super::{error::TidalError, models::*, ApiClient};
 use anyhow::anyhow;
 use anyhow::Error;
 use std::ops::Deref;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::try_join;
 
 pub struct MediaClient(Arc<ApiClient>);
@@ -34,10 +35,23 @@ impl MediaClient {
     }
 
     pub async fn get_artist(&self, id: &str) -> Result<Artist, Error> {
-        let url = format!("https://api.tidal.com/v1/artists/{id}");
+        let url = format!("{}/artists/{id}", &self.api_base);
         self.get::<Artist>(&url, None).await
     }
-    pub async fn get_stream_url(&self, id: usize) -> Result<PlaybackManifest, Error> {
+    /// Gets the album of a track, along with the artist the album should be filed under
+    pub async fn get_album_artist(&self, track: &Track) -> Result<(Album, Artist), Error> {
+        let album_id = &track.album.id;
+        // The track artist can be different than the album artist
+        // important to use the album artist for naming.
+        // prefer to use that, otherwise default to the track artist
+        let artist_id = match track.album.artist.clone() {
+            Some(val) => val.id.to_string(),
+            None => track.artist.id.to_string(),
+        };
+        try_join!(self.get_album(*album_id), self.get_artist(&artist_id))
+    }
+
+    pub async fn get_stream_url(&self, id: usize) -> Result<PlaybackInfo, Error> {
         let url = format!("{}/tracks/{}/playbackinfopostpaywall", &self.api_base, id);
         let query = &[
             ("audioquality".to_string(), self.audio_quality.to_string()),
@@ -53,13 +67,26 @@ impl MediaClient {
             .await?;
 
         match req.manifest_mime_type.as_str() {
-            "application/vnd.tidal.bts" => Ok(PlaybackManifest::from_str(&req.manifest)?),
+            "application/vnd.tidal.bts" => Ok(PlaybackInfo {
+                audio_quality: req.audio_quality,
+                bit_depth: req.bit_depth,
+                sample_rate: req.sample_rate,
+                manifest: PlaybackManifest::from_str(&req.manifest)?,
+            }),
             _ => Err(Error::msg("Incorrect Mimetype on Response")),
         }
     }
 
+    pub async fn get_album_tracks(&self, id: usize) -> Result<Vec<Track>, Error> {
+        let url = format!("{}/albums/{}/items", &self.api_base, id);
+        let items = self
+            .get_items::<ItemResponseItem<Track>>(&url, None, None)
+            .await?;
+        Ok(items.into_iter().map(|i| i.item).collect())
+    }
+
     pub async fn get_artist_albums(&self, id: &str) -> Result<Vec<Album>, Error> {
-        let url = format!("https://api.tidal.com/v1/artists/{id}/albums");
+        let url = format!("{}/artists/{id}/albums", &self.api_base);
         let mut albums: Vec<Album> = Vec::new();
         let album_req = self.get_items::<Album>(&url, None, None);
         if self.include_singles {
@@ -80,21 +107,43 @@ impl MediaClient {
         Ok(albums)
     }
 
-    fn get_cover_url(id: &str, width: usize, height: usize) -> String {
+    fn get_image_url(&self, kind: &str, id: &str, resolution: &str, extension: &str) -> String {
         format!(
-            "https://resources.tidal.com/images/{}/{}x{}.jpg",
+            "{}/{}/{}/{}.{}",
+            &self.resources_base,
+            kind,
             id.replace('-', "/"),
-            width,
-            height
+            resolution,
+            extension
         )
     }
 
-    pub async fn get_cover_data(&self, id: &str) -> Result<Cover, Error> {
+    pub async fn get_cover_data(&self, id: &str, size: CoverSize) -> Result<Cover, Error> {
+        let url = self.get_image_url("images", id, size.resolution(), "jpg");
+        self.get_image_data(&url).await
+    }
+
+    pub async fn get_artist_picture(&self, id: &str, size: CoverSize) -> Result<Cover, Error> {
+        let url = self.get_image_url("images", id, size.artist_resolution(), "jpg");
+        self.get_image_data(&url).await
+    }
+
+    pub async fn get_video_cover(&self, id: &str, size: CoverSize) -> Result<Cover, Error> {
+        let url = self.get_image_url("videos", id, size.video_resolution(), "mp4");
+        self.get_image_data(&url).await
+    }
+
+    async fn get_image_data(&self, url: &str) -> Result<Cover, Error> {
+        // images are rate limited along with the rest of the API
+        let started = Instant::now();
         let req = self
-            .http_client
-            .get(MediaClient::get_cover_url(id, 1280, 1280))
-            .send()
+            .scheduler
+            .send(|| self.http_client.get(url).send())
             .await?;
+        self.metrics.api_request(started.elapsed());
+        if !req.status().is_success() {
+            return Err(TidalError::from_response(req).await.into());
+        }
 
         let content_type = match req.headers().get("Content-Type") {
             Some(val) => val.to_str()?.to_string(),
@@ -1,47 +1,54 @@
 use std::sync::Arc;
+use std::time::Instant;
 
 use self::{
     media::MediaClient,
     models::{AudioQuality, ItemResponse},
 };
-use crate::config::Settings;
-use anyhow::Error;
+use crate::config::{base_url, Settings};
+use crate::metrics::Metrics;
+use anyhow::{anyhow, Error};
 use http_cache_reqwest::{CACacheManager, Cache, CacheMode, HttpCache, HttpCacheOptions};
 use log::debug;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, StatusCode};
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+use reqwest_retry::{
+    default_on_request_failure, default_on_request_success, policies::ExponentialBackoff,
+    RetryTransientMiddleware, Retryable, RetryableStrategy,
+};
 use serde::de::DeserializeOwned;
 
 pub mod auth;
+pub mod error;
 pub mod media;
 pub mod models;
+pub mod scheduler;
 mod search;
+pub mod session;
 
+use error::TidalError;
+use scheduler::RequestScheduler;
 use search::SearchClient;
+use session::Session;
 
-// Share reqwest client for connection pooling
-lazy_static::lazy_static! {
-    pub static ref CLIENT:Client = build_http_client();
-
-}
+/// Header the cache sets to `HIT` on responses it answered, and `MISS` on the others
+const XCACHE: &str = "x-cache";
 
-fn build_http_client() -> Client {
+pub(crate) fn build_http_client() -> Result<Client, Error> {
     reqwest::Client::builder()
     //use the example chrome useragent from MDN Docs as tidal API's will sometimes fail without it
     .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36 Edg/91.0.864.59")
     .build()
-    .expect("Unable to build Reqwest Client")
+    .map_err(|e| anyhow!("Unable to build the HTTP client: {e}"))
 }
 
-fn build_middleware_client(cache_dir: String) -> ClientWithMiddleware {
+fn build_middleware_client(reqwest: Client, cache_dir: String) -> ClientWithMiddleware {
     debug!("Build Request client");
 
-    let reqwest = build_http_client();
     let retry_policy = ExponentialBackoff {
         max_n_retries: 5,
-        max_retry_interval: std::time::Duration::from_millis(1000),
-        min_retry_interval: std::time::Duration::from_millis(2000),
+        min_retry_interval: std::time::Duration::from_millis(500),
+        max_retry_interval: std::time::Duration::from_millis(8000),
         backoff_exponent: 2,
     };
     let cache_manager = CACacheManager {
@@ -57,55 +64,99 @@ fn build_middleware_client(cache_dir: String) -> ClientWithMiddleware {
     };
 
     ClientBuilder::new(reqwest)
-        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+        .with(RetryTransientMiddleware::new_with_policy_and_strategy(
+            retry_policy,
+            NotRateLimited,
+        ))
         .with(Cache(cache_policy))
         .build()
 }
 
+/// Retries transient failures, except 429s, which are left to the [`RequestScheduler`]
+/// so the wait TIDAL asks for applies to every request
+struct NotRateLimited;
+
+impl RetryableStrategy for NotRateLimited {
+    fn handle(
+        &self,
+        res: &Result<reqwest::Response, reqwest_middleware::Error>,
+    ) -> Option<Retryable> {
+        match res {
+            Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => None,
+            Ok(res) => default_on_request_success(res),
+            Err(e) => default_on_request_failure(e),
+        }
+    }
+}
+
 pub struct TidalClient {
     pub search: SearchClient,
     pub media: MediaClient,
 }
 
 impl TidalClient {
-    pub fn new(config: &Settings) -> Self {
-        let api_client = ApiClient::new(config.clone());
-        Self {
+    pub fn new(config: &Settings, session: Arc<Session>) -> Result<Self, Error> {
+        let api_client = ApiClient::new(config, session)?;
+        Ok(Self {
             search: SearchClient::new(api_client.clone()),
             media: MediaClient::new(api_client),
-        }
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct ApiClient {
-    country_code: (String, String),
-    access_token: String,
+    session: Arc<Session>,
+    scheduler: Arc<RequestScheduler>,
+    metrics: Arc<Metrics>,
     audio_quality: AudioQuality,
     include_singles: bool,
     api_base: String,
+    resources_base: String,
     http_client: ClientWithMiddleware,
+    /// Requests files without the cache
+    file_client: Client,
 }
 
 impl ApiClient {
-    fn new(config: Settings) -> Arc<Self> {
-        Arc::new(Self {
-            country_code: (
-                String::from("countryCode"),
-                config
-                    .login_key
-                    .country_code
-                    .expect("Country code is not set in config"),
-            ),
-            access_token: config
-                .login_key
-                .access_token
-                .expect("Access Token is not present in config"),
-            http_client: build_middleware_client(config.cache_dir),
+    fn new(config: &Settings, session: Arc<Session>) -> Result<Arc<Self>, Error> {
+        let file_client = build_http_client()?;
+        Ok(Arc::new(Self {
+            session,
+            scheduler: Arc::new(RequestScheduler::new()),
+            metrics: Arc::default(),
+            http_client: build_middleware_client(file_client.clone(), config.cache_dir.clone()),
+            file_client,
             include_singles: config.include_singles,
-            api_base: String::from("https://api.tidalhifi.com/v1"),
+            api_base: base_url(&config.endpoints.api),
+            resources_base: base_url(&config.endpoints.resources),
             audio_quality: config.audio_quality,
-        })
+        }))
+    }
+
+    /// The URL of a path of the API, such as `albums/1/items`
+    pub fn api_url(&self, path: &str) -> String {
+        format!("{}/{path}", self.api_base)
+    }
+
+    /// The scheduler pacing the requests of this client
+    pub fn scheduler(&self) -> &RequestScheduler {
+        &self.scheduler
+    }
+
+    /// What this client and the downloads using it have done
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// The login the requests of this client are made with
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Starts a request for a track file, which is too large to be cached
+    pub fn get_file(&self, url: &str) -> RequestBuilder {
+        self.file_client.get(url)
     }
 
     async fn get<'a, T>(&self, url: &'a str, query: Option<&[(String, String)]>) -> Result<T, Error>
@@ -116,16 +167,47 @@ impl ApiClient {
         if let Some(query) = query {
             params.extend(query);
         }
-        params.push(&self.country_code);
-        let req = self
-            .http_client
-            .get(url)
-            .bearer_auth(&self.access_token)
-            .query(&params);
-
-        let result = req.send().await?.text().await?;
+        let country_code = (
+            "countryCode".to_string(),
+            self.session.country_code().await?,
+        );
+        params.push(&country_code);
+        let send = |token: String| {
+            self.http_client
+                .get(url)
+                .bearer_auth(token)
+                .query(&params)
+                .send()
+        };
+
+        let token = self.session.access_token().await?;
+        let started = Instant::now();
+        let mut res = self.scheduler.send(|| send(token.clone())).await?;
+        if res.status() == StatusCode::UNAUTHORIZED {
+            // items that can't be streamed, or aren't available in the region, are a 401 too
+            match TidalError::from_response(res).await {
+                TidalError::Unauthorized { .. } => {
+                    // the token was revoked or expired early, retry once with a new one
+                    let token = self.session.refresh(Some(&token)).await?;
+                    res = self.scheduler.send(|| send(token.clone())).await?;
+                }
+                error => {
+                    self.metrics.api_request(started.elapsed());
+                    return Err(error.into());
+                }
+            }
+        }
+        self.metrics.api_request(started.elapsed());
+        if let Some(cache) = res.headers().get(XCACHE) {
+            self.metrics.cache_lookup(cache == "HIT");
+        }
+        if !res.status().is_success() {
+            return Err(TidalError::from_response(res).await.into());
+        }
+        let result = res.text().await?;
         debug!("{}", result);
-        let result = serde_json::from_str::<T>(&result)?;
+        let result = serde_json::from_str::<T>(&result)
+            .map_err(|e| anyhow!("Unexpected response from {url}: {e}"))?;
         Ok(result)
     }
 
@@ -30,6 +30,30 @@ pub struct DeviceAuthResponse {
     pub interval: u64,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct SessionResponse {
+    pub session_id: String,
+    pub user_id: i64,
+    pub country_code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct SubscriptionResponse {
+    pub status: String,
+    pub valid_until: Option<String>,
+    pub subscription: Subscription,
+    /// Kept as text, as TIDAL adds qualities that tdl doesn't download
+    pub highest_sound_quality: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Subscription {
+    #[serde(rename = "type")]
+    pub tier: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviceAuthRequest {
     pub client_id: String,
@@ -82,6 +106,17 @@ pub struct PlaybackInfoPostPaywallRes {
     pub audio_quality: AudioQuality,
     pub manifest_mime_type: String,
     pub manifest: String,
+    pub bit_depth: Option<u8>,
+    pub sample_rate: Option<u32>,
+}
+
+/// The stream a track will be downloaded from
+#[derive(Debug, Clone)]
+pub struct PlaybackInfo {
+    pub audio_quality: AudioQuality,
+    pub bit_depth: Option<u8>,
+    pub sample_rate: Option<u32>,
+    pub manifest: PlaybackManifest,
 }
 
 pub struct Cover {
@@ -89,6 +124,84 @@ pub struct Cover {
     pub data: Vec<u8>,
 }
 
+#[derive(SerializeDisplay, DeserializeFromStr, Clone, Debug, Copy, PartialEq, Eq)]
+/// Resolution of images requested from the TIDAL resources server.
+/// `Origin` is the original upload and can be several megabytes.
+pub enum CoverSize {
+    Px80,
+    Px160,
+    Px320,
+    Px640,
+    Px1280,
+    Origin,
+}
+
+impl CoverSize {
+    /// Name of the image on the resources server, without an extension
+    pub fn resolution(&self) -> &'static str {
+        match self {
+            CoverSize::Px80 => "80x80",
+            CoverSize::Px160 => "160x160",
+            CoverSize::Px320 => "320x320",
+            CoverSize::Px640 => "640x640",
+            CoverSize::Px1280 => "1280x1280",
+            CoverSize::Origin => "origin",
+        }
+    }
+
+    /// Artist pictures are only published in a different set of sizes,
+    /// so pick the largest one that isn't larger than the requested size,
+    /// or the smallest one for sizes below it.
+    pub fn artist_resolution(&self) -> &'static str {
+        match self {
+            CoverSize::Px80 | CoverSize::Px160 => "160x160",
+            CoverSize::Px320 => "320x320",
+            CoverSize::Px640 => "480x480",
+            CoverSize::Px1280 | CoverSize::Origin => "750x750",
+        }
+    }
+
+    /// Video covers are not available as an origin file.
+    pub fn video_resolution(&self) -> &'static str {
+        match self {
+            CoverSize::Origin => CoverSize::Px1280.resolution(),
+            _ => self.resolution(),
+        }
+    }
+}
+
+impl fmt::Display for CoverSize {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let str = match self {
+            CoverSize::Px80 => "80",
+            CoverSize::Px160 => "160",
+            CoverSize::Px320 => "320",
+            CoverSize::Px640 => "640",
+            CoverSize::Px1280 => "1280",
+            CoverSize::Origin => "origin",
+        };
+        fmt.write_str(str)?;
+        Ok(())
+    }
+}
+
+impl FromStr for CoverSize {
+    type Err = String;
+    fn from_str(input: &str) -> Result<CoverSize, Self::Err> {
+        match input {
+            "80" => Ok(CoverSize::Px80),
+            "160" => Ok(CoverSize::Px160),
+            "320" => Ok(CoverSize::Px320),
+            "640" => Ok(CoverSize::Px640),
+            "1280" => Ok(CoverSize::Px1280),
+            "origin" => Ok(CoverSize::Origin),
+            _ => Err(format!(
+                "Invalid cover size {input}. Expected one of 80, 160, 320, 640, 1280, origin"
+            )),
+        }
+    }
+}
+
 trait Named {
     fn get_name(&self) -> &str;
 }
@@ -137,6 +250,10 @@ pub struct Album {
     #[tabled(skip)]
     pub version: Option<String>,
     #[tabled(skip)]
+    pub upc: Option<String>,
+    #[tabled(skip)]
+    pub copyright: Option<String>,
+    #[tabled(skip)]
     pub cover: Option<String>,
     #[tabled(skip)]
     pub video_cover: Option<String>,
@@ -166,6 +283,8 @@ impl Named for Album {
 pub struct Track {
     pub id: usize,
     pub title: String,
+    #[tabled(skip)]
+    pub version: Option<String>,
     pub duration: usize,
     #[tabled(skip)]
     pub track_number: usize,
@@ -238,7 +357,7 @@ pub struct PlaylistCreator {
     creator_type: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct PlaybackManifest {
     pub mime_type: String,
@@ -277,11 +396,15 @@ impl FromStr for PlaybackManifest {
     }
 }
 
-#[derive(SerializeDisplay, DeserializeFromStr, Clone, Debug, Copy)]
+#[derive(
+    SerializeDisplay, DeserializeFromStr, Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
 ///LOW(96kbps AAC)
 ///HIGH(320kbps AAC)
 ///LOSSLESS(1411kbps|16bit/44.1kHz FLAC/ALAC)
 ///HI_RES(24bit/96kHz MQA encoded FLAC)
+///
+/// Variants are ordered from lowest to highest quality
 pub enum AudioQuality {
     Low,
     High,
@@ -415,7 +538,7 @@ impl FromStr for AssetPresentation {
     }
 }
 
-#[derive(SerializeDisplay, DeserializeFromStr, Debug)]
+#[derive(SerializeDisplay, DeserializeFromStr, Debug, Clone)]
 pub enum EncryptionType {
     None,
 }
@@ -0,0 +1,163 @@
+use super::error::retry_after;
+use reqwest::{Response, StatusCode};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::time::{sleep, Duration, Instant};
+
+/// First wait after being rate limited, when TIDAL doesn't say how long to wait
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait after being rate limited, when TIDAL doesn't say how long to wait
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Times a rate limited request is sent again before the 429 is returned
+pub const MAX_RATE_LIMITED_RETRIES: u32 = 5;
+
+type ThrottleHandler = Box<dyn Fn(Duration) + Send + Sync>;
+/// The handlers of every listener, by the ID of its [`ThrottleListener`]
+type ThrottleHandlers = Mutex<Vec<(u64, ThrottleHandler)>>;
+
+/// Paces the requests of every task sharing a client.
+///
+/// When TIDAL rate limits a request, every request waits, not only the one that was
+/// limited. The wait is `Retry-After` if TIDAL sends it, and otherwise doubles with
+/// each 429 in a row, until a request succeeds.
+pub struct RequestScheduler {
+    state: Mutex<State>,
+    on_throttle: Arc<ThrottleHandlers>,
+    listeners: AtomicU64,
+}
+
+/// Keeps a handler added by [`RequestScheduler::on_throttle`] until it's dropped
+#[must_use = "the handler is removed when the listener is dropped"]
+pub struct ThrottleListener {
+    id: u64,
+    handlers: Weak<ThrottleHandlers>,
+}
+
+impl Drop for ThrottleListener {
+    fn drop(&mut self) {
+        if let Some(handlers) = self.handlers.upgrade() {
+            if let Ok(mut handlers) = handlers.lock() {
+                handlers.retain(|(id, _)| *id != self.id);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Requests wait until this time before they're sent
+    resume_at: Option<Instant>,
+    /// 429 responses since the last request that wasn't limited
+    strikes: u32,
+}
+
+impl Default for RequestScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestScheduler {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            on_throttle: Arc::default(),
+            listeners: AtomicU64::new(0),
+        }
+    }
+
+    /// Calls `handler` with the wait every time requests are paused,
+    /// until the returned listener is dropped. Every download sharing the client adds its own.
+    pub fn on_throttle(
+        &self,
+        handler: impl Fn(Duration) + Send + Sync + 'static,
+    ) -> ThrottleListener {
+        let id = self.listeners.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut on_throttle) = self.on_throttle.lock() {
+            on_throttle.push((id, Box::new(handler)));
+        }
+        ThrottleListener {
+            id,
+            handlers: Arc::downgrade(&self.on_throttle),
+        }
+    }
+
+    /// Sends a request once requests are no longer paused,
+    /// and sends it again if it's rate limited
+    pub async fn send<F, Fut, E>(&self, request: F) -> Result<Response, E>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Response, E>>,
+    {
+        let mut retries = 0;
+        loop {
+            self.ready().await;
+            let res = request().await?;
+            if res.status() != StatusCode::TOO_MANY_REQUESTS {
+                self.succeeded();
+                return Ok(res);
+            }
+            if retries >= MAX_RATE_LIMITED_RETRIES {
+                return Ok(res);
+            }
+            retries += 1;
+            self.rate_limited(retry_after(res.headers()));
+        }
+    }
+
+    /// Waits until requests are no longer paused
+    pub async fn ready(&self) {
+        // another request can extend the pause while this one is waiting
+        while let Some(wait) = self.remaining() {
+            sleep(wait).await;
+        }
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        let state = self.state.lock().ok()?;
+        let wait = state.resume_at?.saturating_duration_since(Instant::now());
+        (!wait.is_zero()).then_some(wait)
+    }
+
+    /// Pauses every request after a 429, and returns how long for
+    pub fn rate_limited(&self, retry_after: Option<Duration>) -> Duration {
+        let (wait, paused) = match self.state.lock() {
+            Ok(mut state) => {
+                let now = Instant::now();
+                // requests that were already sent when the pause started don't count again
+                let paused = state.resume_at.is_some_and(|resume_at| resume_at > now);
+                if !paused {
+                    state.strikes += 1;
+                }
+                let wait = retry_after.unwrap_or_else(|| backoff(state.strikes));
+                let resume_at = now + wait;
+                if state.resume_at.is_none_or(|current| current < resume_at) {
+                    state.resume_at = Some(resume_at);
+                }
+                (wait, paused)
+            }
+            Err(_) => (retry_after.unwrap_or(MIN_BACKOFF), true),
+        };
+        // only a new pause is reported, not every request that runs into it
+        if !paused {
+            if let Ok(on_throttle) = self.on_throttle.lock() {
+                for (_, handler) in on_throttle.iter() {
+                    handler(wait);
+                }
+            }
+        }
+        wait
+    }
+
+    fn succeeded(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.strikes = 0;
+        }
+    }
+}
+
+fn backoff(strikes: u32) -> Duration {
+    let factor = 2u32.saturating_pow(strikes.saturating_sub(1));
+    MIN_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
@@ -1,8 +1,7 @@
-use super::ApiClient;
+use super::{models::Track, ApiClient};
 use anyhow::Error;
 use serde::de::DeserializeOwned;
 use std::{ops::Deref, sync::Arc};
-use tabled::{Table, TableIteratorExt, Tabled};
 
 pub struct SearchClient(Arc<ApiClient>);
 
@@ -21,22 +20,32 @@ impl Deref for SearchClient {
 }
 
 impl SearchClient {
-    pub async fn search_content<'a, T>(
+    /// Finds the tracks released with the given ISRC.
+    /// The same recording can be on several releases, such as a single and an album.
+    pub async fn find_by_isrc(&self, isrc: &str) -> Result<Vec<Track>, Error> {
+        let url = format!("{}/search/tracks", self.api_base);
+        let query = ("query".to_string(), isrc.to_string());
+        let tracks = self
+            .get_items::<Track>(&url, Some(vec![query]), Some(50))
+            .await?
+            .into_iter()
+            .filter(|t| t.isrc.eq_ignore_ascii_case(isrc))
+            .collect();
+        Ok(tracks)
+    }
+
+    /// Searches one kind of item, such as `albums`, returning at most `max` of them
+    pub async fn search<'a, T>(
         &self,
-        url: &str,
+        kind: &str,
         query: &str,
         max: Option<usize>,
-    ) -> Result<Table, Error>
+    ) -> Result<Vec<T>, Error>
     where
-        T: DeserializeOwned + 'a + Tabled,
+        T: DeserializeOwned + 'a,
     {
-        let url = format!("{}/search/{}", self.api_base, url);
+        let url = format!("{}/search/{}", self.api_base, kind);
         let query = ("query".to_string(), query.to_string());
-        let table = self
-            .get_items::<T>(&url, Some(vec![query]), max)
-            .await?
-            .table();
-
-        Ok(table)
+        self.get_items::<T>(&url, Some(vec![query]), max).await
     }
 }
@@ -0,0 +1,145 @@
+use super::auth::AuthClient;
+use super::models::RefreshResponse;
+use crate::config::LoginKey;
+use crate::credentials::CredentialStore;
+use anyhow::{anyhow, Error};
+use log::debug;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+/// Tokens are refreshed this many seconds before they expire,
+/// so a request never starts with a token that expires while it's in flight.
+pub const REFRESH_MARGIN: i64 = 300;
+
+/// The login shared by every request of a client.
+///
+/// The access token is refreshed ahead of its expiry, or when TIDAL rejects it,
+/// and the refreshed login is saved to the credential store.
+pub struct Session {
+    auth: AuthClient,
+    login: RwLock<LoginKey>,
+    credentials: Arc<dyn CredentialStore>,
+    /// Whether the login may have values from the environment, which `save` keeps out of the store
+    env_overrides: bool,
+    /// Held while refreshing, so concurrent requests only refresh once
+    refreshing: Mutex<()>,
+}
+
+fn expires_soon(login: &LoginKey) -> bool {
+    let expires_after = login.expires_after.unwrap_or_default();
+    expires_after > 0 && chrono::Utc::now().timestamp() + REFRESH_MARGIN >= expires_after
+}
+
+impl Session {
+    pub fn new(auth: AuthClient, login: LoginKey, credentials: Arc<dyn CredentialStore>) -> Self {
+        Self {
+            auth,
+            login: RwLock::new(login),
+            credentials,
+            env_overrides: false,
+            refreshing: Mutex::new(()),
+        }
+    }
+
+    /// For a login read from the config of the active profile, which the environment overrides.
+    /// Without it, the environment is never read
+    pub fn with_env_overrides(mut self) -> Self {
+        self.env_overrides = true;
+        self
+    }
+
+    /// The current login
+    pub async fn login(&self) -> LoginKey {
+        self.login.read().await.clone()
+    }
+
+    /// Replaces the login, and saves it to the credential store
+    pub async fn save(&self, login: LoginKey) -> Result<(), Error> {
+        match self.env_overrides {
+            true => login.save_to(self.credentials.as_ref())?,
+            false => self.credentials.save(&login)?,
+        }
+        *self.login.write().await = login;
+        Ok(())
+    }
+
+    /// Replaces the login, and saves it to the credential store as it is,
+    /// including values that were set in the environment
+    pub async fn store(&self, login: LoginKey) -> Result<(), Error> {
+        self.credentials.save(&login)?;
+        *self.login.write().await = login;
+        Ok(())
+    }
+
+    /// Removes the login, and clears the credential store
+    pub async fn clear(&self) -> Result<(), Error> {
+        self.credentials.clear()?;
+        *self.login.write().await = LoginKey::default();
+        Ok(())
+    }
+
+    /// Country the catalogue is requested for, from the login
+    pub async fn country_code(&self) -> Result<String, Error> {
+        self.login
+            .read()
+            .await
+            .country_code
+            .clone()
+            .ok_or_else(|| anyhow!("The login has no country code. Run `tdl login`"))
+    }
+
+    /// Returns an access token that won't expire soon, refreshing it first if needed
+    pub async fn access_token(&self) -> Result<String, Error> {
+        {
+            let login = self.login.read().await;
+            let access_token = login
+                .access_token
+                .clone()
+                .ok_or_else(|| anyhow!("Not logged in. Run `tdl login`"))?;
+            if !expires_soon(&login) {
+                return Ok(access_token);
+            }
+        }
+        self.refresh(None).await
+    }
+
+    /// Refreshes the access token.
+    ///
+    /// `rejected` is the token a request failed with. If another request has
+    /// already replaced it, the new token is returned without refreshing again.
+    pub async fn refresh(&self, rejected: Option<&str>) -> Result<String, Error> {
+        let _refreshing = self.refreshing.lock().await;
+        let mut login = self.login().await;
+        let current = match (rejected, &login.access_token) {
+            (Some(rejected), Some(token)) => token != rejected,
+            (None, Some(_)) => !expires_soon(&login),
+            (_, None) => false,
+        };
+        if let (true, Some(token)) = (current, &login.access_token) {
+            return Ok(token.clone());
+        }
+        let refresh_token = login.refresh_token.clone().ok_or_else(|| {
+            anyhow!("The access token has expired, and there is no refresh token to renew it. Run `tdl login`")
+        })?;
+
+        debug!("Refreshing access token");
+        let refresh = self.auth.refresh_access_token(&refresh_token).await?;
+        let access_token = refresh.access_token.clone();
+        apply_refresh(&mut login, refresh);
+        self.save(login).await?;
+        Ok(access_token)
+    }
+}
+
+/// Updates a login with a refreshed token
+pub fn apply_refresh(login: &mut LoginKey, refresh: RefreshResponse) {
+    let now = chrono::Utc::now().timestamp();
+    login.access_token = Some(refresh.access_token);
+    login.expires_after = Some(refresh.expires_in + now);
+    login.user_id = Some(refresh.user.user_id);
+    login.country_code = Some(refresh.user.country_code);
+    // TIDAL doesn't always rotate the refresh token
+    if let Some(refresh_token) = refresh.refresh_token {
+        login.refresh_token = Some(refresh_token);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::api::models::AudioQuality;
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A track that is already present in the library
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveEntry {
+    pub track_id: usize,
+    pub path: PathBuf,
+    /// Quality of the file on disk, if it is known
+    pub quality: Option<AudioQuality>,
+}
+
+/// Record of every track that has been downloaded or imported,
+/// stored as one JSON entry per line so it can be appended to cheaply.
+///
+/// Later entries for the same track replace earlier ones.
+pub struct Archive {
+    path: PathBuf,
+    entries: Mutex<HashMap<usize, ArchiveEntry>>,
+}
+
+impl Archive {
+    /// Loads the archive at `path`. A missing file is an empty archive.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let contents = std::fs::read_to_string(path)?;
+            for (i, line) in contents.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: ArchiveEntry = serde_json::from_str(line).map_err(|e| {
+                    anyhow!(
+                        "Invalid archive entry on line {} of {}: {e}",
+                        i + 1,
+                        path.display()
+                    )
+                })?;
+                entries.insert(entry.track_id, entry);
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn get(&self, track_id: usize) -> Option<ArchiveEntry> {
+        self.lock().ok()?.get(&track_id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().map(|entries| entries.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds an entry to the archive, and appends it to the archive file
+    pub fn record(&self, entry: ArchiveEntry) -> Result<(), Error> {
+        let mut entries = self.lock()?;
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())?;
+        entries.insert(entry.track_id, entry);
+        Ok(())
+    }
+
+    /// Points the entry of `track_id` at `to` after its file was moved, if it was recorded at `from`
+    pub fn relocate(&self, track_id: usize, from: &Path, to: &Path) -> Result<(), Error> {
+        match self.get(track_id) {
+            Some(entry) if is_same_path(&entry.path, from) => self.record(ArchiveEntry {
+                path: to.to_path_buf(),
+                ..entry
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<usize, ArchiveEntry>>, Error> {
+        self.entries
+            .lock()
+            .map_err(|_| anyhow!("Archive lock poisoned"))
+    }
+}
+
+/// Whether two paths point to the same place, even if one of them is relative
+fn is_same_path(a: &Path, b: &Path) -> bool {
+    a == b
+        || matches!(
+            (std::path::absolute(a), std::path::absolute(b)),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
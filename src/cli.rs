@@ -1,4 +1,4 @@
-use crate::{api::models::AudioQuality, config::CONFIG};
+use crate::{api::models::AudioQuality, config::Settings, login::DeviceLogin};
 use clap::{
     arg,
     builder::{
@@ -8,6 +8,12 @@ use clap::{
     value_parser, Arg, ArgMatches, Command,
 };
 use clap_complete::Shell;
+use console::{measure_text_width, pad_str, style, Emoji, Term};
+use indicatif::TermLike;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration, Instant};
 
 pub fn cli() -> Command<'static> {
     Command::new(env!("CARGO_PKG_NAME"))
@@ -15,17 +21,204 @@ pub fn cli() -> Command<'static> {
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .subcommand_required(true)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .global(true)
+                .takes_value(true)
+                .value_parser(value_parser!(PathBuf))
+                .value_name("PATH")
+                .help("Config file to use instead of ~/.config/tdl/config.toml"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .global(true)
+                .takes_value(true)
+                .value_parser(NonEmptyStringValueParser::new())
+                .value_name("NAME")
+                .help("Profile with its own login, download paths and quality, layered over the config file"),
+        )
         .subcommand(get())
         .subcommand(search())
-        .subcommand(
-            Command::new("login").about("Login or re-authenticates with the current access token"),
-        )
+        .subcommand(login())
+        .subcommand(whoami())
         .subcommand(
             Command::new("logout").about("Logout via the TIDAL API and resets the login config"),
         )
+        .subcommand(relayout())
+        .subcommand(import())
+        .subcommand(serve())
+        .subcommand(config())
         .subcommand(autocomplete())
 }
 
+fn login() -> Command<'static> {
+    Command::new("login")
+        .about("Login or re-authenticates with the current access token")
+        .arg(
+            Arg::new("no-interactive")
+                .long("no-interactive")
+                .help("Print the login link instead of waiting for it. Exits with code 3 until the login is approved, run again once it is"),
+        )
+        .arg(
+            Arg::new("refresh-token")
+                .long("refresh-token")
+                .takes_value(true)
+                .value_parser(NonEmptyStringValueParser::new())
+                .value_name("TOKEN")
+                .conflicts_with_all(&["no-interactive", "from-env"])
+                .help("Login with a refresh token from another login, and store it"),
+        )
+        .arg(
+            Arg::new("from-env")
+                .long("from-env")
+                .conflicts_with("no-interactive")
+                .help("Login with the refresh token in TDL_LOGIN_KEY__REFRESH_TOKEN, and store it"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .takes_value(true)
+                .value_parser(PossibleValuesParser::new(["text", "json"]))
+                .default_value("text")
+                .help("Output format of the login status, when the login doesn't prompt"),
+        )
+        .subcommand(whoami().name("status"))
+}
+
+fn whoami() -> Command<'static> {
+    Command::new("whoami")
+        .about("Shows the account, subscription and token expiry of the current login")
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .takes_value(true)
+                .value_parser(PossibleValuesParser::new(["text", "json"]))
+                .default_value("text")
+                .help("Output format"),
+        )
+}
+
+fn import() -> Command<'static> {
+    Command::new("import")
+        .about("Marks an existing library as downloaded, so get skips the tracks in it")
+        .arg(
+            Arg::new("dir")
+                .takes_value(true)
+                .required(true)
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Folder to search for FLAC and M4A files"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .short('n')
+                .long("dry-run")
+                .help("Print the tracks that would be imported without importing them"),
+        )
+}
+
+fn serve() -> Command<'static> {
+    Command::new("serve")
+        .about("Runs a local web page and REST API to queue downloads, until tdl is stopped")
+        .arg(
+            Arg::new("address")
+                .short('a')
+                .long("address")
+                .takes_value(true)
+                .value_parser(value_parser!(SocketAddr))
+                .default_value("127.0.0.1:8686")
+                .value_name("address")
+                .help(
+                    "Address to listen on. Use 0.0.0.0:8686 to accept other devices on the network",
+                ),
+        )
+}
+
+fn relayout() -> Command<'static> {
+    Command::new("relayout")
+        .about("Moves previously downloaded files to match the current download_paths")
+        .arg(
+            Arg::new("dir")
+                .takes_value(true)
+                .required_unless_present("rollback")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Folder to search for files downloaded by tdl"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .short('n')
+                .long("dry-run")
+                .help("Print the files that would be moved without moving them"),
+        )
+        .arg(
+            Arg::new("rollback")
+                .long("rollback")
+                .takes_value(true)
+                .conflicts_with_all(&["dir", "dry-run"])
+                .value_parser(value_parser!(PathBuf))
+                .value_name("JOURNAL")
+                .help("Undo a previous relayout using the journal it wrote"),
+        )
+}
+
+fn config() -> Command<'static> {
+    let show_secrets = Arg::new("show-secrets")
+        .long("show-secrets")
+        .help("Display access tokens and other secrets instead of masking them");
+    Command::new("config")
+        .about("Inspect and change the configuration")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("show")
+                .about("Prints every setting")
+                .arg(show_secrets.clone()),
+        )
+        .subcommand(
+            Command::new("get")
+                .about("Prints a single setting")
+                .arg(
+                    Arg::new("key")
+                        .required(true)
+                        .value_name("KEY")
+                        .help("Dotted name of the setting, e.g. download_paths.track"),
+                )
+                .arg(show_secrets),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Changes a single setting")
+                .arg(
+                    Arg::new("key")
+                        .required(true)
+                        .value_name("KEY")
+                        .help("Dotted name of the setting, e.g. download_paths.track"),
+                )
+                .arg(
+                    Arg::new("value")
+                        .required(true)
+                        .value_name("VALUE")
+                        .help("New value of the setting"),
+                ),
+        )
+        .subcommand(Command::new("path").about("Prints the location of the config file"))
+        .subcommand(
+            Command::new("reset")
+                .about("Restores the default of a single setting, or of every setting except the login")
+                .arg(
+                    Arg::new("key")
+                        .value_name("KEY")
+                        .help("Dotted name of the setting to reset"),
+                ),
+        )
+        .subcommand(Command::new("validate").about("Checks the config file for mistakes"))
+        .subcommand(
+            Command::new("tokens").about("Lists every token that can be used in path templates"),
+        )
+}
+
 fn get() -> Command<'static> {
     Command::new("get")
         .about("Downloads files from the provided TIDAL links")
@@ -80,6 +273,26 @@ fn get() -> Command<'static> {
                 .value_name("boolish")
                 .help("Display the progress bar when downloading files"),
         )
+        .arg(
+            Arg::new("metrics")
+                .long("metrics")
+                .required(false)
+                .takes_value(true)
+                .display_order(2)
+                .value_parser(value_parser!(SocketAddr))
+                .value_name("address")
+                .help("Serve Prometheus metrics on this address while downloading, such as 127.0.0.1:9184"),
+        )
+        .arg(
+            Arg::new("progress-format")
+                .long("progress")
+                .required(false)
+                .takes_value(true)
+                .display_order(2)
+                .value_parser(PossibleValuesParser::new(["bars", "json", "none"]))
+                .value_name("mode")
+                .help("How to show progress: bars, json lines on stdout, or none. Overrides --show-progress"),
+        )
         .arg(
             Arg::new("singles")
                 .short('s')
@@ -91,6 +304,12 @@ fn get() -> Command<'static> {
                 .value_name("boolish")
                 .help("Include singles with getting lists of albums"),
         )
+        .arg(
+            Arg::new("upgrade")
+                .long("upgrade")
+                .display_order(4)
+                .help("Replace files that were downloaded at a lower quality than the requested quality"),
+        )
 }
 
 fn search() -> Command<'static> {
@@ -146,9 +365,16 @@ fn autocomplete() -> Command<'static> {
         )
 }
 
-pub async fn parse_config_flags(matches: &ArgMatches) {
-    let mut config = CONFIG.write().await;
-    let flags = ["downloads", "workers", "progress", "singles", "quality"];
+/// Applies the flags of the `get` command over the settings
+pub fn parse_config_flags(config: &mut Settings, matches: &ArgMatches) {
+    let flags = [
+        "downloads",
+        "workers",
+        "progress",
+        "singles",
+        "quality",
+        "metrics",
+    ];
     for flag in flags {
         match flag {
             "downloads" => set_val::<u8>(&mut config.downloads, flag, matches),
@@ -156,12 +382,17 @@ pub async fn parse_config_flags(matches: &ArgMatches) {
             "progress" => set_val::<bool>(&mut config.show_progress, flag, matches),
             "singles" => set_val::<bool>(&mut config.include_singles, flag, matches),
             "quality" => set_val::<AudioQuality>(&mut config.audio_quality, flag, matches),
+            "metrics" => {
+                if let Ok(Some(addr)) = matches.try_get_one::<SocketAddr>(flag) {
+                    config.metrics_address = Some(*addr);
+                }
+            }
             _ => continue,
         };
     }
 }
 
-fn set_val<'a, T>(dst: &mut T, flag: &str, matches: &'a ArgMatches)
+fn set_val<T>(dst: &mut T, flag: &str, matches: &ArgMatches)
 where
     T: Send + Sync + Copy + Clone + 'static,
 {
@@ -169,3 +400,84 @@ where
         let _ = std::mem::replace(dst, *v);
     }
 }
+
+/// Shows the link to approve a device login, and the time left to do it,
+/// until the returned task is passed to `hide_prompt`
+pub fn show_prompt(code: &DeviceLogin) -> JoinHandle<()> {
+    let code = code.clone();
+    let instant = Instant::now();
+    let expires_in = code.expires_in();
+    tokio::task::spawn(async move {
+        let clocks = vec![
+            "🕛", "🕐", "🕑", "🕒", "🕓", "🕔", "🕕", "🕖", "🕗", "🕘", "🕙", "🕚",
+        ];
+        let mut animation_index = 0;
+        let term = Term::stdout();
+        term.hide_cursor().ok();
+        let mut interval = interval(Duration::from_millis(83));
+        let login_str = fmt_login(&code.verification_uri_complete);
+        let login_str_width = measure_text_width(&login_str);
+        term.write_line(&login_str).ok();
+        loop {
+            interval.tick().await;
+            // re-calc terminal size every tick
+            let term_width: usize = term.width().into();
+            // calculate the time left in the login prompt at the current tick
+            let sec_left = expires_in.saturating_sub(instant.elapsed().as_secs());
+
+            let mut time_str = format!(
+                "{} {}",
+                // display the current frame of the clock spinning or fallback to empty string
+                Emoji(clocks[animation_index], ""),
+                fmt_time_left(sec_left)
+            );
+
+            // if our terminal is wider than our base text, right align the time
+            if term_width > login_str_width {
+                time_str =
+                    pad_str(&time_str, term_width, console::Alignment::Right, None).to_string();
+            }
+            // clear the last frame and re-draw
+            term.clear_line().ok();
+            term.write_str(&time_str).ok();
+            animation_index += 1;
+            if animation_index > clocks.len() - 1 {
+                animation_index = 0
+            }
+        }
+    })
+}
+
+pub fn hide_prompt(task: JoinHandle<()>) {
+    task.abort();
+    let _ = Term::stdout().show_cursor();
+}
+
+fn fmt_login(url: &str) -> String {
+    // ANSI Hyperlink format.
+
+    format!("Please Login to Tidal: {}", style(url).underlined().bold())
+}
+
+// formats a clickable hyperlink in a terminal
+// https://gist.github.com/egmontkob/eb114294efbcd5adb1944c9f3cb5feda
+// https://en.wikipedia.org/wiki/ANSI_escape_code
+fn _fmt_ansi_url(display: &str, url: &str) -> String {
+    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b", display, url)
+}
+
+fn fmt_time_left(sec_left: u64) -> String {
+    let seconds = sec_left % 60;
+    let mins = (sec_left / 60) % 60;
+
+    format!("{}:{}", pad_zero(mins), pad_zero(seconds))
+}
+
+fn pad_zero(s: impl ToString) -> String {
+    let str = s.to_string();
+    if str == "0" {
+        "00".to_string()
+    } else {
+        str
+    }
+}
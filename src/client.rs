@@ -0,0 +1,225 @@
+use crate::api::auth::AuthClient;
+use crate::api::session::Session;
+use crate::api::TidalClient;
+use crate::config::{get_config, LoginKey, Settings};
+use crate::credentials::{credential_store, pending_login_path, CredentialStore, MemoryStore};
+use crate::download::{
+    consume_channel, dispatch_downloads, event_channel, AbortOnDrop, DownloadSummary, EventSender,
+};
+use crate::library::{
+    import, relayout, relayout_rollback, ImportSummary, LibraryEvent, RelayoutSummary,
+    RollbackSummary,
+};
+use crate::login::PendingLogin;
+use crate::metrics::Metrics;
+use anyhow::Error;
+use futures::future::join_all;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::join;
+
+/// Everything tdl can do, for embedding it into other programs.
+///
+/// It's built from explicit settings, and returns results and events instead of printing them.
+/// Logging in is covered by the methods in [`crate::login`].
+///
+/// ```no_run
+/// # async fn run() -> Result<(), anyhow::Error> {
+/// let tdl = tdl::Tdl::builder().build()?;
+/// tdl.login().await?;
+/// let summary = tdl
+///     .download(vec!["https://tidal.com/browse/album/1".to_string()], false)
+///     .await?;
+/// println!("{} downloaded, {} failed", summary.downloaded, summary.failed.len());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Tdl {
+    pub(crate) settings: Arc<Settings>,
+    pub(crate) auth: AuthClient,
+    pub(crate) session: Arc<Session>,
+    pub(crate) client: Arc<TidalClient>,
+    /// Where a device login waiting for approval is kept between runs
+    pub(crate) pending_login: PendingLogin,
+}
+
+#[derive(Default)]
+pub struct TdlBuilder {
+    settings: Option<Settings>,
+    credentials: Option<Arc<dyn CredentialStore>>,
+    pending_login: Option<PathBuf>,
+    profile: bool,
+}
+
+impl TdlBuilder {
+    /// The settings to use. Without them, the config file of the active profile is read
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Where the login is kept. The login it holds replaces the one in the settings.
+    /// Without it, the credential store of the active profile is used,
+    /// or a [`MemoryStore`] if the settings are given
+    pub fn credentials(mut self, store: impl CredentialStore + 'static) -> Self {
+        self.credentials = Some(Arc::new(store));
+        self
+    }
+
+    /// Where a device login waiting for approval is kept
+    /// by [`Tdl::login_non_interactive`], between runs.
+    /// Without it, the file of the active profile is used, or memory if the settings are given
+    pub fn pending_login_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pending_login = Some(path.into());
+        self
+    }
+
+    /// Keeps the login in the credential store and pending login file of the active profile,
+    /// for settings read by [`get_config`], where the environment can override the login
+    pub fn profile(mut self) -> Self {
+        self.profile = true;
+        self
+    }
+
+    /// Builds the client. With explicit settings, nothing is read from the environment
+    /// or the config folder, unless a credential store or pending login path is given for it
+    pub fn build(self) -> Result<Tdl, Error> {
+        let from_profile = self.profile || self.settings.is_none();
+        let mut settings = match self.settings {
+            Some(settings) => settings,
+            None => get_config()?,
+        };
+        settings.validate()?;
+        let credentials: Arc<dyn CredentialStore> = match self.credentials {
+            Some(credentials) => {
+                if let Some(login) = credentials.load()? {
+                    settings.login_key = login;
+                }
+                credentials
+            }
+            None if from_profile => Arc::from(credential_store()?),
+            None => Arc::new(MemoryStore::default()),
+        };
+        let pending_login = match self.pending_login {
+            Some(path) => PendingLogin::File(path),
+            None if from_profile => PendingLogin::File(pending_login_path()),
+            None => PendingLogin::Memory(Default::default()),
+        };
+
+        let auth = AuthClient::new(settings.api_key.clone(), &settings.endpoints)?;
+        let session = Session::new(auth.clone(), settings.login_key.clone(), credentials);
+        let session = Arc::new(match from_profile {
+            true => session.with_env_overrides(),
+            false => session,
+        });
+        let client = Arc::new(TidalClient::new(&settings, session.clone())?);
+        Ok(Tdl {
+            settings: Arc::new(settings),
+            auth,
+            session,
+            client,
+            pending_login,
+        })
+    }
+}
+
+impl Tdl {
+    pub fn builder() -> TdlBuilder {
+        TdlBuilder::default()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// The client for TIDAL's API, logged in with the current login
+    pub fn client(&self) -> &TidalClient {
+        &self.client
+    }
+
+    /// What this client's downloads and requests have done so far.
+    /// [`crate::metrics::serve`] serves them over HTTP
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.client.media.metrics().clone()
+    }
+
+    /// The current login, including tokens that were refreshed since it was built
+    pub async fn login_key(&self) -> LoginKey {
+        self.session.login().await
+    }
+
+    /// Downloads every track of the TIDAL URLs, and returns once every track is done.
+    ///
+    /// A track that fails doesn't stop the others, it's returned in the summary instead.
+    /// With `upgrade`, files with a lower quality than what's available are replaced.
+    pub async fn download(
+        &self,
+        urls: Vec<String>,
+        upgrade: bool,
+    ) -> Result<DownloadSummary, Error> {
+        let (events, _) = event_channel();
+        self.download_with_events(urls, upgrade, events).await
+    }
+
+    /// Downloads like [`Tdl::download`], and sends what happens to each track to `events`.
+    ///
+    /// The events end once the download is done, when the last sender is dropped.
+    pub async fn download_with_events(
+        &self,
+        urls: Vec<String>,
+        upgrade: bool,
+        events: EventSender,
+    ) -> Result<DownloadSummary, Error> {
+        let (handles, downloads, workers) = dispatch_downloads(
+            urls,
+            self.client.clone(),
+            self.settings.clone(),
+            upgrade,
+            events,
+        )
+        .await?;
+        // dropping the download, to cancel it, stops every task it started
+        let handles = handles.into_iter().map(AbortOnDrop);
+        let (handles, downloads, workers) = join!(
+            join_all(handles),
+            consume_channel(downloads, self.settings.downloads.into()),
+            consume_channel(workers, self.settings.workers.into())
+        );
+        let handles = handles
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|e| Err(e.into())))
+            .collect();
+        Ok(DownloadSummary::new(handles, downloads, workers))
+    }
+
+    /// Moves the tracks under `dir` to the paths the `download_paths` templates give them.
+    /// See [`relayout`]
+    pub async fn relayout(
+        &self,
+        dir: &Path,
+        dry_run: bool,
+        on_event: impl FnMut(LibraryEvent),
+    ) -> Result<RelayoutSummary, Error> {
+        relayout(&self.client, &self.settings, dir, dry_run, on_event).await
+    }
+
+    /// Reverts the moves recorded in a relayout journal. See [`relayout_rollback`]
+    pub async fn relayout_rollback(
+        &self,
+        journal: &Path,
+        on_event: impl FnMut(LibraryEvent),
+    ) -> Result<RollbackSummary, Error> {
+        relayout_rollback(&self.settings, journal, on_event).await
+    }
+
+    /// Records the tracks under `dir` in the archive, so they aren't downloaded again.
+    /// See [`import`]
+    pub async fn import(
+        &self,
+        dir: &Path,
+        dry_run: bool,
+        on_event: impl FnMut(LibraryEvent),
+    ) -> Result<ImportSummary, Error> {
+        import(&self.client, &self.settings, dir, dry_run, on_event).await
+    }
+}
@@ -1,20 +1,30 @@
 use crate::api::models::Album;
 use crate::api::models::Artist;
 use crate::api::models::AudioQuality;
+use crate::api::models::CoverSize;
+use crate::api::models::PlaybackInfo;
 use crate::api::models::Track;
-use anyhow::Error;
-use config::{Config, File, FileFormat};
+use crate::credentials::{credential_store, CredentialStore};
+use crate::sanitize::{FilenameMode, Sanitizer, UnicodeNormalization};
+use crate::template::{Template, TokenSource};
+use anyhow::{anyhow, Error};
+use config::builder::{ConfigBuilder, DefaultState};
+use config::{Config, Environment, File, FileFormat};
+use log::info;
 use phf::phf_map;
-use sanitize_filename::sanitize;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use serde_with::NoneAsEmptyString;
 use std::env::var;
 use std::io::Write;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use tabled::Tabled;
 
+#[serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     pub audio_quality: AudioQuality,
@@ -23,30 +33,62 @@ pub struct Settings {
     pub include_singles: bool,
     pub downloads: u8,
     pub workers: u8,
-    pub download_cover: bool,
     pub cache_dir: String,
+    pub archive_file: String,
+    /// Where metrics are served while tdl runs, if anywhere
+    #[serde_as(as = "NoneAsEmptyString")]
+    pub metrics_address: Option<SocketAddr>,
+    /// The bearer token `tdl serve` requires, if any
+    #[serde_as(as = "NoneAsEmptyString")]
+    pub serve_token: Option<String>,
+    pub cover: CoverSettings,
     pub download_paths: DownloadPathSettings,
     pub login_key: LoginKey,
     pub api_key: ApiKey,
+    pub endpoints: Endpoints,
 }
 
 impl Settings {
-    pub fn save(&self) -> Result<(), Error> {
-        let config_file = get_config_file();
-        let config_dir = get_config_dir();
-        let cache_dir = get_cache_dir();
+    /// The settings as they should be saved over `file`.
+    /// Values from the environment only last as long as the process,
+    /// so whatever `file` had is kept instead.
+    fn persisted_value(&self, file: &toml::Value) -> Result<toml::Value, Error> {
+        let mut value = toml::Value::try_from(self)?;
+        restore_env_overrides(&mut value, file)?;
+        Ok(value)
+    }
+
+    /// The settings as TOML, with secrets masked unless `reveal` is set
+    pub fn to_value(&self, reveal: bool) -> Result<toml::Value, Error> {
+        let mut value = toml::Value::try_from(self)?;
+        if !reveal {
+            for key in SECRETS {
+                if let Some(toml::Value::String(secret)) = get_value_mut(&mut value, key) {
+                    if !secret.is_empty() {
+                        *secret = "********".to_string();
+                    }
+                }
+            }
+        }
+        Ok(value)
+    }
 
-        std::fs::create_dir_all(config_dir)?;
-        std::fs::create_dir_all(cache_dir)?;
+    /// The default settings, without reading the config file, credential store or environment
+    pub fn defaults() -> Result<Self, Error> {
+        Ok(defaults()?.build()?.try_deserialize()?)
+    }
 
-        let mut file = std::fs::File::create(config_file)?;
-        let config_str = toml::to_string_pretty(&self)?;
-        file.write_all(config_str.as_bytes())?;
+    /// Checks the templates, which can't be checked by deserializing alone
+    pub fn validate(&self) -> Result<(), Error> {
+        self.download_paths.validate()?;
+        validate_template("cover.filename", &self.cover.filename, TokenLevel::Album)?;
+        self.endpoints.validate()?;
         Ok(())
     }
 }
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct LoginKey {
     #[serde_as(as = "NoneAsEmptyString")]
     pub device_code: Option<String>,
@@ -60,46 +102,214 @@ pub struct LoginKey {
     pub expires_after: Option<i64>,
 }
 
+impl LoginKey {
+    /// Writes the login to the credential store of the active profile.
+    /// The login is the only state tdl saves on its own.
+    pub fn save(&self) -> Result<(), Error> {
+        self.save_to(credential_store()?.as_ref())
+    }
+
+    /// Writes the login to `store`, keeping what it had for values set in the environment
+    pub fn save_to(&self, store: &dyn CredentialStore) -> Result<(), Error> {
+        let stored = store.load()?.unwrap_or_default();
+        let mut login = self.to_settings_value()?;
+        restore_env_overrides(&mut login, &stored.to_settings_value()?)?;
+        store.save(&login["login_key"].clone().try_into()?)
+    }
+
+    /// The login as the `login_key` section of the settings
+    fn to_settings_value(&self) -> Result<toml::Value, Error> {
+        let mut value = toml::Value::Table(Default::default());
+        insert_value(&mut value, "login_key", toml::Value::try_from(self)?)?;
+        Ok(value)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ApiKey {
     pub client_id: String,
     pub client_secret: String,
 }
+/// Base URLs every TIDAL request is built from,
+/// so tdl can be pointed at a stand-in server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Endpoints {
+    /// Catalogue, playback and account requests
+    pub api: String,
+    /// OAuth device login and token refresh
+    pub auth: String,
+    /// Cover art and artist pictures
+    pub resources: String,
+}
+
+impl Endpoints {
+    fn validate(&self) -> Result<(), Error> {
+        for (key, url) in [
+            ("api", &self.api),
+            ("auth", &self.auth),
+            ("resources", &self.resources),
+        ] {
+            let parsed = reqwest::Url::parse(url)
+                .map_err(|e| anyhow!("endpoints.{key}: {url} is not a URL: {e}"))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(anyhow!(
+                    "endpoints.{key}: {url} has to be an http or https URL"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A base URL, without the trailing slash paths are appended after
+pub fn base_url(url: &str) -> String {
+    url.trim_end_matches('/').to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CoverSettings {
+    pub size: CoverSize,
+    pub mode: CoverMode,
+    pub filename: String,
+    pub max_embed_size: usize,
+    pub artist_picture: bool,
+    pub video_cover: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CoverMode {
+    Embed,
+    Sidecar,
+    Both,
+    None,
+}
+
+impl CoverSettings {
+    /// File name of the sidecar cover in the album folder
+    pub fn get_sidecar_name(
+        &self,
+        sanitizer: &Sanitizer,
+        album: &Album,
+        artist: &Artist,
+    ) -> Result<String, Error> {
+        let ctx = TokenContext::new(sanitizer, Some(artist), Some(album), None);
+        let name = render_template(&self.filename, TokenLevel::Album, &ctx)?;
+        Ok(sanitizer.path(&name, 0))
+    }
+}
+
+impl CoverMode {
+    pub fn embed(&self) -> bool {
+        matches!(self, CoverMode::Embed | CoverMode::Both)
+    }
+    pub fn sidecar(&self) -> bool {
+        matches!(self, CoverMode::Sidecar | CoverMode::Both)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DownloadPathSettings {
     pub base_path: String,
     pub artist: String,
     pub album: String,
+    /// Folder between the album and the track, only used for albums with more than one volume
+    pub disc: String,
     pub track: String,
+    pub on_collision: CollisionMode,
+    pub filename_mode: FilenameMode,
+    /// Maximum length in bytes of a single file or folder name
+    pub max_component_length: usize,
+    pub unicode_normalization: UnicodeNormalization,
+}
+
+/// What to do when two different tracks would be saved to the same path
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionMode {
+    /// Fail the download of the second track
+    Error,
+    /// Append the track ID to the file name of the second track
+    Rename,
 }
 
 impl DownloadPathSettings {
+    /// Parses every path template, so mistakes are reported when the config is loaded
+    /// instead of part way through a download.
+    pub fn validate(&self) -> Result<(), Error> {
+        let templates = [
+            ("artist", &self.artist, TokenLevel::Artist),
+            ("album", &self.album, TokenLevel::Album),
+            ("disc", &self.disc, TokenLevel::Track),
+            ("track", &self.track, TokenLevel::Track),
+        ];
+        for (key, template, level) in templates {
+            validate_template(&format!("download_paths.{key}"), template, level)?;
+        }
+        Ok(())
+    }
+    /// Whether a track path can only be built with the stream of the track
+    pub fn uses_stream_tokens(&self) -> Result<bool, Error> {
+        for template in [&self.disc, &self.track] {
+            let template = Template::parse(template, |name| TokenLevel::Track.is_known(name))?;
+            if template
+                .tokens()
+                .iter()
+                .any(|token| STREAM_TOKEN_MAP.contains_key(token))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
     pub fn get_base_path(&self) -> Result<PathBuf, anyhow::Error> {
         Ok(Path::new("").join(shellexpand::full(&self.base_path)?.to_string()))
     }
+    pub fn sanitizer(&self) -> Sanitizer {
+        Sanitizer {
+            mode: self.filename_mode,
+            max_component_length: self.max_component_length,
+            normalization: self.unicode_normalization,
+        }
+    }
     pub fn get_artist_path(&self, artist: Artist) -> Result<PathBuf, anyhow::Error> {
         let base = &self.get_base_path()?;
-        Ok(base.join(artist.replace_path(&self.artist)))
+        let sanitizer = self.sanitizer();
+        let ctx = TokenContext::new(&sanitizer, Some(&artist), None, None);
+        let path = render_template(&self.artist, TokenLevel::Artist, &ctx)?;
+        Ok(base.join(sanitizer.path(&path, 0)))
     }
     pub fn get_album_path(&self, album: Album, artist: Artist) -> Result<PathBuf, anyhow::Error> {
         let base = &self.get_artist_path(artist.clone())?;
-        let path = artist.replace_path(&self.album);
-        Ok(base.join(album.replace_path(&path)))
+        let sanitizer = self.sanitizer();
+        let ctx = TokenContext::new(&sanitizer, Some(&artist), Some(&album), None);
+        let path = render_template(&self.album, TokenLevel::Album, &ctx)?;
+        Ok(base.join(sanitizer.path(&path, 0)))
     }
     pub fn get_track_path(
         &self,
         track: Track,
         album: Album,
         artist: Artist,
+        stream: Option<&PlaybackInfo>,
     ) -> Result<PathBuf, anyhow::Error> {
-        let base = &self.get_album_path(album.clone(), artist.clone())?;
-        let mut path = track.replace_path(&self.track);
-        path = album.replace_path(&path);
-        path = artist.replace_path(&path);
-        Ok(base.join(path))
+        let mut base = self.get_album_path(album.clone(), artist.clone())?;
+        let sanitizer = self.sanitizer();
+        let ctx = TokenContext::new(&sanitizer, Some(&artist), Some(&album), Some(&track))
+            .with_stream(stream);
+        if album.number_of_volumes.unwrap_or(1) > 1 {
+            let disc = render_template(&self.disc, TokenLevel::Track, &ctx)?;
+            base.push(sanitizer.path(&disc, 0));
+        }
+        let path = render_template(&self.track, TokenLevel::Track, &ctx)?;
+        // leave room for the extension, and a track ID if the name collides
+        Ok(base.join(sanitizer.path(&path, TRACK_NAME_RESERVE)))
     }
 }
 
+/// Bytes kept free at the end of a track's file name
+const TRACK_NAME_RESERVE: usize = 24;
+
 trait UnwrapEmptyString<T: ToString> {
     fn unwrap_empty_string(self) -> String;
 }
@@ -116,26 +326,111 @@ where
     }
 }
 
-pub trait DownloadPath<T>
-where
-    Self: Sized + Clone,
-    T: TokenMap<Self> + 'static + Copy,
-{
-    fn replace_path(self, path: &str) -> String {
-        let map = T::token_map();
-        let mut x = path.to_string();
-        map.entries().for_each(|entry| {
-            if x.contains(entry.0) {
-                x = x.replace(entry.0, &entry.1.get_token(&self));
-            };
-        });
-        x
+/// Which tokens a template may use.
+/// Each level can use its own tokens, and the tokens of every level above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TokenLevel {
+    Artist,
+    Album,
+    Track,
+}
+
+impl TokenLevel {
+    pub fn is_known(&self, name: &str) -> bool {
+        ARTIST_TOKEN_MAP.contains_key(name)
+            || (*self >= TokenLevel::Album && ALBUM_TOKEN_MAP.contains_key(name))
+            || (*self >= TokenLevel::Track
+                && (TRACK_TOKEN_MAP.contains_key(name) || STREAM_TOKEN_MAP.contains_key(name)))
+    }
+
+    pub fn available_tokens(&self) -> Vec<&'static str> {
+        let mut tokens: Vec<&'static str> = ARTIST_TOKEN_MAP.keys().copied().collect();
+        if *self >= TokenLevel::Album {
+            tokens.extend(ALBUM_TOKEN_MAP.keys());
+        }
+        if *self >= TokenLevel::Track {
+            tokens.extend(TRACK_TOKEN_MAP.keys());
+            tokens.extend(STREAM_TOKEN_MAP.keys());
+        }
+        tokens.sort_unstable();
+        tokens
+    }
+}
+
+/// The items a template is rendered against
+pub struct TokenContext<'a> {
+    sanitizer: &'a Sanitizer,
+    artist: Option<&'a Artist>,
+    album: Option<&'a Album>,
+    track: Option<&'a Track>,
+    stream: Option<&'a PlaybackInfo>,
+}
+
+impl<'a> TokenContext<'a> {
+    pub fn new(
+        sanitizer: &'a Sanitizer,
+        artist: Option<&'a Artist>,
+        album: Option<&'a Album>,
+        track: Option<&'a Track>,
+    ) -> Self {
+        Self {
+            sanitizer,
+            artist,
+            album,
+            track,
+            stream: None,
+        }
+    }
+
+    pub fn with_stream(mut self, stream: Option<&'a PlaybackInfo>) -> Self {
+        self.stream = stream;
+        self
+    }
+}
+
+impl TokenSource for TokenContext<'_> {
+    fn resolve(&self, name: &str) -> Option<String> {
+        if let (Some(token), Some(artist)) = (ARTIST_TOKEN_MAP.get(name), self.artist) {
+            return Some(token.get_token(artist, self.sanitizer));
+        }
+        if let (Some(token), Some(album)) = (ALBUM_TOKEN_MAP.get(name), self.album) {
+            return Some(token.get_token(album, self.sanitizer));
+        }
+        if let (Some(token), Some(track)) = (TRACK_TOKEN_MAP.get(name), self.track) {
+            return Some(token.get_token(track, self.sanitizer));
+        }
+        if let (Some(token), Some(stream)) = (STREAM_TOKEN_MAP.get(name), self.stream) {
+            return Some(token.get_token(stream, self.sanitizer));
+        }
+        None
+    }
+
+    fn literal(&self, text: &str) -> String {
+        self.sanitizer.literal(text)
+    }
+
+    fn value(&self, value: &str) -> String {
+        self.sanitizer.token(value)
     }
 }
 
-impl DownloadPath<ArtistTokens> for Artist {}
-impl DownloadPath<AlbumTokens> for Album {}
-impl DownloadPath<TrackTokens> for Track {}
+pub fn validate_template(key: &str, template: &str, level: TokenLevel) -> Result<Template, Error> {
+    Template::parse(template, |name| level.is_known(name)).map_err(|e| {
+        anyhow!(
+            "Invalid template for {key} `{template}`: {e}\nAvailable tokens: {}",
+            level.available_tokens().join(", ")
+        )
+    })
+}
+
+pub fn render_template(
+    template: &str,
+    level: TokenLevel,
+    ctx: &TokenContext,
+) -> Result<String, Error> {
+    let template = Template::parse(template, |name| level.is_known(name))?;
+    Ok(template.render(ctx))
+}
 
 pub trait TokenMap<T>
 where
@@ -144,12 +439,15 @@ where
 {
     fn token_map() -> &'static phf::Map<&'static str, Self>;
 
-    fn get_token(self, _: &T) -> String;
+    fn get_token(self, _: &T, sanitizer: &Sanitizer) -> String;
+
+    /// Description and an example value, shown by `tdl config tokens`
+    fn describe(self) -> (&'static str, &'static str);
 }
 
 static ARTIST_TOKEN_MAP: phf::Map<&'static str, ArtistTokens> = phf_map! {
-    "{artist_name}" =>  ArtistTokens::Name,
-    "{artist_id}" => ArtistTokens::ID
+    "artist_name" =>  ArtistTokens::Name,
+    "artist_id" => ArtistTokens::ID
 };
 
 #[derive(Clone, Copy)]
@@ -163,36 +461,51 @@ impl TokenMap<Artist> for ArtistTokens {
         &ARTIST_TOKEN_MAP
     }
 
-    fn get_token(self, a: &Artist) -> String {
+    fn get_token(self, a: &Artist, sanitizer: &Sanitizer) -> String {
         let val = match self {
             ArtistTokens::ID => a.id.to_string(),
             ArtistTokens::Name => a.name.to_string(),
         };
-        sanitize(val)
+        sanitizer.token(&val)
+    }
+
+    fn describe(self) -> (&'static str, &'static str) {
+        match self {
+            ArtistTokens::ID => ("Unique ID from Tidal", "10828611"),
+            ArtistTokens::Name => ("Name of the album artist", "100 gecs"),
+        }
     }
 }
 
 static ALBUM_TOKEN_MAP: phf::Map<&'static str, AlbumTokens> = phf_map! {
-    "{album_id}" => AlbumTokens::ID,
-    "{album_name}" => AlbumTokens::Title,
-    "{album_duration}" => AlbumTokens::Duration,
-    "{album_tracks}" => AlbumTokens::NumberOfTracks,
-    "{album_explicit}" => AlbumTokens::Explicit,
-    "{album_quality}" => AlbumTokens::AudioQuality,
-    "{album_release}" => AlbumTokens::ReleaseDate,
-    "{album_release_year}" => AlbumTokens::ReleaseYear,
+    "album_id" => AlbumTokens::ID,
+    "album_name" => AlbumTokens::Title,
+    "album_duration" => AlbumTokens::Duration,
+    "album_tracks" => AlbumTokens::NumberOfTracks,
+    "album_volumes" => AlbumTokens::NumberOfVolumes,
+    "album_explicit" => AlbumTokens::Explicit,
+    "album_quality" => AlbumTokens::AudioQuality,
+    "album_release" => AlbumTokens::ReleaseDate,
+    "album_release_year" => AlbumTokens::ReleaseYear,
+    "album_version" => AlbumTokens::Version,
+    "album_type" => AlbumTokens::Type,
+    "album_upc" => AlbumTokens::UPC,
+    "album_copyright" => AlbumTokens::Copyright,
+    "album_artist" => AlbumTokens::Artist,
+    "album_artists" => AlbumTokens::Artists,
 };
 impl TokenMap<Album> for AlbumTokens {
     fn token_map() -> &'static phf::Map<&'static str, Self> {
         &ALBUM_TOKEN_MAP
     }
 
-    fn get_token(self, a: &Album) -> String {
+    fn get_token(self, a: &Album, sanitizer: &Sanitizer) -> String {
         let a = match self {
             AlbumTokens::ID => a.id.to_string(),
             AlbumTokens::Title => a.title.as_ref().unwrap_empty_string(),
             AlbumTokens::Duration => a.duration.unwrap_empty_string(),
             AlbumTokens::NumberOfTracks => a.number_of_tracks.unwrap_empty_string(),
+            AlbumTokens::NumberOfVolumes => a.number_of_volumes.unwrap_empty_string(),
             AlbumTokens::Explicit => match a.explicit.unwrap_or(false) {
                 true => String::from("E"),
                 false => String::new(),
@@ -206,8 +519,34 @@ impl TokenMap<Album> for AlbumTokens {
                 .split('-')
                 .next()
                 .unwrap_empty_string(),
+            AlbumTokens::Version => a.version.as_ref().unwrap_empty_string(),
+            AlbumTokens::Type => a.album_type.as_ref().unwrap_empty_string(),
+            AlbumTokens::UPC => a.upc.as_ref().unwrap_empty_string(),
+            AlbumTokens::Copyright => a.copyright.as_ref().unwrap_empty_string(),
+            AlbumTokens::Artist => a.artist.as_ref().map(|a| &a.name).unwrap_empty_string(),
+            AlbumTokens::Artists => join_artists(a.artists.as_deref().unwrap_or_default()),
         };
-        sanitize(a)
+        sanitizer.token(&a)
+    }
+
+    fn describe(self) -> (&'static str, &'static str) {
+        match self {
+            AlbumTokens::ID => ("Unique ID from Tidal", "129835816"),
+            AlbumTokens::Title => ("Album Title", "1000 gecs"),
+            AlbumTokens::Duration => ("Duration in seconds of Album", "3000"),
+            AlbumTokens::NumberOfTracks => ("Number of tracks in Album", "17"),
+            AlbumTokens::NumberOfVolumes => ("Number of discs in Album", "1"),
+            AlbumTokens::Explicit => ("Shortcode if album is explicit, empty if false", "E"),
+            AlbumTokens::AudioQuality => ("Highest quality the album is available in", "HI_RES"),
+            AlbumTokens::ReleaseDate => ("YYYY-MM-DD string of album release date", "2019-05-31"),
+            AlbumTokens::ReleaseYear => ("YYYY string of album release", "2019"),
+            AlbumTokens::Version => ("Edition of the album, empty if none", "Deluxe Edition"),
+            AlbumTokens::Type => ("Kind of release", "ALBUM, EP or SINGLE"),
+            AlbumTokens::UPC => ("Universal Product Code of the release", "0859731543128"),
+            AlbumTokens::Copyright => ("Copyright notice of the album", "2019 Dog Show Records"),
+            AlbumTokens::Artist => ("Main artist credited on the album", "100 gecs"),
+            AlbumTokens::Artists => ("Every artist credited on the album", "100 gecs, Laura Les"),
+        }
     }
 }
 #[derive(Clone, Copy)]
@@ -216,21 +555,32 @@ pub enum AlbumTokens {
     Title,
     Duration,
     NumberOfTracks,
+    NumberOfVolumes,
     Explicit,
     AudioQuality,
     ReleaseDate,
     ReleaseYear,
+    Version,
+    Type,
+    UPC,
+    Copyright,
+    Artist,
+    Artists,
 }
 
 static TRACK_TOKEN_MAP: phf::Map<&'static str, TrackTokens> = phf_map! {
-   "{track_id}" => TrackTokens::ID,
-   "{track_name}" => TrackTokens::Title,
-   "{track_duration}" => TrackTokens::Duration,
-   "{track_num}" => TrackTokens::TrackNumber,
-   "{track_volume}" => TrackTokens::VolumeNumber,
-   "{track_isrc}" => TrackTokens::ISRC,
-   "{track_explicit}" => TrackTokens::Explicit,
-   "{track_quality}" => TrackTokens::AudioQuality,
+   "track_id" => TrackTokens::ID,
+   "track_name" => TrackTokens::Title,
+   "track_duration" => TrackTokens::Duration,
+   "track_num" => TrackTokens::TrackNumber,
+   "track_volume" => TrackTokens::VolumeNumber,
+   "track_isrc" => TrackTokens::ISRC,
+   "track_explicit" => TrackTokens::Explicit,
+   "track_quality" => TrackTokens::AudioQuality,
+   "track_version" => TrackTokens::Version,
+   "track_copyright" => TrackTokens::Copyright,
+   "track_artist" => TrackTokens::Artist,
+   "track_artists" => TrackTokens::Artists,
 };
 
 #[derive(Clone, Copy)]
@@ -243,13 +593,17 @@ pub enum TrackTokens {
     ISRC,
     Explicit,
     AudioQuality,
+    Version,
+    Copyright,
+    Artist,
+    Artists,
 }
 impl TokenMap<Track> for TrackTokens {
     fn token_map() -> &'static phf::Map<&'static str, Self> {
         &TRACK_TOKEN_MAP
     }
 
-    fn get_token(self, v: &Track) -> String {
+    fn get_token(self, v: &Track, sanitizer: &Sanitizer) -> String {
         let a = match self {
             TrackTokens::ID => v.id.to_string(),
             TrackTokens::Title => v.title.clone(),
@@ -262,12 +616,115 @@ impl TokenMap<Track> for TrackTokens {
                 false => String::new(),
             },
             TrackTokens::AudioQuality => v.audio_quality.to_string(),
+            TrackTokens::Version => v.version.as_ref().unwrap_empty_string(),
+            TrackTokens::Copyright => v.copyright.clone(),
+            TrackTokens::Artist => v.artist.name.clone(),
+            TrackTokens::Artists => join_artists(&v.artists),
         };
-        sanitize(a)
+        sanitizer.token(&a)
+    }
+
+    fn describe(self) -> (&'static str, &'static str) {
+        match self {
+            TrackTokens::ID => ("Unique ID from Tidal", "129835817"),
+            TrackTokens::Title => ("Name of Track", "745 sticky"),
+            TrackTokens::Duration => ("Track Duration in Seconds", "120"),
+            TrackTokens::TrackNumber => ("Number track appears on its disc", "7"),
+            TrackTokens::VolumeNumber => ("Disc number of track", "1"),
+            TrackTokens::ISRC => ("International Standard Recording Code", "QZES71982754"),
+            TrackTokens::Explicit => ("Shortcode if track is explicit, empty if false", "E"),
+            TrackTokens::AudioQuality => ("Highest quality the track is available in", "HI_RES"),
+            TrackTokens::Version => ("Version of the track, empty if none", "Remix"),
+            TrackTokens::Copyright => ("Copyright notice of the track", "2019 Dog Show Records"),
+            TrackTokens::Artist => ("Main artist of the track", "100 gecs"),
+            TrackTokens::Artists => ("Every artist of the track", "100 gecs, Laura Les"),
+        }
     }
 }
 
-pub fn get_config() -> Result<Settings, Error> {
+static STREAM_TOKEN_MAP: phf::Map<&'static str, StreamTokens> = phf_map! {
+   "track_bit_depth" => StreamTokens::BitDepth,
+   "track_sample_rate" => StreamTokens::SampleRate,
+   "track_stream_quality" => StreamTokens::AudioQuality,
+};
+
+/// Tokens describing the file that was actually downloaded,
+/// which can be lower than the quality the track is available in.
+#[derive(Clone, Copy)]
+pub enum StreamTokens {
+    BitDepth,
+    SampleRate,
+    AudioQuality,
+}
+impl TokenMap<PlaybackInfo> for StreamTokens {
+    fn token_map() -> &'static phf::Map<&'static str, Self> {
+        &STREAM_TOKEN_MAP
+    }
+
+    fn get_token(self, v: &PlaybackInfo, sanitizer: &Sanitizer) -> String {
+        let a = match self {
+            StreamTokens::BitDepth => v.bit_depth.unwrap_empty_string(),
+            StreamTokens::SampleRate => v.sample_rate.unwrap_empty_string(),
+            StreamTokens::AudioQuality => v.audio_quality.to_string(),
+        };
+        sanitizer.token(&a)
+    }
+
+    fn describe(self) -> (&'static str, &'static str) {
+        match self {
+            StreamTokens::BitDepth => ("Bit depth of the downloaded file", "24"),
+            StreamTokens::SampleRate => ("Sample rate in Hz of the downloaded file", "96000"),
+            StreamTokens::AudioQuality => ("Quality of the downloaded file", "LOSSLESS"),
+        }
+    }
+}
+
+fn join_artists(artists: &[Artist]) -> String {
+    artists
+        .iter()
+        .map(|a| a.name.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+#[derive(Tabled)]
+pub struct TokenInfo {
+    #[tabled(rename = "Token")]
+    pub token: String,
+    #[tabled(rename = "Usable In")]
+    pub level: &'static str,
+    #[tabled(rename = "Description")]
+    pub description: &'static str,
+    #[tabled(rename = "Example")]
+    pub example: &'static str,
+}
+
+/// Every token available to templates, grouped by level and sorted by name
+pub fn token_reference() -> Vec<TokenInfo> {
+    fn level<T: Clone, M: TokenMap<T> + Copy + 'static>(level: &'static str) -> Vec<TokenInfo> {
+        let mut entries: Vec<_> = M::token_map().entries().collect();
+        entries.sort_by_key(|(name, _)| **name);
+        entries
+            .into_iter()
+            .map(|(name, token)| {
+                let (description, example) = token.describe();
+                TokenInfo {
+                    token: format!("{{{name}}}"),
+                    level,
+                    description,
+                    example,
+                }
+            })
+            .collect()
+    }
+    let mut tokens = level::<Artist, ArtistTokens>("artist, album, disc, track, cover");
+    tokens.extend(level::<Album, AlbumTokens>("album, disc, track, cover"));
+    tokens.extend(level::<Track, TrackTokens>("disc, track"));
+    tokens.extend(level::<PlaybackInfo, StreamTokens>("disc, track"));
+    tokens
+}
+
+fn defaults() -> Result<ConfigBuilder<DefaultState>, Error> {
     let config = Config::builder()
         .set_default("audio_quality", "HI_RES")?
         .set_default("show_progress", true)?
@@ -275,10 +732,19 @@ pub fn get_config() -> Result<Settings, Error> {
         .set_default("progress_refresh_rate", 5)?
         .set_default("login_key.device_code", "")?
         .set_default("login_key.country_code", "")?
-        .set_default("download_cover", true)?
+        .set_default("cover.size", "1280")?
+        .set_default("cover.mode", "both")?
+        .set_default("cover.filename", "cover.jpg")?
+        // FLAC metadata blocks can't be larger than 16MiB
+        .set_default("cover.max_embed_size", 16_777_215)?
+        .set_default("cover.artist_picture", true)?
+        .set_default("cover.video_cover", true)?
         .set_default("downloads", 3)?
         .set_default("workers", 1)?
         .set_default("cache_dir", get_cache_dir())?
+        .set_default("archive_file", get_archive_file())?
+        .set_default("metrics_address", "")?
+        .set_default("serve_token", "")?
         .set_default("login_key.access_token", "")?
         .set_default("login_key.refresh_token", "")?
         .set_default("login_key.expires_after", 0)?
@@ -287,21 +753,457 @@ pub fn get_config() -> Result<Settings, Error> {
             "api_key.client_secret",
             "VJKhDFqJPqvsPVNBV6ukXTJmwlvbttP7wlMlrc72se4=",
         )?
+        .set_default("endpoints.api", "https://api.tidal.com/v1")?
+        .set_default("endpoints.auth", "https://auth.tidal.com/v1/oauth2")?
+        .set_default("endpoints.resources", "https://resources.tidal.com")?
         .set_default("download_paths.base_path", "$HOME/Music")?
         .set_default("download_paths.artist", "{artist_name}")?
         .set_default(
             "download_paths.album",
             "{album_name} [{album_id}] [{album_release_year}] ",
         )?
+        .set_default("download_paths.disc", "Disc {track_volume}")?
         .set_default("download_paths.track", "{track_num} - {track_name}")?
-        .add_source(File::new(CONFIG_FILE.as_str(), FileFormat::Toml).required(false))
-        .build()?;
-    let settings: Settings = config.try_deserialize()?;
-    settings.save()?;
+        .set_default("download_paths.on_collision", "rename")?
+        .set_default(
+            "download_paths.filename_mode",
+            match cfg!(windows) {
+                true => "windows-safe",
+                false => "posix",
+            },
+        )?
+        .set_default("download_paths.max_component_length", 255)?
+        .set_default("download_paths.unicode_normalization", "nfc")?;
+    Ok(config)
+}
 
+/// Builds the settings from the defaults, overridden by the `contents` of a config file
+/// and the login in the credential store
+pub fn parse_config(contents: Option<&str>) -> Result<Settings, Error> {
+    let mut builder = defaults()?;
+    // a profile is layered over the config file it belongs to
+    if config_location().profile.is_some() {
+        let base = &config_location().file;
+        if base.exists() {
+            let base = without_login(&std::fs::read_to_string(base)?)?;
+            builder = builder.add_source(File::from_str(&base, FileFormat::Toml));
+        }
+    }
+    if let Some(contents) = contents {
+        let contents = without_login(contents)?;
+        builder = builder.add_source(File::from_str(&contents, FileFormat::Toml));
+    }
+    if let Some(login) = credential_store()?.load()? {
+        let login = toml::to_string(&login.to_settings_value()?)?;
+        builder = builder.add_source(File::from_str(&login, FileFormat::Toml));
+    }
+    builder = builder.add_source(
+        Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator("_")
+            .separator(ENV_SEPARATOR),
+    );
+    let settings: Settings = builder.build()?.try_deserialize()?;
+    settings.validate()?;
     Ok(settings)
 }
 
+/// Removes the login from the contents of a config file, since it's only read from the credential store,
+/// and maps settings of older versions to the ones that replaced them
+fn without_login(contents: &str) -> Result<String, Error> {
+    let mut file: toml::Value = toml::from_str(contents)?;
+    remove_value(&mut file, "login_key");
+    migrate_download_cover(&mut file)?;
+    Ok(toml::to_string(&file)?)
+}
+
+/// `download_cover = false` from before the cover settings turns covers off,
+/// unless `cover.mode` is set as well
+fn migrate_download_cover(file: &mut toml::Value) -> Result<(), Error> {
+    let download = get_value(file, "download_cover").and_then(toml::Value::as_bool);
+    remove_value(file, "download_cover");
+    if download == Some(false) && get_value(file, "cover.mode").is_none() {
+        insert_value(file, "cover.mode", toml::Value::String("none".to_string()))?;
+    }
+    Ok(())
+}
+
+/// Moves a login saved in the config file by an older version of tdl into the credential store
+fn migrate_login() -> Result<(), Error> {
+    let mut file = read_config_file()?;
+    let login = match file.get("login_key") {
+        Some(login) => login.clone(),
+        None => return Ok(()),
+    };
+    let store = credential_store()?;
+    let login: LoginKey = login.try_into()?;
+    let logged_in = login.access_token.is_some() || login.refresh_token.is_some();
+    if logged_in && store.load()?.is_none() {
+        store.save(&login)?;
+        info!(
+            "Moved the login from {} to the credential store",
+            config_location().active_file().display()
+        );
+    }
+    remove_value(&mut file, "login_key");
+    write_config_file(&toml::to_string_pretty(&file)?)
+}
+
+pub fn get_config() -> Result<Settings, Error> {
+    let location = config_location();
+    // only write the file on the first run, so loading never rewrites what the user wrote
+    if !location.file.exists() {
+        let mut value =
+            parse_config(None)?.persisted_value(&toml::Value::Table(Default::default()))?;
+        remove_value(&mut value, "login_key");
+        write_config_file_to(&location.file, &toml::to_string_pretty(&value)?)?;
+    }
+    let path = location.active_file();
+    if !path.exists() {
+        // a new profile only has its own archive, and inherits everything else from the config file
+        let mut profile = toml::Value::Table(Default::default());
+        let archive = location.related_file("archive.jsonl").display().to_string();
+        insert_value(&mut profile, "archive_file", toml::Value::String(archive))?;
+        write_config_file(&toml::to_string_pretty(&profile)?)?;
+    }
+    migrate_login()?;
+    let contents = std::fs::read_to_string(path)?;
+    parse_config(Some(&contents))
+}
+
+const ENV_PREFIX: &str = "TDL";
+const ENV_SEPARATOR: &str = "__";
+
+/// Dotted keys of the settings overridden by environment variables,
+/// e.g. `TDL_DOWNLOAD_PATHS__BASE_PATH` overrides `download_paths.base_path`
+pub fn env_overrides() -> Vec<String> {
+    let prefix = format!("{ENV_PREFIX}_");
+    std::env::vars()
+        .filter_map(|(name, _)| {
+            let key = name.strip_prefix(&prefix)?.to_lowercase();
+            Some(key.split(ENV_SEPARATOR).collect::<Vec<_>>().join("."))
+        })
+        .collect()
+}
+
+/// Puts back the values `file` has for settings overridden by the environment,
+/// or removes them from `value` if `file` doesn't have them.
+fn restore_env_overrides(value: &mut toml::Value, file: &toml::Value) -> Result<(), Error> {
+    for key in env_overrides() {
+        if get_value(value, &key).is_none() {
+            continue;
+        }
+        match get_value(file, &key) {
+            Some(saved) => insert_value(value, &key, saved.clone())?,
+            None => remove_value(value, &key),
+        }
+    }
+    Ok(())
+}
+
+/// Where settings are read from and saved to, chosen by the `--config` and `--profile` flags
+#[derive(Debug, Clone)]
+pub struct ConfigLocation {
+    /// The config file, `~/.config/tdl/config.toml` unless `--config` is given
+    pub file: PathBuf,
+    /// Name of the profile layered over `file`
+    pub profile: Option<String>,
+}
+
+impl ConfigLocation {
+    /// The file settings are saved to. Either the profile, or the config file
+    pub fn active_file(&self) -> PathBuf {
+        match &self.profile {
+            Some(_) => self.profile_file("toml"),
+            None => self.file.clone(),
+        }
+    }
+
+    /// A file that belongs to the config file or to the active profile, such as its login.
+    ///
+    /// The files of `config.toml` are named `<name>` and kept next to it, and those of
+    /// any other config file are prefixed with its name, such as `library.credentials.toml`.
+    /// The files of a profile are in the `profiles` folder next to the config file.
+    pub fn related_file(&self, name: &str) -> PathBuf {
+        match &self.profile {
+            Some(_) => self.profile_file(name),
+            None => self.dir().join(format!("{}{name}", self.prefix())),
+        }
+    }
+
+    fn profile_file(&self, name: &str) -> PathBuf {
+        let profile = self.profile.as_deref().unwrap_or_default();
+        self.dir()
+            .join("profiles")
+            .join(format!("{}{profile}.{name}", self.prefix()))
+    }
+
+    fn dir(&self) -> &Path {
+        self.file.parent().unwrap_or_else(|| Path::new(""))
+    }
+
+    fn prefix(&self) -> String {
+        match self.file.file_stem() {
+            Some(stem) if stem != "config" => format!("{}.", stem.to_string_lossy()),
+            _ => String::new(),
+        }
+    }
+}
+
+static CONFIG_LOCATION: OnceLock<ConfigLocation> = OnceLock::new();
+
+/// Chooses the config file and profile. Has to be called before the settings are first loaded.
+pub fn set_config_location(file: Option<PathBuf>, profile: Option<String>) -> Result<(), Error> {
+    if let Some(profile) = &profile {
+        let valid = !profile.is_empty()
+            && profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(anyhow!(
+                "Invalid profile name `{profile}`. Use letters, numbers, - and _"
+            ));
+        }
+    }
+    let location = ConfigLocation {
+        file: file.unwrap_or_else(|| PathBuf::from(get_config_file())),
+        profile,
+    };
+    CONFIG_LOCATION
+        .set(location)
+        .map_err(|_| anyhow!("The config location can only be set once, before it's used"))
+}
+
+pub fn config_location() -> &'static ConfigLocation {
+    CONFIG_LOCATION.get_or_init(|| ConfigLocation {
+        file: PathBuf::from(get_config_file()),
+        profile: None,
+    })
+}
+
+fn write_config_file(contents: &str) -> Result<(), Error> {
+    write_config_file_to(&config_location().active_file(), contents)
+}
+
+fn write_config_file_to(path: &Path, contents: &str) -> Result<(), Error> {
+    std::fs::create_dir_all(get_cache_dir())?;
+    write_private_file(path, contents)
+}
+
+/// Writes a file through a temporary file, so it's never left half written,
+/// and only lets the owner read it.
+pub fn write_private_file(path: &Path, contents: &str) -> Result<(), Error> {
+    write_file(path, contents.as_bytes(), true)
+}
+
+/// Writes a file through a temporary file next to it,
+/// so readers never observe a partially written file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    write_file(path, contents, false)
+}
+
+fn write_file(path: &Path, contents: &[u8], private: bool) -> Result<(), Error> {
+    // tells apart the temporary files of writes to the same path within this process
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent directory", path.display()))?;
+    std::fs::create_dir_all(parent)?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+        .to_string_lossy();
+    let write = WRITES.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = parent.join(format!(".{file_name}.{}.{write}.tmp", std::process::id()));
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    let result = options.open(&tmp_path).and_then(|mut file| {
+        // the mode is only applied when the file is created
+        #[cfg(unix)]
+        if private {
+            file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    Ok(result?)
+}
+
+/// Settings that are masked when displayed, unless explicitly revealed
+const SECRETS: [&str; 5] = [
+    "login_key.device_code",
+    "login_key.access_token",
+    "login_key.refresh_token",
+    "api_key.client_secret",
+    "serve_token",
+];
+
+pub fn is_secret(key: &str) -> bool {
+    SECRETS.contains(&key)
+}
+
+/// Looks up a dotted key such as `download_paths.track`
+pub fn get_value<'a>(value: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    key.split('.')
+        .try_fold(value, |value, part| value.get(part))
+}
+
+fn get_value_mut<'a>(value: &'a mut toml::Value, key: &str) -> Option<&'a mut toml::Value> {
+    key.split('.')
+        .try_fold(value, |value, part| value.get_mut(part))
+}
+
+/// Sets a dotted key, creating any missing tables along the way
+fn insert_value(root: &mut toml::Value, key: &str, new: toml::Value) -> Result<(), Error> {
+    let (parents, name) = match key.rsplit_once('.') {
+        Some((parents, name)) => (Some(parents), name),
+        None => (None, key),
+    };
+    let mut table = root
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("Config file is not a table"))?;
+    for part in parents.into_iter().flat_map(|p| p.split('.')) {
+        table = table
+            .entry(part)
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("{part} in {key} is not a section"))?;
+    }
+    table.insert(name.to_string(), new);
+    Ok(())
+}
+
+/// Removes a dotted key, if it exists
+fn remove_value(root: &mut toml::Value, key: &str) {
+    let (parent, name) = match key.rsplit_once('.') {
+        Some((parents, name)) => (get_value_mut(root, parents), name),
+        None => (Some(root), key),
+    };
+    if let Some(table) = parent.and_then(|table| table.as_table_mut()) {
+        table.remove(name);
+    }
+}
+
+/// Reads the config file as TOML, without applying defaults or checking it
+fn read_config_file() -> Result<toml::Value, Error> {
+    let path = &config_location().active_file();
+    if !path.exists() {
+        return Ok(toml::Value::Table(Default::default()));
+    }
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| anyhow!("{} is not valid TOML: {e}", path.display()))
+}
+
+/// Checks the file contents in `file`, and saves them if they're valid
+fn save_config_value(file: &toml::Value) -> Result<Settings, Error> {
+    let contents = toml::to_string_pretty(file)?;
+    let settings = parse_config(Some(&contents))?;
+    write_config_file(&contents)?;
+    Ok(settings)
+}
+
+/// Changes a single setting in the config file.
+/// `input` is converted to the type of the setting, and the result is checked before it's saved.
+pub fn set_config_value(key: &str, input: &str) -> Result<Settings, Error> {
+    let defaults = parse_config(None)?.to_value(true)?;
+    let expected = get_value(&defaults, key)
+        .ok_or_else(|| anyhow!("Unknown setting {key}. Run `tdl config show` to list them"))?;
+    let new = match expected {
+        toml::Value::String(_) => toml::Value::String(input.to_string()),
+        toml::Value::Integer(_) => toml::Value::Integer(
+            input
+                .parse()
+                .map_err(|_| anyhow!("{key} must be a whole number"))?,
+        ),
+        toml::Value::Boolean(_) => toml::Value::Boolean(
+            input
+                .parse()
+                .map_err(|_| anyhow!("{key} must be true or false"))?,
+        ),
+        toml::Value::Table(_) => {
+            return Err(anyhow!(
+                "{key} is a section. Set one of its keys instead, such as {key}.{}",
+                expected
+                    .as_table()
+                    .and_then(|t| t.keys().next())
+                    .map(String::as_str)
+                    .unwrap_or_default()
+            ))
+        }
+        _ => return Err(anyhow!("{key} can't be set from the command line")),
+    };
+    if key.starts_with("login_key.") {
+        let store = credential_store()?;
+        let mut login = store.load()?.unwrap_or_default().to_settings_value()?;
+        insert_value(&mut login, key, new)?;
+        let login = login["login_key"]
+            .clone()
+            .try_into()
+            .map_err(|e| anyhow!("Invalid value for {key}: {e}"))?;
+        store.save(&login)?;
+        return get_config();
+    }
+    let mut file = read_config_file()?;
+    insert_value(&mut file, key, new)?;
+    save_config_value(&file).map_err(|e| anyhow!("Invalid value for {key}: {e}"))
+}
+
+/// Resets a single setting, or every setting, to its default.
+/// The login is kept in the credential store, and is removed by logging out instead.
+pub fn reset_config(key: Option<&str>) -> Result<Settings, Error> {
+    let file = match key {
+        Some(key) if key == "login_key" || key.starts_with("login_key.") => {
+            return Err(anyhow!(
+                "The login isn't stored in the config file. Use `tdl logout` to remove it"
+            ))
+        }
+        Some(key) => {
+            let mut file = read_config_file()?;
+            remove_value(&mut file, key);
+            file
+        }
+        None => toml::Value::Table(Default::default()),
+    };
+    save_config_value(&file)
+}
+
+/// Checks the config file, and returns a warning for every key in it that tdl doesn't use
+pub fn validate_config_file() -> Result<Vec<String>, Error> {
+    let mut file = read_config_file()?;
+    let settings = parse_config(Some(&toml::to_string(&file)?))?;
+    let known = settings.to_value(true)?;
+    let mut warnings = Vec::new();
+    if get_value(&file, "download_cover").is_some() {
+        warnings
+            .push("download_cover is replaced by cover.mode. Set cover.mode instead".to_string());
+        remove_value(&mut file, "download_cover");
+    }
+    unknown_keys(&file, &known, "", &mut warnings);
+    Ok(warnings)
+}
+
+fn unknown_keys(file: &toml::Value, known: &toml::Value, prefix: &str, out: &mut Vec<String>) {
+    let table = match file.as_table() {
+        Some(table) => table,
+        None => return,
+    };
+    for (name, value) in table {
+        let key = format!("{prefix}{name}");
+        match known.get(name) {
+            None => out.push(format!("Unknown setting {key} is ignored")),
+            Some(known) => unknown_keys(value, known, &format!("{key}."), out),
+        }
+    }
+}
+
 fn get_config_dir() -> String {
     let config_dir =
         var("XDG_CONFIG_HOME").unwrap_or_else(|_| var("HOME").unwrap_or_else(|_| "".to_string()));
@@ -312,12 +1214,14 @@ fn get_cache_dir() -> String {
     format!("{}/cache", get_config_dir())
 }
 
+fn get_archive_file() -> String {
+    format!("{}/archive.jsonl", get_config_dir())
+}
+
 fn get_config_file() -> String {
     format!("{}/config.toml", get_config_dir())
 }
 
 lazy_static::lazy_static! {
    pub static ref CONFIG_HOME: String = get_config_dir();
-   pub static ref CONFIG_FILE: String = get_config_file();
-   pub static ref CONFIG: RwLock<Settings> = RwLock::new(get_config().expect("Unable to get configuration"));
 }
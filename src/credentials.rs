@@ -0,0 +1,213 @@
+use crate::config::{config_location, write_private_file, LoginKey};
+use anyhow::{anyhow, Error};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Environment variable holding the passphrase of the encrypted credential store
+pub const PASSPHRASE_ENV: &str = "TDL_CREDENTIALS_PASSPHRASE";
+
+/// Where the login is kept, separately from the settings in `config.toml`,
+/// so a config file can be shared without leaking the tokens in it.
+pub trait CredentialStore: Send + Sync {
+    /// Reads the stored login, or `None` if nothing has been stored
+    fn load(&self) -> Result<Option<LoginKey>, Error>;
+    fn save(&self, login: &LoginKey) -> Result<(), Error>;
+    /// Removes the stored login
+    fn clear(&self) -> Result<(), Error>;
+}
+
+/// Stores the login as plain TOML, readable only by the owner
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl CredentialStore for FileStore {
+    fn load(&self) -> Result<Option<LoginKey>, Error> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&self.path)?;
+        let login = toml::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid credentials in {}: {e}", self.path.display()))?;
+        Ok(Some(login))
+    }
+
+    fn save(&self, login: &LoginKey) -> Result<(), Error> {
+        write_private_file(&self.path, &toml::to_string_pretty(login)?)
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        remove_if_exists(&self.path)
+    }
+}
+
+/// Keeps the login in memory only, for clients built from explicit settings.
+/// It starts out empty, so the login in the settings is used
+#[derive(Default)]
+pub struct MemoryStore {
+    login: Mutex<Option<LoginKey>>,
+}
+
+impl MemoryStore {
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Option<LoginKey>>, Error> {
+        self.login
+            .lock()
+            .map_err(|_| anyhow!("Credential store lock poisoned"))
+    }
+}
+
+impl CredentialStore for MemoryStore {
+    fn load(&self) -> Result<Option<LoginKey>, Error> {
+        Ok(self.lock()?.clone())
+    }
+
+    fn save(&self, login: &LoginKey) -> Result<(), Error> {
+        *self.lock()? = Some(login.clone());
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        *self.lock()? = None;
+        Ok(())
+    }
+}
+
+/// Stores the login encrypted with ChaCha20-Poly1305,
+/// using a key derived from a passphrase with Argon2
+pub struct EncryptedStore {
+    path: PathBuf,
+    passphrase: String,
+}
+
+/// The encrypted store as it's written to disk
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    version: u8,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl EncryptedStore {
+    pub fn new(path: PathBuf, passphrase: String) -> Self {
+        Self { path, passphrase }
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Result<ChaCha20Poly1305, Error> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| anyhow!("Unable to derive the credentials key: {e}"))?;
+        Ok(ChaCha20Poly1305::new(&key))
+    }
+}
+
+impl CredentialStore for EncryptedStore {
+    fn load(&self) -> Result<Option<LoginKey>, Error> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&self.path)?;
+        let sealed: Sealed = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid credentials in {}: {e}", self.path.display()))?;
+        if sealed.version != 1 {
+            return Err(anyhow!(
+                "Unsupported credentials version {} in {}",
+                sealed.version,
+                self.path.display()
+            ));
+        }
+        let salt = base64::decode(sealed.salt)?;
+        let nonce = base64::decode(sealed.nonce)?;
+        if nonce.len() != 12 {
+            return Err(anyhow!("Invalid nonce in {}", self.path.display()));
+        }
+        let ciphertext = base64::decode(sealed.ciphertext)?;
+        let plaintext = self
+            .cipher(&salt)?
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| {
+                anyhow!(
+                    "Unable to decrypt {}. {PASSPHRASE_ENV} is wrong, or the file is damaged",
+                    self.path.display()
+                )
+            })?;
+        Ok(Some(toml::from_slice(&plaintext)?))
+    }
+
+    fn save(&self, login: &LoginKey) -> Result<(), Error> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plaintext = toml::to_string(login)?;
+        let ciphertext = self
+            .cipher(&salt)?
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow!("Unable to encrypt credentials"))?;
+        let sealed = Sealed {
+            version: 1,
+            salt: base64::encode(salt),
+            nonce: base64::encode(nonce),
+            ciphertext: base64::encode(ciphertext),
+        };
+        write_private_file(&self.path, &serde_json::to_string_pretty(&sealed)?)
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        remove_if_exists(&self.path)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The credential store of the active profile.
+///
+/// The login is encrypted when a passphrase is set in `TDL_CREDENTIALS_PASSPHRASE`.
+/// Setting it for the first time encrypts a login that was stored as plain text.
+pub fn credential_store() -> Result<Box<dyn CredentialStore>, Error> {
+    let location = config_location();
+    let plain = FileStore::new(location.related_file("credentials.toml"));
+    let encrypted_path = location.related_file("credentials.enc");
+
+    let passphrase = std::env::var(PASSPHRASE_ENV).unwrap_or_default();
+    if passphrase.is_empty() {
+        if encrypted_path.exists() {
+            return Err(anyhow!(
+                "The login in {} is encrypted. Set {PASSPHRASE_ENV} to its passphrase",
+                encrypted_path.display()
+            ));
+        }
+        return Ok(Box::new(plain));
+    }
+
+    let encrypted = EncryptedStore::new(encrypted_path, passphrase);
+    if let Some(login) = plain.load()? {
+        if encrypted.load()?.is_none() {
+            encrypted.save(&login)?;
+        }
+        plain.clear()?;
+    }
+    Ok(Box::new(encrypted))
+}
+
+/// Where a device login that hasn't been approved yet is kept between runs
+/// of `tdl login --no-interactive`
+pub fn pending_login_path() -> PathBuf {
+    config_location().related_file("login-pending.json")
+}
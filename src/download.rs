@@ -1,48 +1,223 @@
-use crate::api::{models::*, TidalClient, CLIENT};
-use crate::config::CONFIG;
+use crate::api::{models::*, scheduler::ThrottleListener, TidalClient};
+use crate::archive::{Archive, ArchiveEntry};
+use crate::config::{self, CollisionMode, CoverMode, Settings};
 
 use crate::models::*;
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, Context, Error};
 use futures::Future;
-use indicatif::{MultiProgress, ProgressDrawTarget};
 use log::{debug, info};
 use metaflac::block::PictureType::CoverFront;
 use metaflac::Tag;
+use serde::Serialize;
 use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::task::{self, Poll};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use tokio::try_join;
 
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::task::JoinHandle;
-use tokio_stream::StreamExt;
+use futures::future::{BoxFuture, FutureExt};
+use futures::StreamExt;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{Duration, Instant};
+use tokio_stream::wrappers::ReceiverStream;
 
 pub type ChannelValue = Pin<Box<dyn Future<Output = Result<bool, Error>> + Send>>;
 pub type ReceiveChannel = Receiver<ChannelValue>;
+pub type EventSender = UnboundedSender<DownloadEvent>;
+pub type EventReceiver = UnboundedReceiver<DownloadEvent>;
 
+/// Progress events are sent at most this often for each file
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What happened to a track, sent as soon as it happens.
+///
+/// The progress bars, `get --progress json` and programs using tdl all show progress from these.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DownloadEvent {
+    /// A track was found in one of the URLs, and is waiting for a worker
+    Queued { track_id: usize },
+    /// The track was looked up on TIDAL, and is waiting to be downloaded to `path`
+    MetadataFetched {
+        track_id: usize,
+        info: String,
+        path: PathBuf,
+    },
+    /// The file started downloading
+    Started { track_id: usize, size: u64 },
+    /// Bytes of the file downloaded so far
+    Progress {
+        track_id: usize,
+        downloaded: u64,
+        size: u64,
+    },
+    /// The file was downloaded, and is being finished
+    Finishing { track_id: usize, step: FinishStep },
+    /// The track is in place at `path`
+    Completed { track_id: usize, path: PathBuf },
+    /// The track wasn't downloaded, because it's already at `path`
+    Skipped {
+        track_id: usize,
+        reason: SkipReason,
+        path: PathBuf,
+    },
+    /// The track is saved under another name, because its path belongs to another track
+    Renamed {
+        track_id: usize,
+        info: String,
+        path: PathBuf,
+    },
+    /// TIDAL rate limited a request, and every request waits before it's sent
+    RateLimited { wait_secs: u64 },
+    /// A track failed, or a URL if there's no `track_id`
+    Failed {
+        track_id: Option<usize>,
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishStep {
+    /// Flushing the file to disk
+    Writing,
+    /// Writing the tags
+    Tagging,
+    /// Swapping the old file for the new one
+    Replacing,
+}
+
+impl fmt::Display for FinishStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FinishStep::Writing => "Writing to Disk",
+            FinishStep::Tagging => "Writing metadata",
+            FinishStep::Replacing => "Replacing File",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The track was already downloaded
+    Exists,
+    /// The track was already downloaded, in the best quality available
+    NoUpgrade,
+}
+
+impl DownloadEvent {
+    pub fn track_id(&self) -> Option<usize> {
+        match self {
+            Self::Queued { track_id }
+            | Self::MetadataFetched { track_id, .. }
+            | Self::Started { track_id, .. }
+            | Self::Progress { track_id, .. }
+            | Self::Finishing { track_id, .. }
+            | Self::Completed { track_id, .. }
+            | Self::Skipped { track_id, .. }
+            | Self::Renamed { track_id, .. } => Some(*track_id),
+            Self::Failed { track_id, .. } => *track_id,
+            Self::RateLimited { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for DownloadEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Queued { track_id } => write!(f, "Queued | {track_id}"),
+            Self::MetadataFetched { info, .. } => write!(f, "Waiting | {info}"),
+            Self::Started { track_id, size } => {
+                write!(f, "Downloading | {track_id} ({size} bytes)")
+            }
+            Self::Progress {
+                track_id,
+                downloaded,
+                size,
+            } => write!(f, "Downloading | {track_id} {downloaded}/{size}"),
+            Self::Finishing { track_id, step } => write!(f, "{step} | {track_id}"),
+            Self::Completed { path, .. } => write!(f, "Download Complete | {}", path.display()),
+            Self::Skipped { reason, path, .. } => match reason {
+                SkipReason::Exists => write!(f, "File Exists | {}", path.display()),
+                SkipReason::NoUpgrade => write!(f, "No Upgrade Available | {}", path.display()),
+            },
+            Self::Renamed { info, path, .. } => {
+                write!(f, "Path Collision | {info} renamed to {}", path.display())
+            }
+            Self::RateLimited { wait_secs } => write!(
+                f,
+                "Rate Limited | Waiting {wait_secs}s before the next request"
+            ),
+            Self::Failed { error, .. } => f.write_str(error),
+        }
+    }
+}
+
+/// Creates the channel download events are sent on.
+/// Events are dropped if the receiver is dropped, without affecting the download.
+pub fn event_channel() -> (EventSender, EventReceiver) {
+    mpsc::unbounded_channel()
+}
+
+/// Starts resolving every URL into tracks.
+///
+/// The tracks are queued on the returned channels, and are only fetched and
+/// downloaded as the channels are consumed, by [`consume_channel`].
+/// Every step is sent to `events`, which is closed once every task is done.
 pub async fn dispatch_downloads(
     urls: Vec<String>,
-    client: TidalClient,
-) -> Result<(Vec<JoinHandle<()>>, ReceiveChannel, ReceiveChannel), Error> {
-    let config = CONFIG.read().await;
-    let progress = setup_multi_progress(config.show_progress, config.progress_refresh_rate);
-    let client = Arc::new(client);
+    client: Arc<TidalClient>,
+    config: Arc<Settings>,
+    upgrade: bool,
+    events: EventSender,
+) -> Result<
+    (
+        Vec<JoinHandle<Result<bool, Error>>>,
+        ReceiveChannel,
+        ReceiveChannel,
+    ),
+    Error,
+> {
+    let events = Arc::new(events);
+    // the scheduler outlives the download, and mustn't keep the events open after it
+    let throttled = Arc::downgrade(&events);
+    let throttle = client.media.scheduler().on_throttle(move |wait| {
+        if let Some(events) = throttled.upgrade() {
+            let _ = events.send(DownloadEvent::RateLimited {
+                wait_secs: wait.as_secs().max(1),
+            });
+        }
+    });
     // the maximum amount of items that can be buffered by the rx channel
     // this should be equal to the total number of of work items possible at a single time
     // the actual concurrent requests will be limited by the consumer.
     let buffer_size = config.workers as usize + config.downloads as usize;
     let (dl_tx, dl_rx) = mpsc::channel(buffer_size);
     let (worker_tx, worker_rx) = mpsc::channel(config.workers as usize);
+    let metrics = client.media.metrics();
+    metrics.watch_queue("downloads", dl_tx.downgrade());
+    metrics.watch_queue("workers", worker_tx.downgrade());
 
     let task = DownloadTask {
         dl_channel: dl_tx,
         worker_channel: worker_tx,
         client,
-        progress,
+        events,
+        archive: Arc::new(Archive::open(Path::new(&config.archive_file))?),
+        config,
+        albums: Memo::default(),
+        covers: Memo::default(),
+        artists: Memo::default(),
+        paths: Arc::default(),
+        upgrade,
+        _throttle: Arc::new(throttle),
     };
     debug!("Download Task");
     let mut handles = Vec::with_capacity(urls.len());
@@ -57,23 +232,19 @@ pub async fn dispatch_downloads(
 
         //spawn the download task for each URL in a new thread
         let handle = tokio::task::spawn(async move {
-            let res = match action.kind {
-                ActionKind::Track => {
-                    let channel = task.worker_channel.clone();
-                    let job = Box::pin(task.download_track(id));
-                    match channel.send(job).await {
-                        Ok(_) => Ok(true),
-                        Err(_) => Err(anyhow!("Error submitting track to worker queue")),
-                    }
-                }
+            let result = match action.kind {
+                ActionKind::Track => match id.parse() {
+                    Ok(id) => task.queue_track(id).await,
+                    Err(_) => Err(anyhow!("Invalid track ID {id}")),
+                },
                 ActionKind::Album => task.download_list(ActionKind::Album, id).await,
                 ActionKind::Artist => task.download_artist(id).await,
                 ActionKind::Playlist => task.download_list(ActionKind::Playlist, id).await,
             };
-            match res {
-                Ok(_) => {}
-                Err(e) => eprint!("{e}"),
-            };
+            if let Err(e) = &result {
+                task.fail(None, e);
+            }
+            result
         });
 
         handles.push(handle)
@@ -84,15 +255,56 @@ pub async fn dispatch_downloads(
 
 #[derive(Clone)]
 pub struct DownloadTask {
-    pub progress: MultiProgress,
     pub dl_channel: Sender<ChannelValue>,
     pub worker_channel: Sender<ChannelValue>,
     pub client: Arc<TidalClient>,
+    config: Arc<Settings>,
+    /// Shared by every task, so the channel closes once the last task is done
+    events: Arc<EventSender>,
+    albums: Memo<usize, Arc<AlbumContext>>,
+    /// The cover to embed into the tracks of each album
+    covers: Memo<usize, Option<Arc<Cover>>>,
+    artists: Memo<usize, ()>,
+    /// Paths claimed by tracks in this run, so two tracks never write to the same file
+    paths: Arc<std::sync::Mutex<HashMap<PathBuf, usize>>>,
+    /// Tracks downloaded or imported by previous runs
+    archive: Arc<Archive>,
+    /// Replace existing files that have a lower quality than what's available
+    upgrade: bool,
+    /// Reports rate limits to the events until every task of the download is done
+    _throttle: Arc<ThrottleListener>,
+}
+
+/// Album level data that is shared between every track of the album
+pub struct AlbumContext {
+    pub album: Album,
+    pub artist: Artist,
 }
 
 impl DownloadTask {
+    fn emit(&self, event: DownloadEvent) {
+        // nobody may be listening
+        let _ = self.events.send(event);
+    }
+
+    /// Counts the error of a failed track or URL, and sends it to the events
+    fn fail(&self, track_id: Option<usize>, error: &Error) {
+        self.client.media.metrics().failure(error);
+        self.emit(DownloadEvent::Failed {
+            track_id,
+            error: format!("{error:#}"),
+        });
+    }
+
+    /// Sends the error of a failed track to the events, and returns the result
+    fn report(&self, track_id: usize, result: Result<bool, Error>) -> Result<bool, Error> {
+        if let Err(e) = &result {
+            self.fail(Some(track_id), e);
+        }
+        result
+    }
+
     async fn download_artist(&self, id: String) -> Result<bool, Error> {
-        self.progress.println("Getting Artist Albums")?;
         let albums = self.client.media.get_artist_albums(&id).await?;
         for album in albums {
             self.download_list(ActionKind::Album, album.id.to_string())
@@ -102,99 +314,273 @@ impl DownloadTask {
     }
 
     async fn download_list(&self, kind: ActionKind, id: String) -> Result<bool, Error> {
-        let url = format!("https://api.tidal.com/v1/{kind}s/{id}/items",);
+        let url = self.client.media.api_url(&format!("{kind}s/{id}/items"));
         let tracks = self
             .client
             .media
             .get_items::<ItemResponseItem<Track>>(&url, None, None)
             .await?;
         for track in tracks {
-            let future = Box::pin(self.clone().download_track(track.item.id.to_string()));
-            match self.clone().worker_channel.send(future).await {
-                Ok(_) => continue,
-                Err(_) => return Err(anyhow!("Error Submitting download_track")),
-            }
+            self.queue_track(track.item.id).await?;
         }
         Ok(true)
     }
 
-    async fn download_track(self, id: String) -> Result<bool, Error> {
-        let track = self.client.media.get_track(&id).await?;
-        let path_str = self.get_path(&track).await?;
-        let download = Box::pin(self.clone().download_file(track, path_str));
-        match &self.dl_channel.send(download).await {
-            Ok(_) => Ok(true),
-            Err(_) => Err(anyhow!("Submitting Download Task failed")),
+    /// Queues a track for a worker to look up
+    async fn queue_track(&self, id: usize) -> Result<bool, Error> {
+        let future = Box::pin(self.clone().download_track(id));
+        match self.worker_channel.send(future).await {
+            Ok(_) => {
+                self.emit(DownloadEvent::Queued { track_id: id });
+                Ok(true)
+            }
+            Err(_) => Err(anyhow!("Error submitting track to worker queue")),
         }
     }
 
-    async fn download_file(self, track: Track, mut path: PathBuf) -> Result<bool, anyhow::Error> {
-        let info = track.get_info();
-        let pb = ProgressBar::new(self.progress.clone(), track.id);
-        let playback_manifest = self.client.media.get_stream_url(track.id).await?;
-        path.set_extension(
-            playback_manifest
-                .get_file_extension()
-                .expect("Unable to determine track file extension"),
-        );
+    async fn download_track(self, id: usize) -> Result<bool, Error> {
+        let result = self.fetch_track(id).await;
+        self.report(id, result)
+    }
 
-        let stream_url = &playback_manifest.urls[0];
+    /// Looks up a track, and queues its file to be downloaded, unless it's already downloaded
+    async fn fetch_track(&self, id: usize) -> Result<bool, Error> {
+        let archived = self.archive.get(id).filter(|entry| entry.path.exists());
+        // the file to replace with a higher quality download, and its quality
+        let mut existing = None;
+        // skip archived tracks before asking TIDAL for anything
+        if let Some(entry) = archived {
+            let requested = self.config.audio_quality;
+            let quality = recorded_quality(&entry.path, entry.quality).await;
+            if !self.upgrade || !is_lower(quality, requested) {
+                self.emit(DownloadEvent::Skipped {
+                    track_id: id,
+                    reason: SkipReason::Exists,
+                    path: entry.path,
+                });
+                return Ok(false);
+            }
+            existing = Some((entry.path, quality));
+        }
+
+        let track = self
+            .client
+            .media
+            .get_track(&id.to_string())
+            .await
+            .with_context(|| format!("Track {id}"))?;
+        let album = self.get_album_context(&track).await?;
+        let stream = self
+            .client
+            .media
+            .get_stream_url(track.id)
+            .await
+            .with_context(|| track.get_info())?;
+        if let Some((path, quality)) = &existing {
+            if !is_lower(*quality, stream.audio_quality) {
+                self.emit(DownloadEvent::Skipped {
+                    track_id: id,
+                    reason: SkipReason::NoUpgrade,
+                    path: path.clone(),
+                });
+                return Ok(false);
+            }
+        }
+        let mut path = self.get_path(&track, &album, &stream).await?;
+        let extension = stream
+            .manifest
+            .get_file_extension()
+            .ok_or_else(|| anyhow!("Unable to determine track file extension"))?;
+        // set_extension would replace anything after a '.' in the track name
+        path = add_extension(&path, extension);
 
         if path.exists() {
-            debug!("Path exists");
-            self.progress
-                .println(format!("File Exists | {}", track.get_info()))?;
-            // Exit early if the file already exists
-            return Ok(false);
+            match read_track_id(&path).await {
+                // a different track was saved here by a previous run
+                Some(id) if id != track.id => {
+                    path = self.resolve_collision(path, &track, id).await?;
+                }
+                _ => {}
+            }
+        }
+
+        let is_existing = matches!(&existing, Some((old, _)) if old == &path);
+        if path.exists() && !is_existing {
+            let quality = match self.upgrade {
+                true => Some(recorded_quality(&path, None).await),
+                false => None,
+            };
+            match quality {
+                Some(quality) if is_lower(quality, stream.audio_quality) => {
+                    existing = Some((path.clone(), quality));
+                }
+                _ => {
+                    debug!("Path exists");
+                    self.emit(DownloadEvent::Skipped {
+                        track_id: id,
+                        reason: SkipReason::Exists,
+                        path,
+                    });
+                    // Exit early if the file already exists
+                    return Ok(false);
+                }
+            }
+        }
+
+        self.emit(DownloadEvent::MetadataFetched {
+            track_id: id,
+            info: track.get_info(),
+            path: path.clone(),
+        });
+        let replace = existing.map(|(old, _)| old);
+        let cover = self.get_cover(&album).await?;
+        let download = Box::pin(
+            self.clone()
+                .download_file(track, album, cover, stream, path, replace),
+        );
+        match &self.dl_channel.send(download).await {
+            Ok(_) => Ok(true),
+            Err(_) => Err(anyhow!("Submitting Download Task failed")),
         }
+    }
+
+    async fn download_file(
+        self,
+        track: Track,
+        album: Arc<AlbumContext>,
+        cover: Option<Arc<Cover>>,
+        playback: PlaybackInfo,
+        path: PathBuf,
+        replace: Option<PathBuf>,
+    ) -> Result<bool, Error> {
+        let track_id = track.id;
+        let result = self
+            .save_file(track, &album, cover.as_deref(), playback, path, replace)
+            .await;
+        self.report(track_id, result)
+    }
+
+    /// Downloads the track next to `path` and moves it into place once it's complete.
+    /// If `replace` is set, that file is swapped out for the new download.
+    async fn save_file(
+        &self,
+        track: Track,
+        album: &AlbumContext,
+        cover: Option<&Cover>,
+        playback: PlaybackInfo,
+        path: PathBuf,
+        replace: Option<PathBuf>,
+    ) -> Result<bool, anyhow::Error> {
+        let info = track.get_info();
+        let track_id = track.id;
+        let stream_url = &playback.manifest.urls[0];
 
-        let response = CLIENT.get(stream_url).send().await?;
+        let response = self.client.media.get_file(stream_url).send().await?;
         let total_size: u64 = response
             .content_length()
             .ok_or_else(|| anyhow!("Failed to get content length from {}", stream_url))?;
-        pb.start_download(total_size, &track);
+        self.emit(DownloadEvent::Started {
+            track_id,
+            size: total_size,
+        });
         debug!("Got Content Length: {total_size} for {}", track.get_info());
         tokio::fs::create_dir_all(
             path.parent()
                 .ok_or_else(|| anyhow!("Parent Directory missing somehow"))?,
         )
         .await?;
-        let file = File::create(path.clone()).await?;
+        // an interrupted download never leaves a partial file at the real path
+        let part = add_extension(&path, "part");
+        let file = File::create(part.clone()).await?;
         // 1 MiB Write buffer to minimize syscalls for slow i/o
         // Reduces write CPU time from 24% to 7%.
         let mut writer = tokio::io::BufWriter::with_capacity(1024 * 1000 * 1000, file);
         let mut downloaded: u64 = 0;
+        let mut reported = Instant::now();
         let mut stream = response.bytes_stream();
         while let Some(item) = stream.next().await {
             let chunk = item?;
             downloaded = min(downloaded + (chunk.len() as u64), total_size);
-            pb.set_position(downloaded);
+            self.client
+                .media
+                .metrics()
+                .bytes_downloaded(chunk.len() as u64);
+            if reported.elapsed() >= PROGRESS_INTERVAL || downloaded == total_size {
+                reported = Instant::now();
+                self.emit(DownloadEvent::Progress {
+                    track_id,
+                    downloaded,
+                    size: total_size,
+                });
+            }
             writer.write_all(&chunk).await?;
         }
 
         //flush buffer to disk;
-        pb.set_message(format!("Writing to Disk | {info}"));
+        self.emit(DownloadEvent::Finishing {
+            track_id,
+            step: FinishStep::Writing,
+        });
         writer.flush().await?;
+        drop(writer);
+        if tokio::fs::metadata(&part).await?.len() != total_size {
+            tokio::fs::remove_file(&part).await?;
+            return Err(anyhow!("Download Incomplete | {info}"));
+        }
 
-        pb.set_message(format!("Writing metadata | {info}"));
-        self.write_metadata(track, path).await?;
-        pb.println(format!("Download Complete | {info}"));
+        if is_flac(&path) {
+            self.emit(DownloadEvent::Finishing {
+                track_id,
+                step: FinishStep::Tagging,
+            });
+            self.write_metadata(track, part.clone(), album, cover, playback.audio_quality)
+                .await?;
+        }
+        match replace {
+            Some(old) => {
+                self.emit(DownloadEvent::Finishing {
+                    track_id,
+                    step: FinishStep::Replacing,
+                });
+                replace_file(&old, &part, &path, track_id).await?;
+            }
+            None => tokio::fs::rename(&part, &path).await?,
+        }
+        self.archive.record(ArchiveEntry {
+            track_id,
+            path: path.clone(),
+            quality: Some(playback.audio_quality),
+        })?;
+        self.client.media.metrics().track_downloaded();
+        self.emit(DownloadEvent::Completed { track_id, path });
 
         Ok(true)
     }
 
-    async fn write_metadata(&self, track: Track, path: PathBuf) -> Result<(), Error> {
+    async fn write_metadata(
+        &self,
+        track: Track,
+        path: PathBuf,
+        album: &AlbumContext,
+        cover: Option<&Cover>,
+        quality: AudioQuality,
+    ) -> Result<(), Error> {
         let fp = path.clone();
         let mut tag = tokio::task::spawn_blocking(move || Tag::read_from_path(fp)).await??;
         tag.set_vorbis("TITLE", vec![track.title]);
         tag.set_vorbis("TRACKNUMBER", vec![track.track_number.to_string()]);
+        tag.set_vorbis("DISCNUMBER", vec![track.volume_number.to_string()]);
+        if let Some(volumes) = album.album.number_of_volumes {
+            tag.set_vorbis("TOTALDISCS", vec![volumes.to_string()]);
+        }
         tag.set_vorbis("ARTIST", vec![track.artist.name]);
         tag.set_vorbis("ALBUM", vec![track.album.title.unwrap_or_default()]);
         tag.set_vorbis("COPYRIGHT", vec![track.copyright]);
         tag.set_vorbis("ISRC", vec![track.isrc]);
-        if let Some(cover_id) = &track.album.cover {
-            let cover = self.get_cover_data(path.clone(), cover_id).await?;
-            tag.add_picture(cover.content_type, CoverFront, cover.data);
+        tag.set_vorbis(TRACK_ID_TAG, vec![track.id.to_string()]);
+        tag.set_vorbis(QUALITY_TAG, vec![quality.to_string()]);
+        if let Some(cover) = cover {
+            tag.add_picture(cover.content_type.clone(), CoverFront, cover.data.clone());
         }
 
         tokio::task::spawn_blocking(move || tag.save()).await??;
@@ -202,51 +588,420 @@ impl DownloadTask {
         Ok(())
     }
 
-    pub async fn get_cover_data(&self, path: PathBuf, cover_id: &str) -> Result<Cover, Error> {
-        let dl_path = Path::new(&path)
-            .parent()
-            .ok_or_else(|| anyhow!("Parent Directory Doesn't exist"))?
-            .join("cover.jpg");
+    /// Fetches the album and album artist once per album,
+    /// no matter how many tracks of the album are being downloaded.
+    async fn get_album_context(&self, track: &Track) -> Result<Arc<AlbumContext>, Error> {
+        self.albums
+            .get_or_try_init(track.album.id, || async {
+                let (album, artist) = self.client.media.get_album_artist(track).await?;
+                Ok(Arc::new(AlbumContext { album, artist }))
+            })
+            .await
+    }
+
+    /// Saves the artwork of an album the first time one of its tracks is downloaded,
+    /// and returns the cover to embed into the track.
+    ///
+    /// The cover is fetched once per album and shared by every track of it,
+    /// for as long as the download runs, like the album context.
+    async fn get_cover(&self, context: &AlbumContext) -> Result<Option<Arc<Cover>>, Error> {
+        self.covers
+            .get_or_try_init(context.album.id, || async {
+                let cover = self.get_artwork(&context.album, &context.artist).await?;
+                Ok(cover.map(Arc::new))
+            })
+            .await
+    }
+
+    /// Saves the configured sidecar images into the album and artist folders,
+    /// and returns the album cover if it should be embedded into the tracks.
+    async fn get_artwork(&self, album: &Album, artist: &Artist) -> Result<Option<Cover>, Error> {
+        let (settings, sanitizer, album_dir, artist_dir) = {
+            let paths = &self.config.download_paths;
+            // without an artist folder there is nowhere sensible to put the artist picture
+            let artist_dir = match paths.artist.is_empty() {
+                true => None,
+                false => Some(paths.get_artist_path(artist.clone())?),
+            };
+            let album_dir = paths.get_album_path(album.clone(), artist.clone())?;
+            (
+                self.config.cover.clone(),
+                paths.sanitizer(),
+                album_dir,
+                artist_dir,
+            )
+        };
+        let sidecar_name = settings.get_sidecar_name(&sanitizer, album, artist)?;
+        let sidecar_path = album_dir.join(sidecar_name);
+
+        if let (true, Some(dir), Some(picture_id)) =
+            (settings.artist_picture, artist_dir, &artist.picture)
+        {
+            // several albums of the same artist can be downloading at once
+            self.artists
+                .get_or_try_init(artist.id, || async {
+                    let dl_path = dir.join("artist.jpg");
+                    if !dl_path.exists() {
+                        let picture = self
+                            .client
+                            .media
+                            .get_artist_picture(picture_id, settings.size)
+                            .await?;
+                        write_atomic(&dl_path, picture.data).await?;
+                        info!("Write artist picture to disk");
+                    }
+                    Ok(())
+                })
+                .await?;
+        }
+
+        if let (true, Some(video_id)) = (settings.video_cover, &album.video_cover) {
+            let dl_path = sidecar_path.with_extension("mp4");
+            if !dl_path.exists() {
+                let video = self
+                    .client
+                    .media
+                    .get_video_cover(video_id, settings.size)
+                    .await?;
+                write_atomic(&dl_path, video.data).await?;
+                info!("Write video cover to disk");
+            }
+        }
+
+        let cover_id = match (settings.mode, &album.cover) {
+            (CoverMode::None, _) | (_, None) => return Ok(None),
+            (_, Some(cover_id)) => cover_id,
+        };
+        let cover = self
+            .get_cover_data(&sidecar_path, cover_id, settings.size, settings.mode)
+            .await?;
+
+        if !settings.mode.embed() {
+            return Ok(None);
+        }
+        if settings.max_embed_size > 0 && cover.data.len() > settings.max_embed_size {
+            info!(
+                "Cover is {} bytes, larger than max_embed_size. Skipping embed",
+                cover.data.len()
+            );
+            return Ok(None);
+        }
+        Ok(Some(cover))
+    }
+
+    pub async fn get_cover_data(
+        &self,
+        dl_path: &Path,
+        cover_id: &str,
+        size: CoverSize,
+        mode: CoverMode,
+    ) -> Result<Cover, Error> {
         if dl_path.exists() {
             let cover = Cover {
-                content_type: "application/jpeg".to_string(),
+                content_type: "image/jpeg".to_string(),
                 data: tokio::fs::read(dl_path).await?,
             };
             return Ok(cover);
         }
 
-        let pic = self.client.media.get_cover_data(cover_id).await?;
-        tokio::fs::write(dl_path, pic.data.clone()).await?;
-        info!("Write cover to disk");
+        let pic = self.client.media.get_cover_data(cover_id, size).await?;
+        if mode.sidecar() {
+            write_atomic(dl_path, pic.data.clone()).await?;
+            info!("Write cover to disk");
+        }
         Ok(pic)
     }
 
-    async fn get_path(&self, track: &Track) -> Result<PathBuf, Error> {
-        let config = &CONFIG.read().await;
-        let dl_path = &config.download_paths;
-        let album_id = &track.album.id;
-        // The track artist can be different than the album artist
-        // important to use the album artist for naming.
-        // prefer to use that, otherwise default to the track artist
-        let artist_id = match track.album.artist.clone() {
-            Some(val) => val.id.to_string(),
-            None => track.artist.id.to_string(),
-        };
-        let (album, artist) = try_join!(
-            self.client.media.get_album(*album_id),
-            self.client.media.get_artist(&artist_id)
+    async fn get_path(
+        &self,
+        track: &Track,
+        album: &AlbumContext,
+        stream: &PlaybackInfo,
+    ) -> Result<PathBuf, Error> {
+        let path = self.config.download_paths.get_track_path(
+            track.clone(),
+            album.album.clone(),
+            album.artist.clone(),
+            Some(stream),
         )?;
 
-        dl_path.get_track_path(track.clone(), album, artist)
+        let claimed_by = {
+            let mut paths = self
+                .paths
+                .lock()
+                .map_err(|_| anyhow!("Path registry lock poisoned"))?;
+            *paths.entry(path.clone()).or_insert(track.id)
+        };
+        match claimed_by == track.id {
+            true => Ok(path),
+            false => self.resolve_collision(path, track, claimed_by).await,
+        }
+    }
+
+    /// Called when `path` already belongs to the track `other_id`.
+    /// Either fails, or returns a path unique to `track` depending on the configured `on_collision`.
+    async fn resolve_collision(
+        &self,
+        path: PathBuf,
+        track: &Track,
+        other_id: usize,
+    ) -> Result<PathBuf, Error> {
+        match self.config.download_paths.on_collision {
+            CollisionMode::Error => Err(anyhow!(
+                "Path Collision | {} would overwrite track {other_id} at {}",
+                track.get_info(),
+                path.display()
+            )),
+            CollisionMode::Rename => {
+                let file_name = path
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Path has no file name"))?
+                    .to_string_lossy();
+                let renamed = match path.extension() {
+                    // keep the extension at the end if one was already added
+                    Some(ext) => {
+                        let ext = ext.to_string_lossy();
+                        let stem = &file_name[..file_name.len() - ext.len() - 1];
+                        format!("{stem} ({}).{ext}", track.id)
+                    }
+                    None => format!("{file_name} ({})", track.id),
+                };
+                let renamed = path.with_file_name(renamed);
+                self.emit(DownloadEvent::Renamed {
+                    track_id: track.id,
+                    info: track.get_info(),
+                    path: renamed.clone(),
+                });
+                Ok(renamed)
+            }
+        }
     }
 }
 
-fn setup_multi_progress(show_progress: bool, refresh_rate: u8) -> MultiProgress {
-    let mp = MultiProgress::new();
-    let draw_target = match show_progress {
-        true => ProgressDrawTarget::stdout_with_hz(refresh_rate),
-        false => ProgressDrawTarget::hidden(),
+/// Outcome of every task of a download
+#[derive(Debug, Default)]
+pub struct DownloadSummary {
+    /// Tracks that were downloaded
+    pub downloaded: usize,
+    /// Tracks that were skipped, because they had already been downloaded
+    pub skipped: usize,
+    /// Errors of the tasks that failed. Every other task runs regardless
+    pub failed: Vec<Error>,
+}
+
+impl DownloadSummary {
+    /// Counts the results of the tasks started by [`dispatch_downloads`]
+    pub fn new(
+        handles: Vec<Result<bool, Error>>,
+        downloads: Vec<Result<bool, Error>>,
+        workers: Vec<Result<bool, Error>>,
+    ) -> Self {
+        let mut summary = Self::default();
+        for result in handles.into_iter().chain(workers) {
+            match result {
+                // a track that was queued is counted once it's downloaded
+                Ok(true) => {}
+                Ok(false) => summary.skipped += 1,
+                Err(e) => summary.failed.push(e),
+            }
+        }
+        for result in downloads {
+            match result {
+                Ok(_) => summary.downloaded += 1,
+                Err(e) => summary.failed.push(e),
+            }
+        }
+        summary
+    }
+}
+
+/// Runs the tasks in the channel, up to `concurrency` at a time, and returns their results.
+///
+/// Boxed, because the compiler can't otherwise tell the stream is `Send`,
+/// and downloads have to be spawned by `tdl serve`.
+pub fn consume_channel(
+    channel: ReceiveChannel,
+    concurrency: usize,
+) -> BoxFuture<'static, Vec<Result<bool, Error>>> {
+    //The channel receives an unexecuted future as a stream
+    ReceiverStream::new(channel)
+        //execute that future in a greenthread, which stops if the consumer is dropped
+        .map(|i| AbortOnDrop(tokio::task::spawn(i)))
+        //up to a maximum concurrent tasks at a single time
+        .buffer_unordered(concurrency)
+        // a task that couldn't be launched failed too
+        .map(|r| r.unwrap_or_else(|e| Err(e.into())))
+        .collect()
+        .boxed()
+}
+
+/// A spawned task that's aborted if it's dropped before it finishes,
+/// so dropping a download stops every task it started
+pub struct AbortOnDrop<T>(pub JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Vorbis comment holding the TIDAL ID of the track a file was downloaded from
+pub const TRACK_ID_TAG: &str = "TIDAL_TRACK_ID";
+
+/// Reads the TIDAL track ID written by tdl from a downloaded file.
+/// Returns `None` if the file has no readable tag, for example if it isn't a FLAC file.
+pub async fn read_track_id(path: &Path) -> Option<usize> {
+    let path = path.to_path_buf();
+    let tag = tokio::task::spawn_blocking(move || Tag::read_from_path(path))
+        .await
+        .ok()?
+        .ok()?;
+    let id = tag.get_vorbis(TRACK_ID_TAG)?.next()?.parse().ok();
+    id
+}
+
+/// Vorbis comment holding the audio quality a file was downloaded at
+pub const QUALITY_TAG: &str = "TIDAL_AUDIO_QUALITY";
+
+/// Quality of a previously downloaded file, from the archive, its tags or its format.
+/// Returns `None` if it can't be determined.
+pub async fn recorded_quality(path: &Path, archived: Option<AudioQuality>) -> Option<AudioQuality> {
+    if archived.is_some() {
+        return archived;
+    }
+    let path = path.to_path_buf();
+    let tag = tokio::task::spawn_blocking(move || Tag::read_from_path(path))
+        .await
+        .ok()?
+        .ok()?;
+    if let Some(quality) = tag.get_vorbis(QUALITY_TAG).and_then(|mut q| q.next()) {
+        return AudioQuality::from_str(quality).ok();
+    }
+    // files not tagged by tdl, such as ones from tidal-dl
+    match tag.get_streaminfo()?.bits_per_sample {
+        0..=16 => Some(AudioQuality::Lossless),
+        _ => Some(AudioQuality::HiRes),
+    }
+}
+
+/// Whether a file of quality `current` should be replaced by one of quality `available`.
+/// Files of unknown quality, such as M4A files from other tools, are kept,
+/// as nothing shows the download would be better, and they'd be downloaded again on every run.
+fn is_lower(current: Option<AudioQuality>, available: AudioQuality) -> bool {
+    match current {
+        Some(current) => current < available,
+        None => false,
+    }
+}
+
+fn is_flac(path: &Path) -> bool {
+    matches!(path.extension(), Some(ext) if ext.eq_ignore_ascii_case("flac"))
+}
+
+/// Swaps `old` for the completed download at `part`, which is moved to `path`.
+///
+/// The old file is kept in a `.tdl-trash` folder next to it until the new file is verified,
+/// and is put back if anything fails.
+async fn replace_file(old: &Path, part: &Path, path: &Path, track_id: usize) -> Result<(), Error> {
+    let parent = old
+        .parent()
+        .ok_or_else(|| anyhow!("Parent Directory Doesn't exist"))?;
+    let file_name = old
+        .file_name()
+        .ok_or_else(|| anyhow!("Path has no file name"))?;
+    let trash_dir = parent.join(".tdl-trash");
+    tokio::fs::create_dir_all(&trash_dir).await?;
+    let trash = trash_dir.join(file_name);
+    tokio::fs::rename(old, &trash).await?;
+
+    let verified = match tokio::fs::rename(part, path).await {
+        Ok(_) => match is_flac(path) {
+            true => read_track_id(path).await == Some(track_id),
+            false => path.exists(),
+        },
+        Err(_) => false,
     };
-    mp.set_draw_target(draw_target);
-    mp
+    if !verified {
+        // the old file is in the trash, so anything at `path` is the new download.
+        // It goes back to `part` for inspection, before the old file is restored,
+        // which is usually at the same path
+        if path.exists() {
+            tokio::fs::rename(path, part).await?;
+        }
+        tokio::fs::rename(&trash, old).await?;
+        let _ = tokio::fs::remove_dir(&trash_dir).await;
+        return Err(anyhow!(
+            "Upgrade Failed | {} could not be verified and was left there, kept {}",
+            part.display(),
+            old.display()
+        ));
+    }
+
+    tokio::fs::remove_file(&trash).await?;
+    // only remove the trash folder if nothing else is waiting in it
+    let _ = tokio::fs::remove_dir(&trash_dir).await;
+    Ok(())
+}
+
+pub fn add_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    path.into()
+}
+
+/// Writes `data` to `path` with [`config::write_atomic`], off the async runtime
+async fn write_atomic(path: &Path, data: Vec<u8>) -> Result<(), Error> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || config::write_atomic(&path, &data)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_flac(path: &Path, track_id: &str) {
+        let mut tag = Tag::new();
+        tag.set_vorbis(TRACK_ID_TAG, vec![track_id]);
+        tag.write_to_path(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restores_the_old_file_when_the_upgrade_is_a_different_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("01 Track.flac");
+        let part = add_extension(&path, "part");
+        write_flac(&path, "1");
+        write_flac(&part, "2");
+
+        let result = replace_file(&path, &part, &path, 1).await;
+
+        assert!(result.is_err());
+        assert_eq!(read_track_id(&path).await, Some(1));
+        assert_eq!(read_track_id(&part).await, Some(2));
+        assert!(!dir.path().join(".tdl-trash").exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_the_old_file_once_the_upgrade_is_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("01 Track.flac");
+        let part = add_extension(&path, "part");
+        write_flac(&path, "1");
+        write_flac(&part, "1");
+
+        replace_file(&path, &part, &path, 1).await.unwrap();
+
+        assert_eq!(read_track_id(&path).await, Some(1));
+        assert!(!part.exists());
+        assert!(!dir.path().join(".tdl-trash").exists());
+    }
 }
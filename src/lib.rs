@@ -1,6 +1,17 @@
 pub mod api;
+pub mod archive;
 pub mod cli;
+pub mod client;
 pub mod config;
+pub mod credentials;
 pub mod download;
+pub mod library;
 pub mod login;
+pub mod metrics;
 pub mod models;
+pub mod progress;
+pub mod sanitize;
+pub mod serve;
+pub mod template;
+
+pub use client::{Tdl, TdlBuilder};
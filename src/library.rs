@@ -0,0 +1,803 @@
+use crate::api::models::{Album, Artist, AudioQuality, Track};
+use crate::api::TidalClient;
+use crate::archive::{Archive, ArchiveEntry};
+use crate::config::Settings;
+use crate::download::{add_extension, read_track_id, recorded_quality, TRACK_ID_TAG};
+use crate::models::Memo;
+use anyhow::{anyhow, Error};
+use futures::StreamExt;
+use metaflac::Tag;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Extensions of the audio files tdl downloads
+pub const AUDIO_EXTENSIONS: [&str; 2] = ["flac", "m4a"];
+
+/// Recursively lists every audio file under `dir`, sorted by path.
+/// Hidden files and folders are skipped, such as the `.tdl-trash` folders left by upgrades
+/// and the files relayout moves aside.
+pub fn scan_audio_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if is_audio_file(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+lazy_static::lazy_static! {
+    // album folders made by tidal-dl and tdl's default template contain `[<album id>]`
+    static ref ALBUM_ID: Regex = Regex::new(r"\[(\d+)\]").unwrap();
+    static ref TRACK_NUMBER: Regex = Regex::new(r"^\s*\[?(\d+)").unwrap();
+    static ref DISC_FOLDER: Regex = Regex::new(r"(?i)^(?:cd|disc)\s*(\d+)$").unwrap();
+}
+
+fn is_audio_file(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => AUDIO_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// A single file move, as recorded in the relayout journal
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// The track whose file is moved, so its archive entry can follow it. Unset for sidecars
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_id: Option<usize>,
+}
+
+/// What happened to a file during `relayout`, `relayout_rollback` or `import`,
+/// reported as soon as it happens
+#[derive(Debug)]
+pub enum LibraryEvent {
+    /// Moves are recorded in the journal at this path
+    Journal(PathBuf),
+    /// A track was moved, or would be moved in a dry run
+    Moved { mv: Move, dry_run: bool },
+    /// A file was left where it is, because its new path is taken
+    Blocked(Move),
+    /// A moved file was put back
+    Restored(Move),
+    /// A moved file couldn't be put back, because one of the paths changed since
+    NotRestored(Move),
+    /// A file was recorded in the archive, or would be in a dry run
+    Imported {
+        file: PathBuf,
+        track: Box<Track>,
+        kind: MatchKind,
+        dry_run: bool,
+    },
+    /// No TIDAL track was found for a file
+    Unmatched {
+        file: PathBuf,
+        reason: Option<String>,
+    },
+    /// A file was left alone because it couldn't be handled
+    Skipped { file: PathBuf, reason: String },
+}
+
+impl LibraryEvent {
+    /// Whether a file was left alone, as opposed to being handled
+    pub fn is_problem(&self) -> bool {
+        matches!(
+            self,
+            Self::Blocked(_) | Self::NotRestored(_) | Self::Unmatched { .. } | Self::Skipped { .. }
+        )
+    }
+}
+
+impl fmt::Display for LibraryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Journal(path) => write!(f, "Writing journal to {}", path.display()),
+            Self::Moved { mv, dry_run } => write!(
+                f,
+                "{} | {} -> {}",
+                if *dry_run { "Would move" } else { "Moved" },
+                mv.from.display(),
+                mv.to.display()
+            ),
+            Self::Blocked(mv) => write!(
+                f,
+                "Skipped | {} already exists, not moving {}",
+                mv.to.display(),
+                mv.from.display()
+            ),
+            Self::Restored(mv) => write!(f, "Restored | {}", mv.from.display()),
+            Self::NotRestored(mv) => write!(
+                f,
+                "Skipped | can't move {} back to {}",
+                mv.to.display(),
+                mv.from.display()
+            ),
+            Self::Imported {
+                file,
+                track,
+                kind,
+                dry_run,
+            } => write!(
+                f,
+                "{} | {} -> {} (by {kind})",
+                if *dry_run { "Would import" } else { "Imported" },
+                file.display(),
+                track.get_info()
+            ),
+            Self::Unmatched { file, reason } => match reason {
+                Some(reason) => write!(f, "Unmatched | {}: {reason}", file.display()),
+                None => write!(f, "Unmatched | {}", file.display()),
+            },
+            Self::Skipped { file, reason } => write!(f, "Skipped | {}: {reason}", file.display()),
+        }
+    }
+}
+
+/// Result of `relayout`
+#[derive(Debug, Default)]
+pub struct RelayoutSummary {
+    pub moved: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub dry_run: bool,
+}
+
+impl fmt::Display for RelayoutSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = if self.dry_run { "Would move" } else { "Moved" };
+        write!(
+            f,
+            "{verb} {} tracks, {} already in place, {} skipped",
+            self.moved, self.unchanged, self.skipped
+        )
+    }
+}
+
+/// Result of `relayout_rollback`
+#[derive(Debug, Default)]
+pub struct RollbackSummary {
+    pub restored: usize,
+    /// Moves in the journal
+    pub total: usize,
+}
+
+impl fmt::Display for RollbackSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Restored {} of {} files", self.restored, self.total)
+    }
+}
+
+/// Result of `import`
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub unmatched: usize,
+    pub dry_run: bool,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = if self.dry_run {
+            "Would import"
+        } else {
+            "Imported"
+        };
+        write!(
+            f,
+            "{verb} {} tracks, {} unmatched",
+            self.imported, self.unmatched
+        )
+    }
+}
+
+/// Moves every track under `dir` that was downloaded by tdl to the path
+/// the current `download_paths` templates would give it, along with its sidecar files.
+///
+/// Every move is appended to a journal in `dir` as it happens, which `relayout_rollback` can undo.
+/// The archive entries of the moved tracks are updated with their new paths.
+pub async fn relayout(
+    client: &TidalClient,
+    config: &Settings,
+    dir: &Path,
+    dry_run: bool,
+    mut on_event: impl FnMut(LibraryEvent),
+) -> Result<RelayoutSummary, Error> {
+    let files = scan_audio_files(dir)?;
+    let with_stream = config.download_paths.uses_stream_tokens()?;
+    let albums: Memo<usize, Arc<(Album, Artist)>> = Memo::default();
+
+    let plans: Vec<(PathBuf, Result<Vec<Move>, Error>)> = futures::stream::iter(files)
+        .map(|file| {
+            let albums = albums.clone();
+            async move {
+                let plan = plan_track(client, config, &albums, &file, with_stream).await;
+                (file, plan)
+            }
+        })
+        .buffered(config.workers.into())
+        .collect()
+        .await;
+
+    let mut summary = RelayoutSummary {
+        dry_run,
+        ..Default::default()
+    };
+    // the moves of each track, starting with the track itself
+    let mut groups = Vec::new();
+    let mut sources = HashSet::new();
+    for (file, plan) in plans {
+        let moves = match plan {
+            Ok(moves) => moves,
+            Err(e) => {
+                on_event(LibraryEvent::Skipped {
+                    file,
+                    reason: e.to_string(),
+                });
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        if moves.is_empty() {
+            summary.unchanged += 1;
+            continue;
+        }
+        let moves: Vec<Move> = moves
+            .into_iter()
+            .enumerate()
+            // sidecars are optional, and shared by every track of an album, so only move them once
+            .filter(|(i, mv)| *i == 0 || (mv.from.exists() && !sources.contains(&mv.from)))
+            .map(|(_, mv)| mv)
+            .collect();
+        sources.extend(moves.iter().map(|mv| mv.from.clone()));
+        groups.push(moves);
+    }
+
+    // a target is free if nothing is there, or if what's there is moved away first
+    while let Some((group, i)) = find_blocked(&groups) {
+        let mv = groups[group].remove(i);
+        on_event(LibraryEvent::Blocked(mv));
+        if i == 0 {
+            // leave the sidecars with the track
+            groups.remove(group);
+            summary.skipped += 1;
+        }
+    }
+    let moves: Vec<Move> = groups.into_iter().flatten().collect();
+    summary.moved = moves.iter().filter(|mv| mv.track_id.is_some()).count();
+    if dry_run {
+        for mv in moves {
+            on_event(LibraryEvent::Moved { mv, dry_run });
+        }
+        return Ok(summary);
+    }
+
+    let name = format!(".tdl-relayout-{}.jsonl", chrono::Utc::now().timestamp());
+    let path = dir.join(name);
+    let mut journal = Journal {
+        file: tokio::fs::File::create(&path).await?,
+        archive: Archive::open(Path::new(&config.archive_file))?,
+    };
+    on_event(LibraryEvent::Journal(path));
+
+    // files in the way of another move, such as when two tracks swap paths,
+    // are moved aside first, so every move can go straight to its target
+    let targets: HashSet<&PathBuf> = moves.iter().map(|mv| &mv.to).collect();
+    let mut staged = Vec::with_capacity(moves.len());
+    for mv in &moves {
+        match targets.contains(&mv.from) {
+            true => {
+                let file_name = mv
+                    .from
+                    .file_name()
+                    .ok_or_else(|| anyhow!("{} has no file name", mv.from.display()))?
+                    .to_string_lossy();
+                let aside = mv.from.with_file_name(format!(".{file_name}.tdl-relayout"));
+                journal.record(mv.moved_to(&mv.from, &aside)).await?;
+                staged.push(aside);
+            }
+            false => staged.push(mv.from.clone()),
+        }
+    }
+    for (mv, from) in moves.into_iter().zip(staged) {
+        journal.record(mv.moved_to(&from, &mv.to)).await?;
+        prune_empty_dirs(&mv.from, dir);
+        on_event(LibraryEvent::Moved { mv, dry_run });
+    }
+    Ok(summary)
+}
+
+/// Finds the first move whose target is taken,
+/// either by a file that stays where it is, or by an earlier move
+fn find_blocked(groups: &[Vec<Move>]) -> Option<(usize, usize)> {
+    let sources: HashSet<&PathBuf> = groups.iter().flatten().map(|mv| &mv.from).collect();
+    let mut targets = HashSet::new();
+    for (group, moves) in groups.iter().enumerate() {
+        for (i, mv) in moves.iter().enumerate() {
+            let taken = mv.to.exists() && !sources.contains(&mv.to);
+            if taken || !targets.insert(&mv.to) {
+                return Some((group, i));
+            }
+        }
+    }
+    None
+}
+
+impl Move {
+    /// The same file moved between different paths, such as to or from a temporary name
+    fn moved_to(&self, from: &Path, to: &Path) -> Move {
+        Move {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            track_id: self.track_id,
+        }
+    }
+}
+
+/// Moves files, and records each move in the relayout journal and the archive
+struct Journal {
+    file: tokio::fs::File,
+    archive: Archive,
+}
+
+impl Journal {
+    async fn record(&mut self, mv: Move) -> Result<(), Error> {
+        move_file(&mv.from, &mv.to).await?;
+        let mut line = serde_json::to_string(&mv)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.flush().await?;
+        if let Some(track_id) = mv.track_id {
+            self.archive.relocate(track_id, &mv.from, &mv.to)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reverts every move recorded in a relayout journal, newest first,
+/// and points the archive entries of the tracks back to their old paths
+pub async fn relayout_rollback(
+    config: &Settings,
+    journal: &Path,
+    mut on_event: impl FnMut(LibraryEvent),
+) -> Result<RollbackSummary, Error> {
+    let root = journal
+        .parent()
+        .ok_or_else(|| anyhow!("Journal has no parent directory"))?;
+    let contents = tokio::fs::read_to_string(journal).await?;
+    let moves = contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<Move>)
+        .collect::<Result<Vec<Move>, _>>()?;
+    let archive = Archive::open(Path::new(&config.archive_file))?;
+
+    let mut summary = RollbackSummary {
+        restored: 0,
+        total: moves.len(),
+    };
+    for mv in moves.into_iter().rev() {
+        if !mv.to.exists() || mv.from.exists() {
+            on_event(LibraryEvent::NotRestored(mv));
+            continue;
+        }
+        move_file(&mv.to, &mv.from).await?;
+        prune_empty_dirs(&mv.to, root);
+        if let Some(track_id) = mv.track_id {
+            archive.relocate(track_id, &mv.to, &mv.from)?;
+        }
+        on_event(LibraryEvent::Restored(mv));
+        summary.restored += 1;
+    }
+    Ok(summary)
+}
+
+async fn plan_track(
+    client: &TidalClient,
+    config: &Settings,
+    albums: &Memo<usize, Arc<(Album, Artist)>>,
+    file: &Path,
+    with_stream: bool,
+) -> Result<Vec<Move>, Error> {
+    let id = read_track_id(file)
+        .await
+        .ok_or_else(|| anyhow!("no TIDAL_TRACK_ID tag"))?;
+    let track = client.media.get_track(&id.to_string()).await?;
+    let album = albums
+        .get_or_try_init(track.album.id, || async {
+            Ok(Arc::new(client.media.get_album_artist(&track).await?))
+        })
+        .await?;
+    let (album, artist) = album.as_ref();
+    let stream = match with_stream {
+        true => Some(client.media.get_stream_url(track.id).await?),
+        false => None,
+    };
+
+    let paths = &config.download_paths;
+    let base = paths.get_track_path(track, album.clone(), artist.clone(), stream.as_ref())?;
+    let extension = file
+        .extension()
+        .ok_or_else(|| anyhow!("file has no extension"))?
+        .to_string_lossy();
+    let target = add_extension(&base, &extension);
+    if target == file {
+        return Ok(Vec::new());
+    }
+
+    let mut moves = vec![
+        Move {
+            from: file.to_path_buf(),
+            to: target,
+            track_id: Some(id),
+        },
+        Move {
+            from: file.with_extension("lrc"),
+            to: add_extension(&base, "lrc"),
+            track_id: None,
+        },
+    ];
+
+    let track_dir = file
+        .parent()
+        .ok_or_else(|| anyhow!("file has no parent directory"))?;
+    // the covers of a multi-disc album are in the album folder, above the disc folders
+    let old_dir = match track_dir.parent() {
+        Some(parent) if is_disc_folder(track_dir) => parent,
+        _ => track_dir,
+    };
+    let new_dir = paths.get_album_path(album.clone(), artist.clone())?;
+    if old_dir != new_dir {
+        let cover = config
+            .cover
+            .get_sidecar_name(&paths.sanitizer(), album, artist)?;
+        let cover = Path::new(&cover);
+        moves.push(Move {
+            from: old_dir.join(cover),
+            to: new_dir.join(cover),
+            track_id: None,
+        });
+        moves.push(Move {
+            from: old_dir.join(cover.with_extension("mp4")),
+            to: new_dir.join(cover.with_extension("mp4")),
+            track_id: None,
+        });
+    }
+    Ok(moves)
+}
+
+/// Renames a file, falling back to copying it when `to` is on a different file system
+async fn move_file(from: &Path, to: &Path) -> Result<(), Error> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    if tokio::fs::rename(from, to).await.is_err() {
+        tokio::fs::copy(from, to).await?;
+        tokio::fs::remove_file(from).await?;
+    }
+    Ok(())
+}
+
+/// Removes the folders that contained `path` if they are now empty, stopping at `root`
+fn prune_empty_dirs(path: &Path, root: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == root || !current.starts_with(root) {
+            break;
+        }
+        // fails if the folder still has files in it
+        if std::fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/// How an imported file was matched to a TIDAL track
+#[derive(Debug, Clone, Copy)]
+pub enum MatchKind {
+    TrackId,
+    Isrc,
+    Filename,
+}
+
+impl fmt::Display for MatchKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let str = match self {
+            MatchKind::TrackId => "track id",
+            MatchKind::Isrc => "isrc",
+            MatchKind::Filename => "filename",
+        };
+        fmt.write_str(str)?;
+        Ok(())
+    }
+}
+
+/// The tags of a local file that can identify it
+#[derive(Default)]
+struct LocalTags {
+    track_id: Option<usize>,
+    isrc: Option<String>,
+    title: Option<String>,
+    disc: Option<usize>,
+}
+
+impl LocalTags {
+    async fn read(path: &Path) -> Self {
+        let path = path.to_path_buf();
+        let is_mp4 = matches!(path.extension(), Some(ext) if ext.eq_ignore_ascii_case("m4a"));
+        tokio::task::spawn_blocking(move || match is_mp4 {
+            true => Self::read_mp4(&path),
+            false => Self::read_flac(&path),
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    fn read_flac(path: &Path) -> Self {
+        let tag = match Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(_) => return Self::default(),
+        };
+        let vorbis = |key: &str| {
+            tag.get_vorbis(key)
+                .and_then(|mut values| values.next().map(str::to_string))
+                .filter(|value| !value.trim().is_empty())
+        };
+        Self {
+            track_id: vorbis(TRACK_ID_TAG).and_then(|id| id.parse().ok()),
+            isrc: vorbis("ISRC"),
+            title: vorbis("TITLE"),
+            // DISCNUMBER can be written as `1/2`
+            disc: vorbis("DISCNUMBER").and_then(|d| d.split('/').next()?.trim().parse().ok()),
+        }
+    }
+
+    /// Reads the iTunes atoms of an M4A file. The ISRC is in `----:com.apple.iTunes:ISRC`
+    fn read_mp4(path: &Path) -> Self {
+        let tag = match mp4ameta::Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(_) => return Self::default(),
+        };
+        let text = |value: Option<&str>| {
+            value
+                .map(str::to_string)
+                .filter(|value| !value.trim().is_empty())
+        };
+        Self {
+            track_id: None,
+            isrc: text(tag.isrc()),
+            title: text(tag.title()),
+            disc: tag.disc_number().map(usize::from),
+        }
+    }
+}
+
+/// Matches the audio files under `dir` to TIDAL tracks, and records them in the archive,
+/// so `get` skips them instead of downloading them again.
+///
+/// Files are matched by their `TIDAL_TRACK_ID` tag, then their ISRC tag,
+/// then by an album ID in the folder name and the track number at the start of the file name.
+pub async fn import(
+    client: &TidalClient,
+    config: &Settings,
+    dir: &Path,
+    dry_run: bool,
+    mut on_event: impl FnMut(LibraryEvent),
+) -> Result<ImportSummary, Error> {
+    let archive = Archive::open(Path::new(&config.archive_file))?;
+    let files = scan_audio_files(dir)?;
+    let albums: Memo<usize, Arc<Vec<Track>>> = Memo::default();
+
+    let matches: Vec<_> = futures::stream::iter(files)
+        .map(|file| {
+            let albums = albums.clone();
+            async move {
+                let result = identify(client, &albums, &file).await;
+                (file, result)
+            }
+        })
+        .buffered(config.workers.into())
+        .collect()
+        .await;
+
+    let mut summary = ImportSummary {
+        dry_run,
+        ..Default::default()
+    };
+    for (file, result) in matches {
+        let (track, kind, quality) = match result {
+            Ok(Some(found)) => found,
+            Ok(None) => {
+                on_event(LibraryEvent::Unmatched { file, reason: None });
+                summary.unmatched += 1;
+                continue;
+            }
+            Err(e) => {
+                on_event(LibraryEvent::Unmatched {
+                    file,
+                    reason: Some(e.to_string()),
+                });
+                summary.unmatched += 1;
+                continue;
+            }
+        };
+        if !dry_run {
+            archive.record(ArchiveEntry {
+                track_id: track.id,
+                path: std::fs::canonicalize(&file)?,
+                quality,
+            })?;
+        }
+        on_event(LibraryEvent::Imported {
+            file,
+            track: Box::new(track),
+            kind,
+            dry_run,
+        });
+        summary.imported += 1;
+    }
+    Ok(summary)
+}
+
+async fn identify(
+    client: &TidalClient,
+    albums: &Memo<usize, Arc<Vec<Track>>>,
+    file: &Path,
+) -> Result<Option<(Track, MatchKind, Option<AudioQuality>)>, Error> {
+    let tags = LocalTags::read(file).await;
+    let quality = recorded_quality(file, None).await;
+
+    if let Some(id) = tags.track_id {
+        let track = client.media.get_track(&id.to_string()).await?;
+        return Ok(Some((track, MatchKind::TrackId, quality)));
+    }
+
+    let album_id = album_id_from_path(file);
+    let mut isrc_releases = 0;
+    if let Some(isrc) = &tags.isrc {
+        let tracks = client.search.find_by_isrc(isrc).await?;
+        // prefer the release the file was downloaded from, then one with the same title
+        let track = tracks
+            .iter()
+            .find(|t| Some(t.album.id) == album_id)
+            .or_else(|| {
+                tracks.iter().find(|t| match &tags.title {
+                    Some(title) => t.title.eq_ignore_ascii_case(title),
+                    None => false,
+                })
+            });
+        match track {
+            Some(track) => return Ok(Some((track.clone(), MatchKind::Isrc, quality))),
+            // guessing could archive a different release than the one the file is from
+            None if !tracks.is_empty() => isrc_releases = tracks.len(),
+            None => {}
+        }
+    }
+
+    let unmatched = match isrc_releases {
+        0 => Ok(None),
+        n => Err(anyhow!(
+            "its ISRC is on {n} releases, none of them from the same album or with the same title"
+        )),
+    };
+    let (album_id, number) = match (album_id, track_number_from_path(file)) {
+        (Some(album_id), Some(number)) => (album_id, number),
+        _ => return unmatched,
+    };
+    let volume = tags.disc.or_else(|| disc_from_path(file)).unwrap_or(1);
+    let tracks = albums
+        .get_or_try_init(album_id, || async {
+            Ok(Arc::new(client.media.get_album_tracks(album_id).await?))
+        })
+        .await?;
+    match tracks
+        .iter()
+        .find(|t| t.track_number == number && t.volume_number == volume)
+    {
+        Some(track) => Ok(Some((track.clone(), MatchKind::Filename, quality))),
+        None => unmatched,
+    }
+}
+
+/// Finds an album ID in the folder of the file, or the folder above it for multi-disc albums
+fn album_id_from_path(file: &Path) -> Option<usize> {
+    file.ancestors().skip(1).take(2).find_map(|dir| {
+        let name = dir.file_name()?.to_string_lossy();
+        ALBUM_ID
+            .captures_iter(&name)
+            .map(|c| c[1].to_string())
+            // skip `[2019]` style release years
+            .find(|id| id.len() > 4)?
+            .parse()
+            .ok()
+    })
+}
+
+fn track_number_from_path(file: &Path) -> Option<usize> {
+    let stem = file.file_stem()?.to_string_lossy();
+    TRACK_NUMBER.captures(&stem)?[1].parse().ok()
+}
+
+fn disc_from_path(file: &Path) -> Option<usize> {
+    let name = file.parent()?.file_name()?.to_string_lossy();
+    DISC_FOLDER.captures(&name)?[1].parse().ok()
+}
+
+/// Whether `dir` is a `CD2` or `Disc 2` folder inside an album folder
+fn is_disc_folder(dir: &Path) -> bool {
+    matches!(dir.file_name(), Some(name) if DISC_FOLDER.is_match(&name.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_album_ids_from_folder_names() {
+        let file = Path::new("Daft Punk/Discovery [2001] [1234567]/01 One More Time.flac");
+        assert_eq!(album_id_from_path(file), Some(1234567));
+        let disc = Path::new("Daft Punk/Discovery [1234567]/CD2/01 Aerodynamic.flac");
+        assert_eq!(album_id_from_path(disc), Some(1234567));
+        let dated = Path::new("Daft Punk/Discovery [2001]/01 One More Time.flac");
+        assert_eq!(album_id_from_path(dated), None);
+        let nested = Path::new("[1234567]/Daft Punk/Discovery/01 One More Time.flac");
+        assert_eq!(album_id_from_path(nested), None);
+    }
+
+    #[test]
+    fn reads_track_numbers_from_file_names() {
+        assert_eq!(track_number_from_path(Path::new("01 Intro.flac")), Some(1));
+        assert_eq!(
+            track_number_from_path(Path::new(" [12] Outro.m4a")),
+            Some(12)
+        );
+        assert_eq!(track_number_from_path(Path::new("3-Track.flac")), Some(3));
+        assert_eq!(track_number_from_path(Path::new("Intro 01.flac")), None);
+    }
+
+    #[test]
+    fn skips_hidden_files_and_folders() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = dir.path().join("Album");
+        std::fs::create_dir_all(album.join(".tdl-trash")).unwrap();
+        for file in [
+            "01 Intro.flac",
+            ".02 Outro.flac",
+            ".tdl-trash/01 Intro.flac",
+        ] {
+            std::fs::write(album.join(file), "").unwrap();
+        }
+
+        let files = scan_audio_files(dir.path()).unwrap();
+
+        assert_eq!(files, [album.join("01 Intro.flac")]);
+    }
+
+    #[test]
+    fn reads_discs_from_folder_names() {
+        assert_eq!(disc_from_path(Path::new("Album/CD2/01.flac")), Some(2));
+        assert_eq!(disc_from_path(Path::new("Album/disc 10/01.flac")), Some(10));
+        assert_eq!(disc_from_path(Path::new("Album/Disco 2/01.flac")), None);
+        assert_eq!(disc_from_path(Path::new("Album/01.flac")), None);
+        assert!(is_disc_folder(Path::new("Album/Disc1")));
+        assert!(!is_disc_folder(Path::new("Album/CD2 Bonus")));
+    }
+}
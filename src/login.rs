@@ -1,183 +1,338 @@
-use std::pin::Pin;
-
-use crate::api::auth::AuthClient;
-use crate::api::models::DeviceAuthResponse;
-use crate::api::TidalClient;
-use crate::config::CONFIG;
+use crate::api::models::{DeviceAuthResponse, RefreshResponse};
+use crate::api::session::{apply_refresh, REFRESH_MARGIN};
+use crate::client::Tdl;
+use crate::config::{write_private_file, LoginKey};
 use anyhow::anyhow;
 use anyhow::Error;
-use console::{measure_text_width, Emoji, Term};
-use console::{pad_str, style};
-use futures::Future;
-use indicatif::TermLike;
 use log::debug;
-use tokio::task::JoinHandle;
-use tokio::time::{interval, sleep, Duration, Instant};
-
-type LoginResponse = Pin<Box<dyn Future<Output = Result<TidalClient, Error>>>>;
-
-pub async fn login() -> TidalClient {
-    let config = CONFIG.read().await;
-
-    let auth = AuthClient::new(config.api_key.clone());
-    drop(config);
-    let methods: [LoginResponse; 2] = [
-        Box::pin(login_config(auth.clone())),
-        Box::pin(login_web(auth.clone())),
-    ];
-
-    for method in methods {
-        match method.await {
-            Ok(v) => {
-                debug!("Login sucessful");
-                return v;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+/// Exit code of `tdl login --no-interactive` when the login still has to be approved
+pub const EXIT_AUTH_REQUIRED: i32 = 3;
+
+/// Environment variable `tdl login --from-env` reads the refresh token from
+pub const REFRESH_TOKEN_ENV: &str = "TDL_LOGIN_KEY__REFRESH_TOKEN";
+
+/// Where a device login waiting for approval is kept
+pub(crate) enum PendingLogin {
+    /// In a file, so it's found by the next run
+    File(PathBuf),
+    /// Only for the lifetime of the client, when it's built from explicit settings
+    Memory(Mutex<Option<DeviceLogin>>),
+}
+
+impl PendingLogin {
+    fn load(&self) -> Result<Option<DeviceLogin>, Error> {
+        let pending = match self {
+            PendingLogin::File(path) if !path.exists() => None,
+            PendingLogin::File(path) => Some(serde_json::from_str::<DeviceLogin>(
+                &std::fs::read_to_string(path)?,
+            )?),
+            PendingLogin::Memory(pending) => pending
+                .lock()
+                .map_err(|_| anyhow!("Pending login lock poisoned"))?
+                .clone(),
+        };
+        Ok(pending.filter(|pending| pending.expires_in() > 0))
+    }
+
+    fn save(&self, login: &DeviceLogin) -> Result<(), Error> {
+        match self {
+            PendingLogin::File(path) => {
+                write_private_file(path, &serde_json::to_string_pretty(login)?)
+            }
+            PendingLogin::Memory(pending) => {
+                *pending
+                    .lock()
+                    .map_err(|_| anyhow!("Pending login lock poisoned"))? = Some(login.clone());
+                Ok(())
+            }
+        }
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        match self {
+            PendingLogin::File(path) => match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+            PendingLogin::Memory(pending) => {
+                *pending
+                    .lock()
+                    .map_err(|_| anyhow!("Pending login lock poisoned"))? = None;
+                Ok(())
             }
-            Err(e) => eprintln!("{e}"),
         }
     }
+}
+
+/// Result of a login that doesn't prompt
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LoginStatus {
+    LoggedIn {
+        user_id: Option<i64>,
+        country_code: Option<String>,
+    },
+    /// The device login has to be approved at `verification_uri_complete` before it expires
+    AuthorizationRequired {
+        user_code: String,
+        verification_uri: String,
+        verification_uri_complete: String,
+        expires_in: u64,
+    },
+}
 
-    panic!("All Login methods failed")
+/// A device login waiting to be approved by the user at `verification_uri_complete`.
+///
+/// It's kept between runs of `tdl login --no-interactive`, until it's approved or expires.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceLogin {
+    device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    /// Unix timestamp the device code expires at
+    pub expires_at: i64,
+    /// Seconds to wait between checks for the approval
+    #[serde(default = "default_interval")]
+    pub interval: u64,
 }
 
-pub async fn login_web(client: AuthClient) -> Result<TidalClient, Error> {
-    let code = client.get_device_code().await?;
-    let now = Instant::now();
-    let prompt = show_prompt(code.clone(), now);
+fn default_interval() -> u64 {
+    5
+}
 
-    while now.elapsed().as_secs() <= code.expires_in {
-        let login = client.check_auth_status(&code.device_code).await;
-        if login.is_err() {
-            sleep(Duration::from_secs(code.interval)).await;
-            continue;
+impl DeviceLogin {
+    fn new(code: DeviceAuthResponse) -> Self {
+        Self {
+            expires_at: chrono::Utc::now().timestamp() + code.expires_in as i64,
+            device_code: code.device_code,
+            user_code: code.user_code,
+            verification_uri: login_url(&code.verification_uri),
+            verification_uri_complete: login_url(&code.verification_uri_complete),
+            interval: code.interval,
         }
-        hide_prompt(prompt);
-        let timestamp = chrono::Utc::now().timestamp();
-        {
-            let mut config = CONFIG.write().await;
-            //login will not be error if this is reached.
-            let login_results = login?;
-            config.login_key.device_code = Some(code.device_code);
-            config.login_key.access_token = Some(login_results.access_token);
-            config.login_key.refresh_token = login_results.refresh_token;
-            config.login_key.expires_after = Some(login_results.expires_in + timestamp);
-            config.login_key.user_id = Some(login_results.user.user_id);
-            config.login_key.country_code = Some(login_results.user.country_code);
-            config.save()?;
+    }
+
+    /// Seconds left until the device code expires
+    pub fn expires_in(&self) -> u64 {
+        (self.expires_at - chrono::Utc::now().timestamp()).max(0) as u64
+    }
+
+    fn status(&self) -> LoginStatus {
+        LoginStatus::AuthorizationRequired {
+            user_code: self.user_code.clone(),
+            // logins saved by older versions have links without a scheme
+            verification_uri: login_url(&self.verification_uri),
+            verification_uri_complete: login_url(&self.verification_uri_complete),
+            expires_in: self.expires_in(),
         }
-        return Ok(TidalClient::new(&*CONFIG.read().await));
     }
-    hide_prompt(prompt);
-    Err(anyhow!(
-        "Login Request timed out. Please generate a new code"
-    ))
 }
 
-pub async fn login_config(client: AuthClient) -> Result<TidalClient, Error> {
-    let config = CONFIG.read().await;
-    if let Some(access_token) = config.login_key.access_token.as_ref() {
-        debug!("Attempting to validate access token");
-        if client.verify_access_token(access_token).await? {
-            println!("Access Token Valid");
-            return Ok(TidalClient::new(&*config));
+/// The stored login, and the account it belongs to
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AccountStatus {
+    LoggedIn(Account),
+    NotLoggedIn,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Account {
+    pub user_id: i64,
+    pub country_code: String,
+    /// Unix timestamp the access token expires at
+    pub expires_after: Option<i64>,
+    pub subscription: String,
+    pub subscription_status: String,
+    pub valid_until: Option<String>,
+    /// Highest quality the subscription can stream
+    pub highest_quality: Option<String>,
+}
+
+impl Tdl {
+    /// Checks the stored login, and refreshes the access token if it has expired
+    pub async fn login(&self) -> Result<LoginStatus, Error> {
+        let login = self.session.login().await;
+        if let Some(access_token) = login.access_token.as_ref() {
+            let expires_after = login.expires_after.unwrap_or_default();
+            // the client refreshes the token before it expires, so it doesn't need to be checked
+            if expires_after > chrono::Utc::now().timestamp() + REFRESH_MARGIN {
+                debug!("Access token has not expired");
+                return Ok(self.login_status().await);
+            }
+            debug!("Attempting to validate access token");
+            if self.auth.verify_access_token(access_token).await? {
+                debug!("Access token valid");
+                return Ok(self.login_status().await);
+            }
         }
-    }
 
-    if let Some(refresh_token) = config.login_key.refresh_token.as_ref() {
-        debug!("Attempting to refresh access token");
-        let refresh = client.refresh_access_token(refresh_token).await?;
-        drop(config); //Drop our read lock
-        debug!("Access token refreshed");
-        let now = chrono::Utc::now().timestamp();
-        {
-            let mut config = CONFIG.write().await;
-            config.login_key.expires_after = Some(refresh.expires_in + now);
-            config.login_key.access_token = Some(refresh.access_token);
-            debug!("Attempting to save access token");
-            config.save().expect("Failed to Save Access Token");
-            println!("Access Token Refreshed with Refresh Token");
+        if login.refresh_token.is_some() {
+            debug!("Attempting to refresh access token");
+            self.session.refresh(login.access_token.as_deref()).await?;
+            debug!("Access token refreshed with refresh token");
+            return Ok(self.login_status().await);
         }
+        debug!("All methods failed");
+        Err(Error::msg(
+            "Unable to authenticate with both client and refresh token",
+        ))
+    }
 
-        return Ok(TidalClient::new(&*CONFIG.read().await));
+    /// Starts a device login, which the user has to approve before it expires
+    pub async fn device_login(&self) -> Result<DeviceLogin, Error> {
+        Ok(DeviceLogin::new(self.auth.get_device_code().await?))
     }
-    debug!("All methods failed");
-    Err(Error::msg(
-        "Unable to authenticate with both client and refresh token",
-    ))
-}
 
-fn show_prompt(code: DeviceAuthResponse, instant: Instant) -> JoinHandle<()> {
-    tokio::task::spawn(async move {
-        let clocks = vec![
-            "🕛", "🕐", "🕑", "🕒", "🕓", "🕔", "🕕", "🕖", "🕗", "🕘", "🕙", "🕚",
-        ];
-        let mut animation_index = 0;
-        let term = Term::stdout();
-        term.hide_cursor().ok();
-        let mut interval = interval(Duration::from_millis(83));
-        let login_str = fmt_login(&code.verification_uri_complete);
-        let login_str_width = measure_text_width(&login_str);
-        term.write_line(&login_str).ok();
-        loop {
-            interval.tick().await;
-            // re-calc terminal size every tick
-            let term_width: usize = term.width().into();
-            // calculate the time left in the login prompt at the current tick
-            let sec_left = code.expires_in - instant.elapsed().as_secs();
-
-            let mut time_str = format!(
-                "{} {}",
-                // display the current frame of the clock spinning or fallback to empty string
-                Emoji(clocks[animation_index], ""),
-                fmt_time_left(sec_left)
-            );
-
-            // if our terminal is wider than our base text, right align the time
-            if term_width > login_str_width {
-                time_str =
-                    pad_str(&time_str, term_width, console::Alignment::Right, None).to_string();
+    /// Waits until a device login is approved, and stores the login
+    pub async fn wait_for_login(&self, device: &DeviceLogin) -> Result<LoginStatus, Error> {
+        while device.expires_in() > 0 {
+            match self.auth.check_auth_status(&device.device_code).await {
+                Ok(login) => {
+                    self.save_login(&device.device_code, login).await?;
+                    return Ok(self.login_status().await);
+                }
+                Err(_) => sleep(Duration::from_secs(device.interval)).await,
             }
-            // clear the last frame and re-draw
-            term.clear_line().ok();
-            term.write_str(&time_str).ok();
-            animation_index += 1;
-            if animation_index > clocks.len() - 1 {
-                animation_index = 0
+        }
+        Err(anyhow!(
+            "Login Request timed out. Please generate a new code"
+        ))
+    }
+
+    /// Logs in without waiting, for scripts and servers.
+    ///
+    /// A stored login is used if it's still valid. Otherwise a device login is started
+    /// and returned, to be approved by the user. Once it's approved, the next call
+    /// completes the login.
+    pub async fn login_non_interactive(&self) -> Result<LoginStatus, Error> {
+        if self.login().await.is_ok() {
+            return Ok(self.login_status().await);
+        }
+
+        if let Some(pending) = self.pending_login.load()? {
+            match self.auth.check_auth_status(&pending.device_code).await {
+                Ok(login) => {
+                    self.save_login(&pending.device_code, login).await?;
+                    self.pending_login.clear()?;
+                    return Ok(self.login_status().await);
+                }
+                Err(e) => {
+                    debug!("Device login is not approved yet: {e}");
+                    return Ok(pending.status());
+                }
             }
         }
-    })
-}
 
-fn hide_prompt(task: JoinHandle<()>) {
-    task.abort();
-    let _ = Term::stdout().show_cursor();
-}
+        let pending = self.device_login().await?;
+        self.pending_login.save(&pending)?;
+        Ok(pending.status())
+    }
 
-fn fmt_login(uri: &str) -> String {
-    let url = format!("https://{}", uri);
-    // ANSI Hyperlink format.
+    /// Logs in with a refresh token, and stores the login.
+    ///
+    /// Used to provision a machine with a login that was made elsewhere.
+    pub async fn login_refresh_token(&self, refresh_token: &str) -> Result<LoginStatus, Error> {
+        let refresh = self.auth.refresh_access_token(refresh_token).await?;
+        let mut login = LoginKey {
+            refresh_token: Some(refresh_token.to_string()),
+            ..Default::default()
+        };
+        apply_refresh(&mut login, refresh);
+        // saved as it is, so a token given in the environment is stored too
+        self.session.store(login).await?;
+        self.pending_login.clear()?;
+        Ok(self.login_status().await)
+    }
 
-    format!("Please Login to Tidal: {}", style(url).underlined().bold())
-}
+    /// Logs in with the refresh token in `TDL_LOGIN_KEY__REFRESH_TOKEN`, and stores the login
+    pub async fn login_from_env(&self) -> Result<LoginStatus, Error> {
+        let refresh_token = std::env::var(REFRESH_TOKEN_ENV)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| anyhow!("{REFRESH_TOKEN_ENV} is not set"))?;
+        self.login_refresh_token(&refresh_token).await
+    }
 
-// formats a clickable hyperlink in a terminal
-// https://gist.github.com/egmontkob/eb114294efbcd5adb1944c9f3cb5feda
-// https://en.wikipedia.org/wiki/ANSI_escape_code
-fn _fmt_ansi_url(display: &str, url: &str) -> String {
-    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b", display, url)
-}
+    /// Looks up the account of the stored login, refreshing the access token first if it has expired
+    pub async fn account(&self) -> Result<AccountStatus, Error> {
+        let login = self.session.login().await;
+        if login.access_token.is_none() && login.refresh_token.is_none() {
+            return Ok(AccountStatus::NotLoggedIn);
+        }
+        self.login().await?;
 
-fn fmt_time_left(sec_left: u64) -> String {
-    let seconds = sec_left % 60;
-    let mins = (sec_left / 60) % 60;
+        let login = self.session.login().await;
+        let access_token = login
+            .access_token
+            .ok_or_else(|| anyhow!("Not logged in. Run `tdl login`"))?;
+        let session = self.auth.get_session(&access_token).await?;
+        let subscription = self
+            .auth
+            .get_subscription(&access_token, session.user_id, &session.country_code)
+            .await?;
+        Ok(AccountStatus::LoggedIn(Account {
+            user_id: session.user_id,
+            country_code: session.country_code,
+            expires_after: login.expires_after,
+            subscription: subscription.subscription.tier,
+            subscription_status: subscription.status,
+            valid_until: subscription.valid_until,
+            highest_quality: subscription.highest_sound_quality,
+        }))
+    }
 
-    format!("{}:{}", pad_zero(mins), pad_zero(seconds))
+    /// Ends the session with TIDAL, and removes the stored login.
+    /// Returns `false` if there was no login to end.
+    pub async fn logout(&self) -> Result<bool, Error> {
+        let access_token = match self.session.login().await.access_token {
+            Some(access_token) => access_token,
+            None => return Ok(false),
+        };
+        self.auth
+            .logout(access_token)
+            .await
+            .map_err(|e| anyhow!("Error Logging out: {e}"))?;
+        self.session
+            .clear()
+            .await
+            .map_err(|e| anyhow!("Logged out, but the login could not be removed: {e}"))?;
+        Ok(true)
+    }
+
+    async fn login_status(&self) -> LoginStatus {
+        let login = self.session.login().await;
+        LoginStatus::LoggedIn {
+            user_id: login.user_id,
+            country_code: login.country_code,
+        }
+    }
+
+    /// Stores the login of an approved device code
+    async fn save_login(&self, device_code: &str, login: RefreshResponse) -> Result<(), Error> {
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut key = self.session.login().await;
+        key.device_code = Some(device_code.to_string());
+        key.access_token = Some(login.access_token);
+        key.refresh_token = login.refresh_token;
+        key.expires_after = Some(login.expires_in + timestamp);
+        key.user_id = Some(login.user.user_id);
+        key.country_code = Some(login.user.country_code);
+        self.session.save(key).await
+    }
 }
 
-fn pad_zero(s: impl ToString) -> String {
-    let str = s.to_string();
-    if str == "0" {
-        "00".to_string()
+/// TIDAL returns the login link without a scheme
+fn login_url(uri: &str) -> String {
+    if uri.starts_with("http://") || uri.starts_with("https://") {
+        uri.to_string()
     } else {
-        str
+        format!("https://{}", uri)
     }
 }
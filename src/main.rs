@@ -1,132 +1,459 @@
+use chrono::TimeZone;
 use clap::ArgMatches;
 use std::io;
-use tdl::api::auth::AuthClient;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tdl::api::error::TidalError;
 use tdl::api::models::{Album, Artist, Track};
-use tdl::cli::{cli, parse_config_flags};
-use tdl::config::CONFIG;
-use tdl::download::dispatch_downloads;
-use tdl::download::ReceiveChannel;
+use tdl::api::TidalClient;
+use tdl::cli::{cli, hide_prompt, parse_config_flags, show_prompt};
+use tdl::config::{
+    config_location, get_config, get_value, is_secret, reset_config, set_config_location,
+    set_config_value, token_reference, validate_config_file,
+};
+use tdl::download::event_channel;
+use tdl::library::LibraryEvent;
 use tdl::login::*;
+use tdl::metrics;
+use tdl::progress::{show_progress, ProgressMode};
+use tdl::serve::{self, JobQueue};
+use tdl::Tdl;
 
 use clap_complete::{generate, Shell};
 use clap_complete_fig::Fig;
 
 use env_logger::Env;
-use futures::future::join_all;
-use futures::StreamExt;
 
 use log::debug;
-use tokio::join;
-use tokio_stream::wrappers::ReceiverStream;
+use tabled::TableIteratorExt;
 
 #[tokio::main]
 async fn main() {
-    // read from config to always trigger initialization of the default config if it doesn't exist
-    // then release lock immediately.
-    {
-        CONFIG.read().await;
-    }
     env_logger::Builder::from_env(Env::default().default_filter_or("none")).init();
     let matches = cli().get_matches();
+    let config_file = matches.get_one::<PathBuf>("config").cloned();
+    let profile = matches.get_one::<String>("profile").cloned();
+    if let Err(e) = set_config_location(config_file, profile) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    // these don't need the settings, and config can inspect and fix a config that doesn't load
     match matches.subcommand() {
-        Some(("get", get_matches)) => get(get_matches).await,
-        Some(("search", search_matches)) => search(search_matches).await,
-        Some(("login", _)) => {
-            login().await;
+        Some(("config", matches)) => return config(matches),
+        Some(("autocomplete", matches)) => return autocomplete(matches),
+        _ => {}
+    }
+
+    // loading the settings also writes the default config if it doesn't exist
+    let mut settings = match get_config() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Unable to get configuration: {e}");
+            std::process::exit(1);
         }
-        Some(("logout", _)) => logout().await,
-        Some(("autocomplete", matches)) => autocomplete(matches),
+    };
+    // the client reads the requested quality, so flags have to be applied before it's built
+    if let Some(("get", matches)) = matches.subcommand() {
+        parse_config_flags(&mut settings, matches);
+    }
+    let tdl = match Tdl::builder().settings(settings).profile().build() {
+        Ok(tdl) => tdl,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(exit_code(&e));
+        }
+    };
+    match matches.subcommand() {
+        Some(("get", get_matches)) => get(&tdl, get_matches).await,
+        Some(("search", search_matches)) => search(&tdl, search_matches).await,
+        Some(("login", matches)) => match matches.subcommand() {
+            Some(("status", matches)) => whoami(&tdl, matches).await,
+            _ => login_command(&tdl, matches).await,
+        },
+        Some(("whoami", matches)) => whoami(&tdl, matches).await,
+        Some(("logout", _)) => logout(&tdl).await,
+        Some(("relayout", matches)) => relayout(&tdl, matches).await,
+        Some(("import", matches)) => import(&tdl, matches).await,
+        Some(("serve", matches)) => serve(tdl, matches).await,
         _ => unreachable!(), // If all subcommands are defined above, anything else is unreachable!()
     }
 }
 
-async fn get(matches: &ArgMatches) {
-    let client = login().await;
-
-    parse_config_flags(matches).await;
+async fn get(tdl: &Tdl, matches: &ArgMatches) {
+    client(tdl).await;
     if let Some(urls) = matches.get_many::<String>("URL") {
-        let url: Vec<String> = urls.map(|i| i.to_owned()).collect();
+        let urls: Vec<String> = urls.map(|i| i.to_owned()).collect();
         debug!("Collected args");
-        let (handles, download, worker) = dispatch_downloads(url, client)
-            .await
-            .expect("Unable to dispatch download thread");
-        let config = CONFIG.read().await;
-        join!(
-            join_all(handles),
-            consume_channel(download, config.downloads.into(),),
-            consume_channel(worker, config.workers.into())
-        );
-    }
-}
-
-async fn consume_channel(channel: ReceiveChannel, concurrency: usize) {
-    //The channel receives an unexecuted future as a stream
-    ReceiverStream::new(channel)
-        //execute that future in a greenthread
-        .map(|i| async { tokio::task::spawn(i).await })
-        //up to a maximum concurrent tasks at a single time
-        .buffer_unordered(concurrency)
-        .for_each(|r| async {
-            match r {
-                Ok(l) => match l {
-                    Ok(_) => {}
-                    //if the task failed
-                    Err(f) => eprintln!("{f}"),
-                },
-                // if we failed to launch the task
-                Err(e) => eprintln!("{e}"),
+        let mode = match matches.get_one::<String>("progress-format") {
+            Some(mode) => mode.parse().unwrap_or(ProgressMode::Bars),
+            None if tdl.settings().show_progress => ProgressMode::Bars,
+            None => ProgressMode::None,
+        };
+        // the metrics are only served for as long as the download runs
+        let metrics = match tdl.settings().metrics_address {
+            Some(addr) => match metrics::serve(tdl.metrics(), addr) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            },
+            None => None,
+        };
+        let (events, receiver) = event_channel();
+        let progress = tokio::spawn(show_progress(
+            receiver,
+            mode,
+            tdl.settings().progress_refresh_rate,
+        ));
+        let summary = tdl
+            .download_with_events(urls, matches.contains_id("upgrade"), events)
+            .await;
+        // failures were shown as they happened, so wait for the last of them
+        let _ = progress.await;
+        if let Some(server) = metrics {
+            server.abort();
+        }
+        let summary = match summary {
+            Ok(summary) => summary,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(exit_code(&e));
+            }
+        };
+        // a login that has to be renewed outranks any other failure
+        let code = summary.failed.iter().map(exit_code).max().unwrap_or(0);
+        if code != 0 {
+            std::process::exit(code);
+        }
+    }
+}
+
+/// Downloads the jobs submitted to the API, until tdl is stopped
+async fn serve(tdl: Tdl, matches: &ArgMatches) {
+    client(&tdl).await;
+    let addr = matches
+        .get_one::<SocketAddr>("address")
+        .copied()
+        .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 8686)));
+    let jobs = match JobQueue::open(&serve::jobs_path()) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let warning = serve::exposure(addr, tdl.settings().serve_token.as_deref());
+    let server = match serve::serve(Arc::new(tdl), Arc::new(jobs), addr) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(warning) = warning {
+        eprintln!("Warning: {warning}");
+    }
+    println!("Serving on http://{addr}");
+    let _ = server.await;
+}
+
+/// Logs in with the stored login, or by prompting for a device login
+async fn login(tdl: &Tdl) -> Result<LoginStatus, anyhow::Error> {
+    match tdl.login().await {
+        Ok(status) => return Ok(status),
+        // the prompt is the next login method, so this error is only shown
+        Err(e) => eprintln!("{e}"),
+    }
+    let device = tdl.device_login().await?;
+    let prompt = show_prompt(&device);
+    let status = tdl.wait_for_login(&device).await;
+    hide_prompt(prompt);
+    debug!("Login sucessful");
+    status
+}
+
+/// Logs in, or exits if that's not possible
+async fn client(tdl: &Tdl) -> &TidalClient {
+    if let Err(e) = login(tdl).await {
+        eprintln!("{e}");
+        std::process::exit(exit_code(&e));
+    }
+    tdl.client()
+}
+
+/// Exit code for an error, so scripts can tell a login that has to be renewed apart
+fn exit_code(e: &anyhow::Error) -> i32 {
+    match e.downcast_ref::<TidalError>() {
+        Some(TidalError::Unauthorized { .. }) => EXIT_AUTH_REQUIRED,
+        _ => 1,
+    }
+}
+
+async fn login_command(tdl: &Tdl, matches: &ArgMatches) {
+    let json = matches.get_one::<String>("format").map(String::as_str) == Some("json");
+    let result = if let Some(token) = matches.get_one::<String>("refresh-token") {
+        tdl.login_refresh_token(token).await
+    } else if matches.contains_id("from-env") {
+        tdl.login_from_env().await
+    } else if matches.contains_id("no-interactive") {
+        tdl.login_non_interactive().await
+    } else {
+        login(tdl).await
+    };
+
+    match result {
+        Ok(status) => {
+            if json {
+                // the status only holds strings and numbers, so it always serializes
+                println!("{}", serde_json::to_string(&status).unwrap_or_default());
+            } else {
+                print_login_status(&status);
+            }
+            if matches!(status, LoginStatus::AuthorizationRequired { .. }) {
+                std::process::exit(EXIT_AUTH_REQUIRED);
+            }
+        }
+        Err(e) => {
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "status": "error", "message": e.to_string() })
+                );
+            } else {
+                eprintln!("{e}");
+            }
+            std::process::exit(exit_code(&e));
+        }
+    }
+}
+
+fn print_login_status(status: &LoginStatus) {
+    match status {
+        LoginStatus::LoggedIn {
+            user_id,
+            country_code,
+        } => println!(
+            "Logged in as user {} ({})",
+            user_id.map(|id| id.to_string()).unwrap_or_default(),
+            country_code.as_deref().unwrap_or_default()
+        ),
+        LoginStatus::AuthorizationRequired {
+            user_code,
+            verification_uri,
+            verification_uri_complete,
+            expires_in,
+        } => {
+            println!("Login required: {verification_uri_complete}");
+            println!("Or enter the code {user_code} at {verification_uri}");
+            println!("The code expires in {expires_in} seconds. Run this command again once the login is approved");
+        }
+    }
+}
+
+async fn whoami(tdl: &Tdl, matches: &ArgMatches) {
+    let json = matches.get_one::<String>("format").map(String::as_str) == Some("json");
+    let status = match tdl.account().await {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(exit_code(&e));
+        }
+    };
+    if json {
+        // the status only holds strings and numbers, so it always serializes
+        println!("{}", serde_json::to_string(&status).unwrap_or_default());
+    }
+    match status {
+        AccountStatus::LoggedIn(account) if !json => {
+            println!("User ID:          {}", account.user_id);
+            println!("Country:          {}", account.country_code);
+            println!("Token expires:    {}", fmt_expiry(account.expires_after));
+            println!(
+                "Subscription:     {} ({})",
+                account.subscription, account.subscription_status
+            );
+            if let Some(valid_until) = account.valid_until {
+                println!("Valid until:      {valid_until}");
             }
-        })
-        .await;
+            println!(
+                "Highest quality:  {}",
+                account.highest_quality.as_deref().unwrap_or("Unknown")
+            );
+        }
+        AccountStatus::LoggedIn(_) => {}
+        AccountStatus::NotLoggedIn => {
+            if !json {
+                println!("Not logged in. Run `tdl login`");
+            }
+            std::process::exit(EXIT_AUTH_REQUIRED);
+        }
+    }
+}
+
+fn fmt_expiry(expires_after: Option<i64>) -> String {
+    let expires_after = match expires_after {
+        Some(timestamp) => timestamp,
+        None => return "Unknown".to_string(),
+    };
+    let time = match chrono::Utc.timestamp_opt(expires_after, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => return "Unknown".to_string(),
+    };
+    let left = expires_after - chrono::Utc::now().timestamp();
+    if left <= 0 {
+        return format!("{time} (expired)");
+    }
+    format!("{time} (in {}h {}m)", left / 3600, (left / 60) % 60)
 }
 
-async fn search(matches: &ArgMatches) {
-    let client = login().await;
+async fn search(tdl: &Tdl, matches: &ArgMatches) {
+    let client = client(tdl).await;
     if let Some(query) = matches.get_one::<String>("query") {
-        let max = matches.get_one::<usize>("max").cloned();
+        let max = matches.get_one::<u32>("max").map(|max| *max as usize);
         let result = match matches.get_one::<String>("filter") {
             Some(filter) => match filter.as_str() {
-                "artist" => {
-                    client
-                        .search
-                        .search_content::<Artist>("artists", query, max)
-                        .await
-                }
-                "track" => {
-                    client
-                        .search
-                        .search_content::<Track>("tracks", query, max)
-                        .await
-                }
-                "album" => {
-                    client
-                        .search
-                        .search_content::<Album>("albums", query, max)
-                        .await
-                }
+                "artist" => client
+                    .search
+                    .search::<Artist>("artists", query, max)
+                    .await
+                    .map(|items| items.table()),
+                "track" => client
+                    .search
+                    .search::<Track>("tracks", query, max)
+                    .await
+                    .map(|items| items.table()),
+                "album" => client
+                    .search
+                    .search::<Album>("albums", query, max)
+                    .await
+                    .map(|items| items.table()),
                 _ => unreachable!(),
             },
             None => todo!(), //search all
         };
         match result {
             Ok(t) => println!("{t}"),
-            Err(e) => eprintln!("{e}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(exit_code(&e));
+            }
         }
     }
 }
 
-async fn logout() {
-    let config = CONFIG.read().await;
-    match config.login_key.access_token.clone() {
-        Some(token) => match AuthClient::new(config.api_key.clone())
-            .logout(token.to_owned())
+async fn logout(tdl: &Tdl) {
+    match tdl.logout().await {
+        Ok(true) => println!("Logout Sucessful"),
+        Ok(false) => println!("No Auth Token is configured to logout with"),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(exit_code(&e));
+        }
+    }
+}
+
+fn print_event(event: LibraryEvent) {
+    match event.is_problem() {
+        true => eprintln!("{event}"),
+        false => println!("{event}"),
+    }
+}
+
+async fn relayout(tdl: &Tdl, matches: &ArgMatches) {
+    let result = match matches.get_one::<PathBuf>("rollback") {
+        Some(journal) => tdl
+            .relayout_rollback(journal, print_event)
             .await
-        {
-            Ok(_) => println!("Logout Sucessful"),
-            Err(e) => eprintln!("Error Logging out: {e}"),
+            .map(|summary| summary.to_string()),
+        None => {
+            client(tdl).await;
+            // required unless rollback is present
+            let dir = matches.get_one::<PathBuf>("dir").unwrap();
+            tdl.relayout(dir, matches.contains_id("dry-run"), print_event)
+                .await
+                .map(|summary| summary.to_string())
+        }
+    };
+    match result {
+        Ok(summary) => println!("{summary}"),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(exit_code(&e));
+        }
+    }
+}
+
+async fn import(tdl: &Tdl, matches: &ArgMatches) {
+    client(tdl).await;
+    // dir is a required argument
+    let dir = matches.get_one::<PathBuf>("dir").unwrap();
+    match tdl
+        .import(dir, matches.contains_id("dry-run"), print_event)
+        .await
+    {
+        Ok(summary) => println!("{summary}"),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(exit_code(&e));
+        }
+    }
+}
+
+fn config(matches: &ArgMatches) {
+    let result = match matches.subcommand() {
+        Some(("show", matches)) => get_config()
+            .and_then(|settings| settings.to_value(matches.contains_id("show-secrets")))
+            .and_then(|value| Ok(toml::to_string_pretty(&value)?))
+            .map(|value| print!("{value}")),
+        Some(("get", matches)) => config_get(matches),
+        Some(("set", matches)) => {
+            // both are required arguments
+            let key = matches.get_one::<String>("key").unwrap();
+            let value = matches.get_one::<String>("value").unwrap();
+            set_config_value(key, value).map(|_| match is_secret(key) {
+                true => println!("Set {key}"),
+                false => println!("Set {key} to {value}"),
+            })
+        }
+        Some(("path", _)) => {
+            println!("{}", config_location().active_file().display());
+            Ok(())
+        }
+        Some(("reset", matches)) => match matches.get_one::<String>("key") {
+            Some(key) => reset_config(Some(key)).map(|_| println!("Reset {key}")),
+            None => reset_config(None).map(|_| println!("Reset every setting except the login")),
         },
-        None => println!("No Auth Token is configured to logout with"),
+        Some(("validate", _)) => validate_config_file().map(|warnings| {
+            for warning in &warnings {
+                eprintln!("{warning}");
+            }
+            println!("{} is valid", config_location().active_file().display());
+        }),
+        Some(("tokens", _)) => {
+            println!("{}", token_reference().table());
+            Ok(())
+        }
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn config_get(matches: &ArgMatches) -> Result<(), anyhow::Error> {
+    // key is a required argument
+    let key = matches.get_one::<String>("key").unwrap();
+    let value = get_config()?.to_value(matches.contains_id("show-secrets"))?;
+    match get_value(&value, key) {
+        Some(toml::Value::String(s)) => println!("{s}"),
+        Some(value @ toml::Value::Table(_)) => print!("{}", toml::to_string_pretty(value)?),
+        Some(value) => println!("{value}"),
+        None => return Err(anyhow::anyhow!("Unknown setting {key}")),
     }
+    Ok(())
 }
 
 fn autocomplete(matches: &ArgMatches) {
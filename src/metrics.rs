@@ -0,0 +1,271 @@
+use crate::api::error::TidalError;
+use crate::download::ChannelValue;
+use anyhow::{anyhow, Error};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::warn;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::WeakSender;
+use tokio::task::JoinHandle;
+
+/// Upper bounds in seconds of the buckets API request latencies are counted in
+const LATENCY_BUCKETS: [f64; 10] = [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Counters of what a client has done, shared by every download it runs.
+///
+/// Served in the Prometheus text format by [`serve`], when `metrics_address` is set.
+#[derive(Default)]
+pub struct Metrics {
+    tracks_downloaded: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    failures: Mutex<BTreeMap<&'static str, u64>>,
+    api_latency: Mutex<Latency>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// The channels of running downloads. They aren't kept open by being watched
+    queues: Mutex<Vec<(&'static str, WeakSender<ChannelValue>)>>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Latency {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// The current values of the metrics, as served by `/status`
+#[derive(Debug, Serialize)]
+pub struct MetricsStatus {
+    pub tracks_downloaded: u64,
+    pub downloaded_bytes: u64,
+    pub failures: BTreeMap<&'static str, u64>,
+    pub api_requests: u64,
+    pub api_request_seconds: f64,
+    pub queues: BTreeMap<&'static str, usize>,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+impl Metrics {
+    pub fn track_downloaded(&self) {
+        self.tracks_downloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_downloaded(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a failed track or URL under the kind of its error
+    pub fn failure(&self, error: &Error) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        *failures.entry(error_kind(error)).or_default() += 1;
+    }
+
+    pub fn api_request(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut latency = self.api_latency.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            latency.buckets[bucket] += 1;
+        }
+        latency.sum += seconds;
+        latency.count += 1;
+    }
+
+    /// Counts a response by whether the cache answered it
+    pub fn cache_lookup(&self, hit: bool) {
+        match hit {
+            true => self.cache_hits.fetch_add(1, Ordering::Relaxed),
+            false => self.cache_misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Reports the tasks waiting on a channel as the depth of `queue`, while the channel is open
+    pub fn watch_queue(&self, queue: &'static str, channel: WeakSender<ChannelValue>) {
+        let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+        queues.push((queue, channel));
+    }
+
+    fn queue_depths(&self) -> BTreeMap<&'static str, usize> {
+        let mut depths = BTreeMap::from([("downloads", 0), ("workers", 0)]);
+        let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+        queues.retain(|(queue, channel)| match channel.upgrade() {
+            Some(channel) => {
+                *depths.entry(*queue).or_default() += channel.max_capacity() - channel.capacity();
+                true
+            }
+            // every task is done with it
+            None => false,
+        });
+        depths
+    }
+
+    pub fn status(&self) -> MetricsStatus {
+        self.snapshot().0
+    }
+
+    /// The current values, and the latencies they were counted from,
+    /// so the summary and histogram of a render always agree
+    fn snapshot(&self) -> (MetricsStatus, Latency) {
+        let latency = *self.api_latency.lock().unwrap_or_else(|e| e.into_inner());
+        let status = MetricsStatus {
+            tracks_downloaded: self.tracks_downloaded.load(Ordering::Relaxed),
+            downloaded_bytes: self.downloaded_bytes.load(Ordering::Relaxed),
+            failures: self
+                .failures
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+            api_requests: latency.count,
+            api_request_seconds: latency.sum,
+            queues: self.queue_depths(),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+        };
+        (status, latency)
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let (status, latency) = self.snapshot();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        metric(
+            "tdl_tracks_downloaded_total",
+            "counter",
+            "Tracks downloaded",
+            vec![(String::new(), status.tracks_downloaded.to_string())],
+        );
+        metric(
+            "tdl_downloaded_bytes_total",
+            "counter",
+            "Bytes of track files downloaded",
+            vec![(String::new(), status.downloaded_bytes.to_string())],
+        );
+        metric(
+            "tdl_failures_total",
+            "counter",
+            "Tracks and URLs that failed, by the kind of error",
+            status
+                .failures
+                .iter()
+                .map(|(kind, n)| (format!("{{kind=\"{kind}\"}}"), n.to_string()))
+                .collect(),
+        );
+        metric(
+            "tdl_queue_depth",
+            "gauge",
+            "Tasks waiting for a download or worker slot",
+            status
+                .queues
+                .iter()
+                .map(|(queue, n)| (format!("{{queue=\"{queue}\"}}"), n.to_string()))
+                .collect(),
+        );
+        metric(
+            "tdl_cache_requests_total",
+            "counter",
+            "API responses, by whether the cache answered them",
+            vec![
+                (
+                    "{result=\"hit\"}".to_string(),
+                    status.cache_hits.to_string(),
+                ),
+                (
+                    "{result=\"miss\"}".to_string(),
+                    status.cache_misses.to_string(),
+                ),
+            ],
+        );
+        let mut cumulative = 0;
+        let mut samples = Vec::new();
+        for (le, n) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+            cumulative += n;
+            samples.push((format!("_bucket{{le=\"{le}\"}}"), cumulative.to_string()));
+        }
+        samples.push((
+            "_bucket{le=\"+Inf\"}".to_string(),
+            latency.count.to_string(),
+        ));
+        samples.push(("_sum".to_string(), latency.sum.to_string()));
+        samples.push(("_count".to_string(), latency.count.to_string()));
+        metric(
+            "tdl_api_request_duration_seconds",
+            "histogram",
+            "Time taken by requests to TIDAL's API, including waits for rate limits",
+            samples,
+        );
+        out
+    }
+}
+
+/// A short name for the kind of an error, such as `not_found` or `network`
+pub fn error_kind(error: &Error) -> &'static str {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<TidalError>() {
+            return e.kind();
+        }
+        if cause.is::<reqwest::Error>() || cause.is::<reqwest_middleware::Error>() {
+            return "network";
+        }
+        if cause.is::<std::io::Error>() {
+            return "io";
+        }
+    }
+    "other"
+}
+
+/// Serves `/metrics` in the Prometheus text format, and `/status` as JSON, on `addr`.
+///
+/// Fails if `addr` can't be bound. The server runs until the returned task is aborted.
+pub fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> Result<JoinHandle<()>, Error> {
+    let server =
+        Server::try_bind(&addr).map_err(|e| anyhow!("Unable to serve metrics on {addr}: {e}"))?;
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&metrics, req)) }
+            }))
+        }
+    });
+    Ok(tokio::spawn(async move {
+        if let Err(e) = server.serve(make_service).await {
+            warn!("The metrics server stopped: {e}");
+        }
+    }))
+}
+
+/// Answers `/metrics` and `/status`, and anything else with a 404
+pub(crate) fn respond(metrics: &Metrics, req: Request<Body>) -> Response<Body> {
+    let (content_type, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => ("text/plain; version=0.0.4", metrics.render()),
+        (&Method::GET, "/status") => (
+            "application/json",
+            serde_json::to_string(&metrics.status()).unwrap_or_default(),
+        ),
+        _ => {
+            let mut res = Response::new(Body::from("Not Found"));
+            *res.status_mut() = StatusCode::NOT_FOUND;
+            return res;
+        }
+    };
+    let mut res = Response::new(Body::from(body));
+    if let Ok(value) = content_type.parse() {
+        res.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
+    res
+}
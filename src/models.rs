@@ -1,43 +1,12 @@
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use futures::Future;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
 
-use indicatif::{MultiProgress, ProgressStyle};
-
-use crate::api::models::Track;
-use std::ops::Deref;
 use std::{fmt, str::FromStr};
 
-pub struct ProgressBar(indicatif::ProgressBar);
-
-impl ProgressBar {
-    pub fn new(parent: MultiProgress, id: usize) -> Self {
-        let pb = parent.add(indicatif::ProgressBar::new(0));
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{msg}\n{spinner:.green}")
-                .expect("Progress Bar Template is Invalid"),
-        );
-        pb.set_message(format!("Getting Track Details: {}", id));
-
-        Self(pb)
-    }
-
-    pub fn start_download(&self, length: u64, track: &Track) {
-        self.set_length(length);
-        self.set_style(ProgressStyle::default_bar()
-                        .template("{wide_msg}\n{spinner:.green} [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec:4}, ETA: {eta:2})").expect("Progress Bar Template is invalid")
-                        .progress_chars("#>-"));
-        self.set_message(format!("Downloading File | {}", track.get_info()));
-    }
-}
-
-impl Deref for ProgressBar {
-    type Target = indicatif::ProgressBar;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
 #[derive(Debug)]
 pub struct Action {
     pub kind: ActionKind,
@@ -47,7 +16,7 @@ impl FromStr for Action {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let url_parts: Vec<&str> = s.split('/').collect();
-        let [kind, id]: [_; 2] = url_parts[url_parts.len() - 2..].try_into()?;
+        let [kind, id]: [_; 2] = url_parts[url_parts.len().saturating_sub(2)..].try_into()?;
         Ok(Self {
             kind: ActionKind::from_str(kind)?,
             id: id.into(),
@@ -86,3 +55,42 @@ impl fmt::Display for ActionKind {
         Ok(())
     }
 }
+
+/// Runs an async initializer at most once per key.
+/// Callers that ask for a key while it's being initialized wait for, and share, the same result.
+/// A failed initialization isn't stored, so the next caller will retry it.
+pub struct Memo<K, V>(Arc<std::sync::Mutex<HashMap<K, Arc<OnceCell<V>>>>>);
+
+impl<K, V> Memo<K, V>
+where
+    K: Eq + Hash,
+    V: Clone,
+{
+    pub async fn get_or_try_init<F, Fut>(&self, key: K, init: F) -> Result<V, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, Error>>,
+    {
+        let cell = self
+            .0
+            .lock()
+            .map_err(|_| anyhow!("Memo lock poisoned"))?
+            .entry(key)
+            .or_default()
+            .clone();
+        cell.get_or_try_init(init).await.cloned()
+    }
+}
+
+impl<K, V> Default for Memo<K, V> {
+    fn default() -> Self {
+        Self(Arc::new(std::sync::Mutex::new(HashMap::new())))
+    }
+}
+
+// derive(Clone) would require K and V to be Clone
+impl<K, V> Clone for Memo<K, V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
@@ -0,0 +1,127 @@
+use crate::download::{DownloadEvent, EventReceiver};
+use anyhow::{anyhow, Error};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::HashMap;
+use std::{fmt, str::FromStr};
+
+/// How the progress of a download is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// A progress bar for every file being downloaded
+    Bars,
+    /// Every event as a line of JSON on stdout
+    Json,
+    /// Only failures, on stderr
+    None,
+}
+
+impl FromStr for ProgressMode {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "bars" => Ok(ProgressMode::Bars),
+            "json" => Ok(ProgressMode::Json),
+            "none" => Ok(ProgressMode::None),
+            _ => Err(anyhow!(
+                "Unknown progress mode {input}. Use bars, json or none"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProgressMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let str = match self {
+            ProgressMode::Bars => "bars",
+            ProgressMode::Json => "json",
+            ProgressMode::None => "none",
+        };
+        fmt.write_str(str)
+    }
+}
+
+/// Shows the events of a download until it's done
+pub async fn show_progress(events: EventReceiver, mode: ProgressMode, refresh_rate: u8) {
+    match mode {
+        ProgressMode::Bars => show_bars(events, refresh_rate).await,
+        ProgressMode::Json => print_json(events).await,
+        ProgressMode::None => print_failures(events).await,
+    }
+}
+
+async fn show_bars(mut events: EventReceiver, refresh_rate: u8) {
+    let progress =
+        MultiProgress::with_draw_target(ProgressDrawTarget::stdout_with_hz(refresh_rate));
+    let mut bars: HashMap<usize, ProgressBar> = HashMap::new();
+    // the names of tracks that have been looked up
+    let mut names: HashMap<usize, String> = HashMap::new();
+    while let Some(event) = events.recv().await {
+        let track_id = event.track_id().unwrap_or_default();
+        let name = names
+            .get(&track_id)
+            .cloned()
+            .unwrap_or_else(|| track_id.to_string());
+        match &event {
+            DownloadEvent::Queued { .. } => {}
+            DownloadEvent::MetadataFetched { info, .. } => {
+                names.insert(track_id, info.clone());
+            }
+            DownloadEvent::Started { size, .. } => {
+                let bar = progress.add(ProgressBar::new(*size));
+                bar.set_style(download_style());
+                bar.set_message(format!("Downloading File | {name}"));
+                bars.insert(track_id, bar);
+            }
+            DownloadEvent::Progress { downloaded, .. } => {
+                if let Some(bar) = bars.get(&track_id) {
+                    bar.set_position(*downloaded);
+                }
+            }
+            DownloadEvent::Finishing { step, .. } => {
+                if let Some(bar) = bars.get(&track_id) {
+                    bar.set_message(format!("{step} | {name}"));
+                }
+            }
+            DownloadEvent::Completed { .. } => {
+                if let Some(bar) = bars.remove(&track_id) {
+                    bar.finish_and_clear();
+                }
+                names.remove(&track_id);
+                let _ = progress.println(format!("Download Complete | {name}"));
+            }
+            DownloadEvent::Failed { .. } => {
+                if let Some(bar) = bars.remove(&track_id) {
+                    bar.finish_and_clear();
+                }
+                // failures go to stderr, like every other error
+                progress.suspend(|| eprintln!("{event}"));
+            }
+            _ => {
+                let _ = progress.println(event.to_string());
+            }
+        }
+    }
+}
+
+fn download_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{wide_msg}\n{spinner:.green} [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec:4}, ETA: {eta:2})")
+        .map(|style| style.progress_chars("#>-"))
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+}
+
+async fn print_json(mut events: EventReceiver) {
+    while let Some(event) = events.recv().await {
+        if let Ok(line) = serde_json::to_string(&event) {
+            println!("{line}");
+        }
+    }
+}
+
+async fn print_failures(mut events: EventReceiver) {
+    while let Some(event) = events.recv().await {
+        if let DownloadEvent::Failed { .. } = event {
+            eprintln!("{event}");
+        }
+    }
+}
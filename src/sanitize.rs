@@ -0,0 +1,193 @@
+use sanitize_filename::{sanitize_with_options, Options};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization as _;
+
+/// Which file systems the generated names have to be valid on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilenameMode {
+    /// Removes `/` and control characters, which are invalid on Linux and macOS,
+    /// and `? < > \ : * | "`, which other tools and file systems often can't handle.
+    /// Keeps names like `CON`, and trailing dots and spaces.
+    Posix,
+    /// Also safe for NTFS, FAT32, exFAT and Samba shares.
+    /// Removes reserved characters, reserved names like `CON`, and trailing dots and spaces.
+    WindowsSafe,
+    /// `WindowsSafe`, with every non-ASCII character transliterated. `Beyoncé` becomes `Beyonce`
+    AsciiOnly,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeNormalization {
+    None,
+    /// Composed form, used by Linux and Windows
+    Nfc,
+    /// Decomposed form, used by macOS on HFS+
+    Nfd,
+}
+
+/// Makes token values and path components safe to use as file names
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    pub mode: FilenameMode,
+    pub max_component_length: usize,
+    pub normalization: UnicodeNormalization,
+}
+
+lazy_static::lazy_static! {
+    static ref WINDOWS_RESERVED: regex::Regex =
+        regex::Regex::new(r"(?i)^(con|prn|aux|nul|com[0-9]|lpt[0-9])(\..*)?$").unwrap();
+}
+
+impl Sanitizer {
+    /// Sanitizes the value of a single token
+    pub fn token(&self, value: &str) -> String {
+        let value = self.normalize(value);
+        let options = Options {
+            windows: self.mode != FilenameMode::Posix,
+            // length is enforced per component, after tokens are combined
+            truncate: false,
+            replacement: "",
+        };
+        sanitize_with_options(value, options)
+    }
+
+    /// Sanitizes the literal text of a template. `/` is kept, as it separates folders.
+    /// Whole components are checked once the path is rendered, by [`Sanitizer::path`].
+    pub fn literal(&self, text: &str) -> String {
+        self.normalize(text)
+            .chars()
+            .filter(|c| *c == '/' || !is_illegal(*c))
+            .collect()
+    }
+
+    fn normalize(&self, value: &str) -> String {
+        let value: String = match self.normalization {
+            UnicodeNormalization::None => value.to_string(),
+            UnicodeNormalization::Nfc => value.nfc().collect(),
+            UnicodeNormalization::Nfd => value.nfd().collect(),
+        };
+        match self.mode {
+            FilenameMode::AsciiOnly => deunicode::deunicode(&value),
+            _ => value,
+        }
+    }
+
+    /// Sanitizes a rendered path, one component at a time.
+    /// `reserve` bytes are kept free in the last component, for an extension added later.
+    pub fn path(&self, value: &str, reserve: usize) -> String {
+        let components: Vec<&str> = value.split('/').collect();
+        let last = components.len() - 1;
+        components
+            .iter()
+            .enumerate()
+            .map(|(i, c)| match i == last {
+                true => self.component(c, reserve),
+                false => self.component(c, 0),
+            })
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    fn component(&self, value: &str, reserve: usize) -> String {
+        let max = self.max_component_length.saturating_sub(reserve).max(1);
+        let mut end = value.len().min(max);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut value = value[..end].to_string();
+        if self.mode != FilenameMode::Posix {
+            value = value.trim_end_matches(['.', ' ']).to_string();
+            if WINDOWS_RESERVED.is_match(&value) {
+                value.insert(0, '_');
+            }
+        }
+        value
+    }
+}
+
+/// Characters that sanitize_filename removes from token values
+fn is_illegal(c: char) -> bool {
+    matches!(c, '/' | '?' | '<' | '>' | '\\' | ':' | '*' | '|' | '"')
+        || matches!(c, '\x00'..='\x1f' | '\u{80}'..='\u{9f}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitizer(mode: FilenameMode) -> Sanitizer {
+        Sanitizer {
+            mode,
+            max_component_length: 255,
+            normalization: UnicodeNormalization::Nfc,
+        }
+    }
+
+    #[test]
+    fn removes_separators_from_tokens() {
+        let posix = sanitizer(FilenameMode::Posix);
+        assert_eq!(posix.token("AC/DC"), "ACDC");
+        assert_eq!(posix.token("What? Why: Because"), "What Why Because");
+    }
+
+    #[test]
+    fn keeps_separators_in_literals() {
+        let posix = sanitizer(FilenameMode::Posix);
+        assert_eq!(posix.literal("Music/Albums?"), "Music/Albums");
+    }
+
+    #[test]
+    fn makes_components_windows_safe() {
+        let posix = sanitizer(FilenameMode::Posix);
+        let windows = sanitizer(FilenameMode::WindowsSafe);
+        assert_eq!(posix.path("Con/Track.", 0), "Con/Track.");
+        assert_eq!(windows.path("Con/Track. ", 0), "_Con/Track");
+        assert_eq!(windows.path("lpt1.txt/aux", 0), "_lpt1.txt/_aux");
+        assert_eq!(windows.path("Console/Auxiliary", 0), "Console/Auxiliary");
+    }
+
+    #[test]
+    fn transliterates_in_ascii_only_mode() {
+        let ascii = sanitizer(FilenameMode::AsciiOnly);
+        assert_eq!(ascii.token("Beyoncé"), "Beyonce");
+        assert_eq!(ascii.literal("Música/"), "Musica/");
+        assert_eq!(
+            sanitizer(FilenameMode::WindowsSafe).token("Beyoncé"),
+            "Beyoncé"
+        );
+    }
+
+    #[test]
+    fn normalizes_unicode() {
+        let decomposed = "Beyonce\u{301}";
+        assert_eq!(sanitizer(FilenameMode::Posix).token(decomposed), "Beyoncé");
+        let nfd = Sanitizer {
+            normalization: UnicodeNormalization::Nfd,
+            ..sanitizer(FilenameMode::Posix)
+        };
+        assert_eq!(nfd.token("Beyoncé"), decomposed);
+        let none = Sanitizer {
+            normalization: UnicodeNormalization::None,
+            ..sanitizer(FilenameMode::Posix)
+        };
+        assert_eq!(none.token(decomposed), decomposed);
+    }
+
+    #[test]
+    fn truncates_each_component() {
+        let short = Sanitizer {
+            max_component_length: 8,
+            ..sanitizer(FilenameMode::WindowsSafe)
+        };
+        assert_eq!(
+            short.path("Discovery/One More Time", 0),
+            "Discover/One More"
+        );
+        // room is kept for the extension
+        assert_eq!(short.path("Discovery/One More Time", 5), "Discover/One");
+        // never cuts a character in half
+        assert_eq!(short.path("ééééé", 0), "éééé");
+    }
+}
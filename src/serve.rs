@@ -0,0 +1,500 @@
+use crate::client::Tdl;
+use crate::config::{config_location, write_atomic};
+use crate::download::{event_channel, DownloadEvent, DownloadSummary};
+use crate::metrics;
+use crate::models::Action;
+use anyhow::{anyhow, Error};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::task::{AbortHandle, JoinError, JoinHandle};
+
+/// The page served at `/`, to submit and follow jobs from a browser
+const INDEX: &str = include_str!("serve.html");
+/// Finished jobs that are kept. Older ones are dropped from the queue
+pub const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for the jobs before it
+    Queued,
+    Running,
+    /// Every track was downloaded or skipped
+    Completed,
+    /// At least one track or URL failed
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+/// URLs submitted to be downloaded together, and how far they got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub upgrade: bool,
+    pub state: JobState,
+    /// Unix timestamps
+    pub submitted_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    /// Tracks found in the URLs so far
+    #[serde(default)]
+    pub tracks: usize,
+    #[serde(default)]
+    pub downloaded: usize,
+    #[serde(default)]
+    pub skipped: usize,
+    #[serde(default)]
+    pub failed: usize,
+    /// Why the tracks and URLs that failed did
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+impl Job {
+    /// Counts an event of the job's download
+    fn record(&mut self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::Queued { .. } => self.tracks += 1,
+            DownloadEvent::Completed { .. } => self.downloaded += 1,
+            DownloadEvent::Skipped { .. } => self.skipped += 1,
+            DownloadEvent::Failed { error, .. } => {
+                self.failed += 1;
+                self.errors.push(error.clone());
+            }
+            _ => {}
+        }
+    }
+
+    fn start(&mut self) {
+        self.state = JobState::Running;
+        self.started_at = Some(chrono::Utc::now().timestamp());
+        self.finished_at = None;
+        self.tracks = 0;
+        self.downloaded = 0;
+        self.skipped = 0;
+        self.failed = 0;
+        self.errors.clear();
+    }
+
+    fn finish(&mut self, result: Result<Result<DownloadSummary, Error>, JoinError>) {
+        self.finished_at = Some(chrono::Utc::now().timestamp());
+        if self.state == JobState::Cancelled {
+            return;
+        }
+        match result {
+            Ok(Ok(summary)) => {
+                self.downloaded = summary.downloaded;
+                self.skipped = summary.skipped;
+                self.failed = summary.failed.len();
+                self.errors = summary.failed.iter().map(|e| format!("{e:#}")).collect();
+                self.state = match self.failed {
+                    0 => JobState::Completed,
+                    _ => JobState::Failed,
+                };
+            }
+            Ok(Err(e)) => {
+                self.errors.push(format!("{e:#}"));
+                self.state = JobState::Failed;
+            }
+            Err(e) if e.is_cancelled() => self.state = JobState::Cancelled,
+            Err(e) => {
+                self.errors.push(e.to_string());
+                self.state = JobState::Failed;
+            }
+        }
+    }
+}
+
+/// Where the job queue of the active config file or profile is kept between runs
+pub fn jobs_path() -> PathBuf {
+    config_location().related_file("jobs.json")
+}
+
+/// Jobs waiting to be downloaded, or that were, saved to a file whenever a job is
+/// submitted, starts or finishes, so they survive restarts.
+/// Only the last [`MAX_FINISHED_JOBS`] finished jobs are kept.
+///
+/// Jobs are downloaded one at a time by [`JobQueue::run`], in the order they were submitted.
+pub struct JobQueue {
+    path: PathBuf,
+    jobs: Mutex<Vec<Job>>,
+    /// Wakes the runner when a job is submitted
+    submitted: Notify,
+    /// The job being downloaded, and what stops it
+    running: Mutex<Option<(u64, AbortHandle)>>,
+}
+
+impl JobQueue {
+    /// Loads the jobs saved at `path`. A missing file is an empty queue.
+    /// A job that was running when tdl stopped is queued again,
+    /// and the tracks it already downloaded are skipped.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut jobs: Vec<Job> = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| anyhow!("Invalid job queue in {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for job in jobs.iter_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            jobs: Mutex::new(jobs),
+            submitted: Notify::new(),
+            running: Mutex::new(None),
+        })
+    }
+
+    /// Queues the URLs to be downloaded after the jobs before them
+    pub fn submit(&self, urls: Vec<String>, upgrade: bool) -> Result<Job, Error> {
+        if urls.is_empty() {
+            return Err(anyhow!("A job needs at least one URL"));
+        }
+        for url in &urls {
+            Action::from_str(url).map_err(|_| {
+                anyhow!("{url} isn't the URL of a TIDAL track, album, artist or playlist")
+            })?;
+        }
+        let mut jobs = self.lock();
+        let job = Job {
+            id: jobs.iter().map(|job| job.id).max().unwrap_or_default() + 1,
+            urls,
+            upgrade,
+            state: JobState::Queued,
+            submitted_at: chrono::Utc::now().timestamp(),
+            started_at: None,
+            finished_at: None,
+            tracks: 0,
+            downloaded: 0,
+            skipped: 0,
+            failed: 0,
+            errors: Vec::new(),
+        };
+        jobs.push(job.clone());
+        self.save(&mut jobs)?;
+        drop(jobs);
+        self.submitted.notify_one();
+        Ok(job)
+    }
+
+    /// Every job, oldest first
+    pub fn jobs(&self) -> Vec<Job> {
+        self.lock().clone()
+    }
+
+    pub fn job(&self, id: u64) -> Option<Job> {
+        self.lock().iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Cancels a job that hasn't finished. If it's running, its download is stopped,
+    /// and files that were still downloading are left as `.part` files
+    pub fn cancel(&self, id: u64) -> Result<Job, Error> {
+        let job = self.update(id, |job| {
+            if job.state.is_finished() {
+                return Err(anyhow!("Job {id} already finished"));
+            }
+            job.state = JobState::Cancelled;
+            job.finished_at = Some(chrono::Utc::now().timestamp());
+            Ok(())
+        })?;
+        if let Some((running, download)) = &*self.running.lock().unwrap_or_else(|e| e.into_inner())
+        {
+            if *running == id {
+                download.abort();
+            }
+        }
+        Ok(job)
+    }
+
+    /// Downloads the queued jobs one after another, and waits for more. Never returns
+    pub async fn run(self: Arc<Self>, tdl: Arc<Tdl>) {
+        loop {
+            let Some(job) = self.start_next() else {
+                self.submitted.notified().await;
+                continue;
+            };
+            info!("Job {} | Started", job.id);
+
+            let (events, mut receiver) = event_channel();
+            let download = {
+                let tdl = tdl.clone();
+                tokio::spawn(async move {
+                    tdl.download_with_events(job.urls, job.upgrade, events)
+                        .await
+                })
+            };
+            *self.running.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some((job.id, download.abort_handle()));
+            // it may have been cancelled before it could be stopped
+            if self.job(job.id).map(|job| job.state) == Some(JobState::Cancelled) {
+                download.abort();
+            }
+            // the events end once the download is done, or stopped
+            while let Some(event) = receiver.recv().await {
+                self.record(job.id, &event);
+            }
+            let result = download.await;
+            *self.running.lock().unwrap_or_else(|e| e.into_inner()) = None;
+            match self.update(job.id, |job| {
+                job.finish(result);
+                Ok(())
+            }) {
+                Ok(job) => info!("Job {} | {:?}", job.id, job.state),
+                Err(e) => warn!("Unable to finish job {}: {e}", job.id),
+            }
+        }
+    }
+
+    /// Starts the oldest queued job. It's found and started at once,
+    /// so a job that's cancelled in the meantime is never started
+    fn start_next(&self) -> Option<Job> {
+        let mut jobs = self.lock();
+        let job = jobs.iter_mut().find(|job| job.state == JobState::Queued)?;
+        job.start();
+        let job = job.clone();
+        if let Err(e) = self.save(&mut jobs) {
+            warn!("Unable to save the start of job {}: {e}", job.id);
+        }
+        Some(job)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Changes a job, and saves the queue if `change` succeeds
+    fn update(
+        &self,
+        id: u64,
+        change: impl FnOnce(&mut Job) -> Result<(), Error>,
+    ) -> Result<Job, Error> {
+        let mut jobs = self.lock();
+        let job = jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or_else(|| anyhow!("No job {id}"))?;
+        change(job)?;
+        let job = job.clone();
+        self.save(&mut jobs)?;
+        Ok(job)
+    }
+
+    /// Counts an event of a running job. The counts are saved once the job finishes,
+    /// as a job that was stopped halfway counts its tracks again when it restarts
+    fn record(&self, id: u64, event: &DownloadEvent) {
+        if let Some(job) = self.lock().iter_mut().find(|job| job.id == id) {
+            job.record(event);
+        }
+    }
+
+    /// Drops the oldest finished jobs beyond [`MAX_FINISHED_JOBS`], and saves the queue
+    fn save(&self, jobs: &mut Vec<Job>) -> Result<(), Error> {
+        // jobs are in the order they were submitted
+        let finished = jobs.iter().filter(|job| job.state.is_finished()).count();
+        let mut excess = finished.saturating_sub(MAX_FINISHED_JOBS);
+        jobs.retain(|job| {
+            let drop = excess > 0 && job.state.is_finished();
+            if drop {
+                excess -= 1;
+            }
+            !drop
+        });
+        write_atomic(&self.path, serde_json::to_string_pretty(jobs)?.as_bytes()).map_err(|e| {
+            anyhow!(
+                "Unable to save the job queue to {}: {e}",
+                self.path.display()
+            )
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Submission {
+    urls: Vec<String>,
+    #[serde(default)]
+    upgrade: bool,
+}
+
+/// Serves the API for `jobs` on `addr`, and downloads the jobs as they're submitted.
+/// Every request but the page needs the `serve_token` setting as a bearer token, if it's set.
+/// Nothing is printed. [`exposure`] says what to warn about before serving on `addr`.
+///
+/// Fails if `addr` can't be bound. Runs until the returned task is aborted.
+pub fn serve(
+    tdl: Arc<Tdl>,
+    jobs: Arc<JobQueue>,
+    addr: SocketAddr,
+) -> Result<JoinHandle<()>, Error> {
+    let server = Server::try_bind(&addr).map_err(|e| anyhow!("Unable to serve on {addr}: {e}"))?;
+    let runner = jobs.clone().run(tdl.clone());
+    let make_service = make_service_fn(move |_| {
+        let tdl = tdl.clone();
+        let jobs = jobs.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let tdl = tdl.clone();
+                let jobs = jobs.clone();
+                async move { Ok::<_, Infallible>(respond(&tdl, &jobs, req).await) }
+            }))
+        }
+    });
+    Ok(tokio::spawn(async move {
+        select! {
+            _ = runner => {}
+            result = server.serve(make_service) => {
+                if let Err(e) = result {
+                    warn!("The server stopped: {e}");
+                }
+            }
+        }
+    }))
+}
+
+async fn respond(tdl: &Tdl, jobs: &JobQueue, req: Request<Body>) -> Response<Body> {
+    let path: Vec<String> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(str::to_string)
+        .collect();
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    // the page asks for the token itself
+    let is_page = req.method() == Method::GET && path.is_empty();
+    if !is_page && !is_authorized(tdl.settings().serve_token.as_deref(), &req) {
+        let mut res = error(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong token. Send the serve_token setting as `Authorization: Bearer <token>`"
+                .to_string(),
+        );
+        if let Ok(value) = "Bearer".parse() {
+            res.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+        }
+        return res;
+    }
+    match (req.method().clone(), path.as_slice()) {
+        (Method::GET, []) => {
+            let mut res = Response::new(Body::from(INDEX));
+            if let Ok(value) = "text/html; charset=utf-8".parse() {
+                res.headers_mut().insert(header::CONTENT_TYPE, value);
+            }
+            res
+        }
+        (Method::GET, ["jobs"]) => json(StatusCode::OK, &jobs.jobs()),
+        (Method::POST, ["jobs"]) => {
+            if !is_json(&req) {
+                return error(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "Jobs are submitted as `Content-Type: application/json`".to_string(),
+                );
+            }
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()),
+            };
+            let submission: Submission = match serde_json::from_slice(&body) {
+                Ok(submission) => submission,
+                Err(e) => return error(StatusCode::BAD_REQUEST, format!("Invalid job: {e}")),
+            };
+            match jobs.submit(submission.urls, submission.upgrade) {
+                Ok(job) => json(StatusCode::CREATED, &job),
+                Err(e) => error(StatusCode::BAD_REQUEST, e.to_string()),
+            }
+        }
+        (method, ["jobs", id]) => {
+            let Some(job) = id.parse().ok().and_then(|id| jobs.job(id)) else {
+                return error(StatusCode::NOT_FOUND, format!("No job {id}"));
+            };
+            match method {
+                Method::GET => json(StatusCode::OK, &job),
+                Method::DELETE => match jobs.cancel(job.id) {
+                    Ok(job) => json(StatusCode::OK, &job),
+                    Err(e) => error(StatusCode::CONFLICT, e.to_string()),
+                },
+                _ => error(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "Use GET or DELETE".to_string(),
+                ),
+            }
+        }
+        _ => metrics::respond(&tdl.metrics(), req),
+    }
+}
+
+/// Why serving on `addr` lets other machines use the API, if it does
+pub fn exposure(addr: SocketAddr, token: Option<&str>) -> Option<String> {
+    if addr.ip().is_loopback() {
+        return None;
+    }
+    Some(match token {
+        Some(_) => format!(
+            "{addr} can be reached from other machines. Requests need the serve_token, but aren't encrypted"
+        ),
+        None => format!(
+            "{addr} can be reached from other machines, and serve_token isn't set. Anyone who can reach it can queue downloads"
+        ),
+    })
+}
+
+fn is_authorized(token: Option<&str>, req: &Request<Body>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // compares every byte, so the time taken doesn't tell how much of the token was right
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn is_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    let mut res = Response::new(Body::from(serde_json::to_string(value).unwrap_or_default()));
+    *res.status_mut() = status;
+    if let Ok(value) = "application/json".parse() {
+        res.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
+    res
+}
+
+fn error(status: StatusCode, message: String) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": message }))
+}
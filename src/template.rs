@@ -0,0 +1,382 @@
+use anyhow::{anyhow, Error};
+use std::iter::Peekable;
+use std::str::Chars;
+use std::str::FromStr;
+
+/// A parsed path template.
+///
+/// Supported syntax:
+/// - `{token}` inserts the value of a token
+/// - `{token:02}` pads the value to a width of 2 with zeros, `{token:3}` pads with spaces
+/// - `{token|truncate:80}` applies filters, which can be chained: `{token|lower|truncate:10}`
+/// - `{token|default:Unknown}` uses `Unknown` when the token is empty
+/// - `{?token:text}` renders `text` only if `token` isn't empty. `text` can contain other tokens
+/// - `{!token:text}` renders `text` only if `token` is empty
+/// - `{{` and `}}` insert literal braces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Token(TokenExpr),
+    Conditional {
+        token: String,
+        negate: bool,
+        body: Template,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TokenExpr {
+    name: String,
+    width: Option<usize>,
+    zero_pad: bool,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Filter {
+    Truncate(usize),
+    Upper,
+    Lower,
+    Trim,
+    Default(String),
+}
+
+/// Anything that can provide values for the tokens of a template
+pub trait TokenSource {
+    /// Returns the value of a token, or `None` if the token isn't known to the source
+    fn resolve(&self, name: &str) -> Option<String>;
+
+    /// Makes the literal text of the template safe to insert
+    fn literal(&self, text: &str) -> String {
+        text.to_string()
+    }
+
+    /// Makes a value that doesn't come from a token, such as a `default:` value, safe to insert
+    fn value(&self, value: &str) -> String {
+        value.to_string()
+    }
+}
+
+impl Template {
+    /// Parses a template, rejecting any tokens that `is_known` returns false for
+    pub fn parse(input: &str, is_known: impl Fn(&str) -> bool) -> Result<Self, Error> {
+        let mut chars = input.chars().peekable();
+        let template = Parser {
+            is_known: &is_known,
+        }
+        .parse(&mut chars, false)?;
+        Ok(template)
+    }
+
+    pub fn render(&self, source: &impl TokenSource) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(&source.literal(text)),
+                Segment::Token(expr) => out.push_str(&expr.render(source)),
+                Segment::Conditional {
+                    token,
+                    negate,
+                    body,
+                } => {
+                    let empty = source.resolve(token).unwrap_or_default().is_empty();
+                    if empty == *negate {
+                        out.push_str(&body.render(source));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Names of every token used in the template, including inside conditionals
+    pub fn tokens(&self) -> Vec<&str> {
+        let mut tokens = Vec::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(_) => {}
+                Segment::Token(expr) => tokens.push(expr.name.as_str()),
+                Segment::Conditional { token, body, .. } => {
+                    tokens.push(token.as_str());
+                    tokens.extend(body.tokens());
+                }
+            }
+        }
+        tokens
+    }
+}
+
+impl TokenExpr {
+    fn render(&self, source: &impl TokenSource) -> String {
+        let mut value = source.resolve(&self.name).unwrap_or_default();
+        if let Some(width) = self.width {
+            let len = value.chars().count();
+            if len < width {
+                let fill = if self.zero_pad { '0' } else { ' ' };
+                let pad: String = std::iter::repeat_n(fill, width - len).collect();
+                value = pad + &value;
+            }
+        }
+        for filter in &self.filters {
+            value = filter.apply(value, source);
+        }
+        value
+    }
+}
+
+impl Filter {
+    fn apply(&self, value: String, source: &impl TokenSource) -> String {
+        match self {
+            Filter::Truncate(max) => match value.char_indices().nth(*max) {
+                // don't leave dangling whitespace where the cut was made
+                Some((idx, _)) => value[..idx].trim_end().to_string(),
+                None => value,
+            },
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Trim => value.trim().to_string(),
+            Filter::Default(fallback) => match value.is_empty() {
+                true => source.value(fallback),
+                false => value,
+            },
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match input.split_once(':') {
+            Some((name, arg)) => (name.trim(), Some(arg)),
+            None => (input.trim(), None),
+        };
+        match (name, arg) {
+            ("default", Some(arg)) => Ok(Filter::Default(arg.to_string())),
+            ("default", None) => Err(anyhow!("default requires a value, e.g. `default:Unknown`")),
+            ("truncate", Some(arg)) => {
+                Ok(Filter::Truncate(arg.trim().parse().map_err(|_| {
+                    anyhow!("truncate expects a number of characters, got `{arg}`")
+                })?))
+            }
+            ("truncate", None) => Err(anyhow!("truncate requires a length, e.g. `truncate:80`")),
+            ("upper", None) => Ok(Filter::Upper),
+            ("lower", None) => Ok(Filter::Lower),
+            ("trim", None) => Ok(Filter::Trim),
+            ("upper" | "lower" | "trim", Some(_)) => {
+                Err(anyhow!("filter `{name}` doesn't take an argument"))
+            }
+            _ => Err(anyhow!(
+                "Unknown filter `{name}`. Available filters: truncate:<n>, default:<text>, upper, lower, trim"
+            )),
+        }
+    }
+}
+
+struct Parser<'a> {
+    is_known: &'a dyn Fn(&str) -> bool,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses until the end of input, or the closing brace of a conditional when `nested` is set
+    fn parse(&self, chars: &mut Peekable<Chars>, nested: bool) -> Result<Template, Error> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        loop {
+            match chars.next() {
+                None if nested => return Err(anyhow!("Unclosed `{{?` conditional")),
+                None => break,
+                Some('{') if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                Some('}') if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                Some('}') if nested => break,
+                Some('}') => return Err(anyhow!("Unmatched `}}`. Use `}}}}` for a literal brace")),
+                Some('{') => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(self.parse_expr(chars)?);
+                }
+                Some(c) => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+
+    fn parse_expr(&self, chars: &mut Peekable<Chars>) -> Result<Segment, Error> {
+        let negate = match chars.peek() {
+            Some('?') => Some(false),
+            Some('!') => Some(true),
+            _ => None,
+        };
+        if let Some(negate) = negate {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some(':') => break,
+                    Some('}') | None => {
+                        return Err(anyhow!(
+                            "Conditional `{{?{token}` is missing `:` before its text"
+                        ))
+                    }
+                    Some(c) => token.push(c),
+                }
+            }
+            let token = self.check_token(token.trim())?;
+            let body = self.parse(chars, true)?;
+            return Ok(Segment::Conditional {
+                token,
+                negate,
+                body,
+            });
+        }
+
+        let mut expr = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some('{') | None => return Err(anyhow!("Unclosed token `{{{expr}`")),
+                Some(c) => expr.push(c),
+            }
+        }
+
+        let mut parts = expr.split('|');
+        let head = parts.next().unwrap_or_default();
+        let (name, spec) = match head.split_once(':') {
+            Some((name, spec)) => (name.trim(), Some(spec.trim())),
+            None => (head.trim(), None),
+        };
+        let name = self.check_token(name)?;
+        let (width, zero_pad) = match spec {
+            Some(spec) => {
+                let width = spec.parse::<usize>().map_err(|_| {
+                    anyhow!("Invalid width `{spec}` for `{{{name}}}`, expected a number like `02`")
+                })?;
+                (Some(width), spec.starts_with('0'))
+            }
+            None => (None, false),
+        };
+        let filters = parts
+            .map(Filter::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("{e} in `{{{expr}}}`"))?;
+
+        Ok(Segment::Token(TokenExpr {
+            name,
+            width,
+            zero_pad,
+            filters,
+        }))
+    }
+
+    fn check_token(&self, name: &str) -> Result<String, Error> {
+        if name.is_empty() {
+            return Err(anyhow!(
+                "Empty token `{{}}`. Use `{{{{` for a literal brace"
+            ));
+        }
+        if !(self.is_known)(name) {
+            return Err(anyhow!("Unknown token `{{{name}}}`"));
+        }
+        Ok(name.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Values(HashMap<&'static str, &'static str>);
+
+    impl TokenSource for Values {
+        fn resolve(&self, name: &str) -> Option<String> {
+            self.0.get(name).map(|value| value.to_string())
+        }
+    }
+
+    fn render(template: &str) -> String {
+        let values = Values(HashMap::from([
+            ("artist", "Daft Punk"),
+            ("album", "Discovery"),
+            ("number", "7"),
+            ("year", ""),
+        ]));
+        Template::parse(template, |name| name != "unknown")
+            .unwrap()
+            .render(&values)
+    }
+
+    fn parse_error(template: &str) -> String {
+        Template::parse(template, |name| name != "unknown")
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn renders_tokens_and_literals() {
+        assert_eq!(render("{artist}/{album}"), "Daft Punk/Discovery");
+        assert_eq!(render("{{{album}}}"), "{Discovery}");
+        assert_eq!(render("no tokens"), "no tokens");
+    }
+
+    #[test]
+    fn pads_values() {
+        assert_eq!(render("{number:02}"), "07");
+        assert_eq!(render("{number:3}"), "  7");
+        assert_eq!(render("{album:2}"), "Discovery");
+    }
+
+    #[test]
+    fn applies_filters_in_order() {
+        assert_eq!(render("{artist|upper}"), "DAFT PUNK");
+        assert_eq!(render("{artist|lower|truncate:4}"), "daft");
+        // the cut doesn't leave a trailing space
+        assert_eq!(render("{artist|truncate:5}"), "Daft");
+        assert_eq!(render("{year|default:Unknown}"), "Unknown");
+        assert_eq!(render("{album|default:Unknown}"), "Discovery");
+    }
+
+    #[test]
+    fn renders_conditionals() {
+        assert_eq!(render("{album}{?year: ({year})}"), "Discovery");
+        assert_eq!(render("{album}{!year: (undated)}"), "Discovery (undated)");
+        assert_eq!(
+            render("{?artist:{artist} - }{album}"),
+            "Daft Punk - Discovery"
+        );
+    }
+
+    #[test]
+    fn lists_tokens() {
+        let template = Template::parse("{artist}/{?year:{year} - }{album}", |_| true).unwrap();
+        assert_eq!(template.tokens(), ["artist", "year", "year", "album"]);
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(parse_error("{unknown}").contains("Unknown token"));
+        assert!(parse_error("{}").contains("Empty token"));
+        assert!(parse_error("{album").contains("Unclosed token"));
+        assert!(parse_error("album}").contains("Unmatched"));
+        assert!(parse_error("{?year ({year})}").contains("missing `:`"));
+        assert!(parse_error("{?year: ({year})").contains("Unclosed"));
+        assert!(parse_error("{number:ab}").contains("Invalid width"));
+        assert!(parse_error("{album|shout}").contains("Unknown filter"));
+        assert!(parse_error("{album|truncate}").contains("requires a length"));
+        assert!(parse_error("{album|upper:1}").contains("doesn't take an argument"));
+    }
+}
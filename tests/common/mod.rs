@@ -0,0 +1,490 @@
+//! A stand-in TIDAL server for the integration tests.
+//!
+//! It serves the endpoints tdl uses from the JSON fixtures in `tests/fixtures`,
+//! with a FLAC generated on the fly as the audio of every track.
+
+#![allow(dead_code)]
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+use tdl::config::Settings;
+use tdl::credentials::FileStore;
+use tdl::Tdl;
+use tempfile::TempDir;
+
+pub const ACCESS_TOKEN: &str = "mock-access-token";
+pub const REFRESH_TOKEN: &str = "mock-refresh-token";
+/// A track TIDAL answers with a 401 for, as it isn't available in the country of the login
+pub const REGION_LOCKED_TRACK: &str = "4032";
+pub const USER_ID: i64 = 12345;
+/// Items per page of a list, smaller than tdl asks for, so every list is paginated
+pub const PAGE_SIZE: usize = 2;
+/// Bytes served as every image
+pub const IMAGE: &[u8] = b"\xFF\xD8\xFF\xE0mock-image\xFF\xD9";
+
+const ARTISTS: &str = include_str!("../fixtures/artists.json");
+const ALBUMS: &str = include_str!("../fixtures/albums.json");
+const TRACKS: &str = include_str!("../fixtures/tracks.json");
+const DEVICE_AUTHORIZATION: &str = include_str!("../fixtures/device_authorization.json");
+const TOKEN: &str = include_str!("../fixtures/token.json");
+const SESSION: &str = include_str!("../fixtures/session.json");
+const SUBSCRIPTION: &str = include_str!("../fixtures/subscription.json");
+
+/// A request the server received
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<Recorded>,
+    /// Times the device code has been polled. It's approved on the second poll.
+    device_polls: usize,
+    /// Image requests still to be answered with a 429
+    limited_images: usize,
+}
+
+pub struct MockTidal {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTidal {
+    pub fn start() -> Self {
+        let state: Arc<Mutex<State>> = Arc::default();
+        let shared = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = shared.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = state.clone();
+                    let addr = req
+                        .headers()
+                        .get("host")
+                        .and_then(|host| host.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+                    async move { Ok::<_, Infallible>(handle(req, &state, &addr).await) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Self { addr, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Requests to paths starting with `prefix`
+    pub fn requests_to(&self, prefix: &str) -> Vec<Recorded> {
+        self.requests()
+            .into_iter()
+            .filter(|req| req.path.starts_with(prefix))
+            .collect()
+    }
+
+    /// Answers the next `times` image requests with a 429
+    pub fn rate_limit_images(&self, times: usize) {
+        self.state.lock().unwrap().limited_images = times;
+    }
+}
+
+async fn handle(req: Request<Body>, state: &Mutex<State>, host: &str) -> Response<Body> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query: HashMap<String, String> = req
+        .uri()
+        .query()
+        .map(|query| serde_urlencoded::from_str(query).unwrap_or_default())
+        .unwrap_or_default();
+    let authorized = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        == Some(&format!("Bearer {ACCESS_TOKEN}"));
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    let form: HashMap<String, String> = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+    state.lock().unwrap().requests.push(Recorded {
+        method: method.clone(),
+        path: path.clone(),
+        query: query.clone(),
+    });
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (&method, segments.as_slice()) {
+        (&Method::POST, ["v1", "oauth2", "device_authorization"]) => {
+            json_response(StatusCode::OK, DEVICE_AUTHORIZATION)
+        }
+        (&Method::POST, ["v1", "oauth2", "token"]) => token(&form, state),
+        (&Method::GET, ["media", _]) => Response::builder()
+            .header("content-type", "audio/flac")
+            .body(Body::from(flac()))
+            .unwrap(),
+        (&Method::GET, ["resources", ..]) if take_limited_image(state) => Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("retry-after", "0")
+            .body(Body::empty())
+            .unwrap(),
+        (&Method::GET, ["resources", ..]) => Response::builder()
+            .header("content-type", "image/jpeg")
+            .body(Body::from(IMAGE))
+            .unwrap(),
+        (_, ["v1", ..]) if !authorized => {
+            error(StatusCode::UNAUTHORIZED, 11002, "Token is invalid")
+        }
+        (&Method::GET, ["v1", "sessions"]) => json_response(StatusCode::OK, SESSION),
+        (&Method::GET, ["v1", "users", _, "subscription"]) => {
+            json_response(StatusCode::OK, SUBSCRIPTION)
+        }
+        (&Method::POST, ["v1", "logout"]) => json_response(StatusCode::OK, ""),
+        (&Method::GET, ["v1", "tracks", REGION_LOCKED_TRACK]) => error(
+            StatusCode::UNAUTHORIZED,
+            4032,
+            "This track is not available in your region",
+        ),
+        (&Method::GET, ["v1", "tracks", id]) => find(TRACKS, id),
+        (&Method::GET, ["v1", "tracks", id, "playbackinfopostpaywall"]) => playback_info(id, host),
+        (&Method::GET, ["v1", "albums", id]) => find(ALBUMS, id),
+        (&Method::GET, ["v1", "albums", id, "items"]) => {
+            let items = fixture(TRACKS)
+                .into_iter()
+                .filter(|track| has_id(&track["album"], id))
+                .map(|track| json!({ "item": track, "type": "track" }))
+                .collect();
+            page(items, &query)
+        }
+        (&Method::GET, ["v1", "artists", id]) => find(ARTISTS, id),
+        (&Method::GET, ["v1", "artists", id, "albums"]) => {
+            let albums = fixture(ALBUMS)
+                .into_iter()
+                .filter(|album| has_id(&album["artist"], id))
+                .filter(|album| {
+                    let single = album["type"] == "SINGLE";
+                    single == (query.get("filter").map(String::as_str) == Some("EPSANDSINGLES"))
+                })
+                .collect();
+            page(albums, &query)
+        }
+        (&Method::GET, ["v1", "search", kind]) => {
+            let (items, field) = match *kind {
+                "artists" => (ARTISTS, "name"),
+                "albums" => (ALBUMS, "title"),
+                "tracks" => (TRACKS, "title"),
+                _ => return error(StatusCode::NOT_FOUND, 2001, "Not found"),
+            };
+            let search = query
+                .get("query")
+                .cloned()
+                .unwrap_or_default()
+                .to_lowercase();
+            let items = fixture(items)
+                .into_iter()
+                .filter(|item| {
+                    item[field]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_lowercase()
+                        .contains(&search)
+                        || item["isrc"].as_str().map(str::to_lowercase) == Some(search.clone())
+                })
+                .collect();
+            page(items, &query)
+        }
+        _ => error(
+            StatusCode::NOT_FOUND,
+            2001,
+            "The requested resource could not be found",
+        ),
+    }
+}
+
+fn token(form: &HashMap<String, String>, state: &Mutex<State>) -> Response<Body> {
+    match form.get("grant_type").map(String::as_str) {
+        Some("urn:ietf:params:oauth:grant-type:device_code") => {
+            let mut state = state.lock().unwrap();
+            state.device_polls += 1;
+            if state.device_polls < 2 {
+                return json_response(
+                    StatusCode::BAD_REQUEST,
+                    r#"{"status":400,"error":"authorization_pending","sub_status":1002,"error_description":"Device Authorization code is not authorized yet"}"#,
+                );
+            }
+            json_response(StatusCode::OK, TOKEN)
+        }
+        Some("refresh_token")
+            if form.get("refresh_token").map(String::as_str) == Some(REFRESH_TOKEN) =>
+        {
+            json_response(StatusCode::OK, TOKEN)
+        }
+        _ => json_response(
+            StatusCode::BAD_REQUEST,
+            r#"{"status":400,"error":"invalid_grant","sub_status":11101,"error_description":"Token could not be verified"}"#,
+        ),
+    }
+}
+
+fn take_limited_image(state: &Mutex<State>) -> bool {
+    let mut state = state.lock().unwrap();
+    let limited = state.limited_images > 0;
+    state.limited_images = state.limited_images.saturating_sub(1);
+    limited
+}
+
+fn playback_info(id: &str, host: &str) -> Response<Body> {
+    if !fixture(TRACKS).iter().any(|track| has_id(track, id)) {
+        return error(StatusCode::NOT_FOUND, 2001, "Track not found");
+    }
+    let manifest = json!({
+        "mimeType": "audio/flac",
+        "codecs": "flac",
+        "encryptionType": "NONE",
+        "urls": [format!("http://{host}/media/{id}.flac")],
+    });
+    let body = json!({
+        "trackId": id.parse::<usize>().unwrap(),
+        "assetPresentation": "FULL",
+        "audioQuality": "LOSSLESS",
+        "manifestMimeType": "application/vnd.tidal.bts",
+        "manifest": base64::encode(manifest.to_string()),
+        "bitDepth": 16,
+        "sampleRate": 44100,
+    });
+    json_response(StatusCode::OK, &body.to_string())
+}
+
+/// A page of `items`, honoring the `limit` and `offset` tdl asks for, up to [`PAGE_SIZE`]
+fn page(items: Vec<Value>, query: &HashMap<String, String>) -> Response<Body> {
+    let param = |key: &str, default: usize| {
+        query
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    let limit = param("limit", PAGE_SIZE).min(PAGE_SIZE);
+    let offset = param("offset", 0);
+    let body = json!({
+        "limit": limit,
+        "offset": offset,
+        "totalNumberOfItems": items.len(),
+        "items": items.iter().skip(offset).take(limit).collect::<Vec<_>>(),
+    });
+    json_response(StatusCode::OK, &body.to_string())
+}
+
+fn find(fixture_json: &str, id: &str) -> Response<Body> {
+    match fixture(fixture_json)
+        .into_iter()
+        .find(|item| has_id(item, id))
+    {
+        Some(item) => json_response(StatusCode::OK, &item.to_string()),
+        None => error(
+            StatusCode::NOT_FOUND,
+            2001,
+            "The requested resource could not be found",
+        ),
+    }
+}
+
+fn has_id(item: &Value, id: &str) -> bool {
+    id.parse::<u64>().is_ok_and(|id| item["id"] == id)
+}
+
+fn fixture(json: &str) -> Vec<Value> {
+    serde_json::from_str(json).expect("invalid fixture")
+}
+
+fn error(status: StatusCode, sub_status: i64, message: &str) -> Response<Body> {
+    let body = json!({
+        "status": status.as_u16(),
+        "subStatus": sub_status,
+        "userMessage": message,
+    });
+    json_response(status, &body.to_string())
+}
+
+fn json_response(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// An M4A with a movie header, no tags and no audio
+pub fn m4a() -> Vec<u8> {
+    fn atom(name: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = (body.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend(name);
+        data.extend(body);
+        data
+    }
+    let mut mvhd = vec![0; 100];
+    // time scale
+    mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+    let mut data = atom(b"ftyp", b"M4A \0\0\0\0M4A isom");
+    data.extend(atom(b"moov", &atom(b"mvhd", &mvhd)));
+    data.extend(atom(b"mdat", b""));
+    data
+}
+
+/// A FLAC with 16 bit, 44.1kHz stereo STREAMINFO, no tags and no audio
+pub fn flac() -> Vec<u8> {
+    let mut data = b"fLaC".to_vec();
+    // STREAMINFO, marked as the last metadata block
+    data.extend([0x80, 0, 0, 34]);
+    data.extend(4096u16.to_be_bytes());
+    data.extend(4096u16.to_be_bytes());
+    // unknown frame sizes
+    data.extend([0; 6]);
+    // sample rate (20 bits), channels - 1 (3 bits), bits per sample - 1 (5 bits), total samples (36 bits)
+    let info: u64 = (44_100 << 44) | (1 << 41) | (15 << 36);
+    data.extend(info.to_be_bytes());
+    // MD5 of the audio
+    data.extend([0; 16]);
+    data
+}
+
+/// A home folder for one run of tdl, pointed at a mock server
+pub struct Home {
+    pub dir: TempDir,
+    server_url: String,
+}
+
+impl Home {
+    pub fn new(server: &MockTidal) -> Self {
+        Self {
+            dir: tempfile::tempdir().unwrap(),
+            server_url: server.url(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn config_dir(&self) -> PathBuf {
+        self.path().join(".config/tdl")
+    }
+
+    pub fn music(&self) -> PathBuf {
+        self.path().join("Music")
+    }
+
+    pub fn credentials(&self) -> PathBuf {
+        self.config_dir().join("credentials.toml")
+    }
+
+    /// Stores a login that doesn't need to be refreshed
+    pub fn login(&self) {
+        std::fs::create_dir_all(self.config_dir()).unwrap();
+        let login = format!(
+            "user_id = {USER_ID}\n\
+             country_code = \"US\"\n\
+             access_token = \"{ACCESS_TOKEN}\"\n\
+             refresh_token = \"{REFRESH_TOKEN}\"\n\
+             expires_after = {}\n",
+            chrono::Utc::now().timestamp() + 86_400
+        );
+        std::fs::write(self.credentials(), login).unwrap();
+    }
+
+    /// Settings that only use the files in this folder, and the mock server
+    pub fn settings(&self) -> Settings {
+        let mut settings = Settings::defaults().unwrap();
+        settings.endpoints.api = format!("{}/v1", self.server_url);
+        settings.endpoints.auth = format!("{}/v1/oauth2", self.server_url);
+        settings.endpoints.resources = format!("{}/resources", self.server_url);
+        settings.download_paths.base_path = self.music().display().to_string();
+        settings.cache_dir = self.path().join("cache").display().to_string();
+        settings.archive_file = self.path().join("archive.jsonl").display().to_string();
+        settings.show_progress = false;
+        settings
+    }
+
+    /// A library client that only uses the files in this folder, and the mock server
+    pub fn client(&self) -> Tdl {
+        self.client_with(self.settings())
+    }
+
+    /// A library client with `settings`, that keeps its login in this folder
+    pub fn client_with(&self, settings: Settings) -> Tdl {
+        Tdl::builder()
+            .settings(settings)
+            .credentials(FileStore::new(self.credentials()))
+            .pending_login_path(self.path().join("login-pending.json"))
+            .build()
+            .unwrap()
+    }
+
+    /// Runs tdl with `args`, isolated from the environment of the tests
+    pub async fn tdl(&self, args: &[&str]) -> Output {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_tdl"))
+            .args(args)
+            .env_clear()
+            .env("HOME", self.path())
+            .env("XDG_CONFIG_HOME", self.path())
+            .env("TDL_ENDPOINTS__API", format!("{}/v1", self.server_url))
+            .env(
+                "TDL_ENDPOINTS__AUTH",
+                format!("{}/v1/oauth2", self.server_url),
+            )
+            .env(
+                "TDL_ENDPOINTS__RESOURCES",
+                format!("{}/resources", self.server_url),
+            )
+            .env("TDL_DOWNLOAD_PATHS__BASE_PATH", self.music())
+            .env("TDL_SHOW_PROGRESS", "false")
+            .output()
+            .await
+            .expect("failed to run tdl")
+    }
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+/// Every file under `dir` with the extension `extension`
+pub fn files_with_extension(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().and_then(|e| e.to_str()) == Some(extension) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    found
+}
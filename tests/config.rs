@@ -0,0 +1,75 @@
+mod common;
+
+use common::{stderr, stdout, Home, MockTidal};
+
+#[tokio::test]
+async fn reads_download_cover_from_older_versions() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    std::fs::create_dir_all(home.config_dir()).unwrap();
+    std::fs::write(
+        home.config_dir().join("config.toml"),
+        "download_cover = false\n",
+    )
+    .unwrap();
+
+    let output = home.tdl(&["config", "get", "cover.mode"]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output).trim(), "none");
+}
+
+#[tokio::test]
+async fn keeps_a_login_and_profiles_per_config_file() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let library = home.path().join("library.toml").display().to_string();
+    let token = ["config", "get", "login_key.access_token", "--show-secrets"];
+
+    let default = home.tdl(&token).await;
+    let other = home
+        .tdl(&[&["--config", &library], &token[..]].concat())
+        .await;
+
+    assert!(other.status.success(), "{}", stderr(&other));
+    assert_eq!(stdout(&default).trim(), common::ACCESS_TOKEN);
+    assert_eq!(stdout(&other).trim(), "");
+
+    let set = home
+        .tdl(&[
+            "--config",
+            &library,
+            "--profile",
+            "family",
+            "config",
+            "set",
+            "download_paths.base_path",
+            "/mnt/family",
+        ])
+        .await;
+
+    assert!(set.status.success(), "{}", stderr(&set));
+    assert!(home.path().join("profiles/library.family.toml").exists());
+    assert!(!home.config_dir().join("profiles/family.toml").exists());
+}
+
+#[tokio::test]
+async fn profiles_inherit_later_changes_to_the_config_file() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    let workers = ["config", "get", "workers"];
+    let profile = ["--profile", "family"];
+    let created = home.tdl(&[&profile[..], &workers[..]].concat()).await;
+    assert!(created.status.success(), "{}", stderr(&created));
+
+    let set = home.tdl(&["config", "set", "workers", "4"]).await;
+    assert!(set.status.success(), "{}", stderr(&set));
+    let inherited = home.tdl(&[&profile[..], &workers[..]].concat()).await;
+
+    assert_eq!(stdout(&inherited).trim(), "4");
+    let file = std::fs::read_to_string(home.config_dir().join("profiles/family.toml")).unwrap();
+    let file: toml::Value = toml::from_str(&file).unwrap();
+    let keys: Vec<&String> = file.as_table().unwrap().keys().collect();
+    assert_eq!(keys, ["archive_file"]);
+}
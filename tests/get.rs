@@ -0,0 +1,267 @@
+mod common;
+
+use common::{
+    files_with_extension, m4a, stderr, stdout, Home, MockTidal, IMAGE, REGION_LOCKED_TRACK,
+};
+use metaflac::block::PictureType::CoverFront;
+use metaflac::Tag;
+
+const ALBUM_URL: &str = "https://tidal.com/browse/album/100";
+
+fn vorbis(tag: &Tag, key: &str) -> Option<String> {
+    tag.get_vorbis(key)?.next().map(str::to_string)
+}
+
+#[tokio::test]
+async fn downloads_an_album() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home.tdl(&["get", ALBUM_URL]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let files = files_with_extension(&home.music(), "flac");
+    assert_eq!(files.len(), 3, "{files:?}");
+
+    // 3 tracks take two pages
+    let pages = server.requests_to("/v1/albums/100/items");
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[1].query.get("offset").map(String::as_str), Some("2"));
+    assert_eq!(
+        pages[1].query.get("countryCode").map(String::as_str),
+        Some("US")
+    );
+    assert_eq!(server.requests_to("/media/").len(), 3);
+
+    // the album and artist are only fetched once for the whole album
+    assert_eq!(server.requests_to("/v1/albums/100").len() - pages.len(), 1);
+    let cover = files[0].parent().unwrap().join("cover.jpg");
+    assert_eq!(std::fs::read(cover).unwrap(), IMAGE);
+}
+
+#[tokio::test]
+async fn writes_metadata() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home
+        .tdl(&["get", "https://tidal.com/browse/track/1002"])
+        .await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let files = files_with_extension(&home.music(), "flac");
+    assert_eq!(files.len(), 1, "{files:?}");
+    let tag = Tag::read_from_path(&files[0]).unwrap();
+    assert_eq!(vorbis(&tag, "TITLE").as_deref(), Some("Second Song"));
+    assert_eq!(vorbis(&tag, "ARTIST").as_deref(), Some("Mock Artist"));
+    assert_eq!(vorbis(&tag, "ALBUM").as_deref(), Some("Mock Album"));
+    assert_eq!(vorbis(&tag, "TRACKNUMBER").as_deref(), Some("2"));
+    assert_eq!(vorbis(&tag, "DISCNUMBER").as_deref(), Some("1"));
+    assert_eq!(vorbis(&tag, "TOTALDISCS").as_deref(), Some("1"));
+    assert_eq!(vorbis(&tag, "ISRC").as_deref(), Some("USMCK2000002"));
+    assert_eq!(vorbis(&tag, "TIDAL_TRACK_ID").as_deref(), Some("1002"));
+    assert_eq!(
+        vorbis(&tag, "TIDAL_AUDIO_QUALITY").as_deref(),
+        Some("LOSSLESS")
+    );
+    let picture = tag.pictures().next().expect("cover is embedded");
+    assert_eq!(picture.picture_type, CoverFront);
+    assert_eq!(picture.data, IMAGE);
+    // the stream info of the generated FLAC survives the rewrite
+    assert_eq!(tag.get_streaminfo().unwrap().bits_per_sample, 16);
+
+    // no partial download is left behind
+    assert!(files_with_extension(&home.music(), "part").is_empty());
+}
+
+#[tokio::test]
+async fn skips_archived_tracks() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let first = home.tdl(&["get", ALBUM_URL]).await;
+    assert!(first.status.success(), "{}", stderr(&first));
+    let archive = std::fs::read_to_string(home.config_dir().join("archive.jsonl")).unwrap();
+    assert_eq!(archive.lines().count(), 3);
+
+    let second = home.tdl(&["get", ALBUM_URL]).await;
+
+    assert!(second.status.success(), "{}", stderr(&second));
+    assert_eq!(server.requests_to("/media/").len(), 3);
+    assert_eq!(files_with_extension(&home.music(), "flac").len(), 3);
+}
+
+#[tokio::test]
+async fn skips_artwork_when_every_track_exists() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let first = home.tdl(&["get", ALBUM_URL]).await;
+    assert!(first.status.success(), "{}", stderr(&first));
+    let images = server.requests_to("/resources/").len();
+    // the files are found by their paths instead of the archive
+    std::fs::remove_file(home.config_dir().join("archive.jsonl")).unwrap();
+    let files = files_with_extension(&home.music(), "flac");
+    std::fs::remove_file(files[0].parent().unwrap().join("cover.jpg")).unwrap();
+
+    let second = home.tdl(&["get", ALBUM_URL]).await;
+
+    assert!(second.status.success(), "{}", stderr(&second));
+    assert_eq!(server.requests_to("/media/").len(), 3);
+    assert_eq!(server.requests_to("/resources/").len(), images);
+}
+
+#[tokio::test]
+async fn fetches_an_embedded_cover_once_per_album() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    std::fs::write(
+        home.config_dir().join("config.toml"),
+        "[cover]\nmode = \"embed\"\nartist_picture = false\n",
+    )
+    .unwrap();
+
+    let output = home.tdl(&["get", ALBUM_URL]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let files = files_with_extension(&home.music(), "flac");
+    assert_eq!(files.len(), 3, "{files:?}");
+    for file in &files {
+        let tag = Tag::read_from_path(file).unwrap();
+        assert!(tag.pictures().next().is_some(), "{}", file.display());
+    }
+    assert_eq!(server.requests_to("/resources/").len(), 1);
+}
+
+#[tokio::test]
+async fn keeps_files_of_unknown_quality_when_upgrading() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let file = home.music().join("Second Song.m4a");
+    std::fs::create_dir_all(home.music()).unwrap();
+    std::fs::write(&file, m4a()).unwrap();
+    let entry = serde_json::json!({ "track_id": 1002, "path": file, "quality": null });
+    std::fs::write(
+        home.config_dir().join("archive.jsonl"),
+        format!("{entry}\n"),
+    )
+    .unwrap();
+
+    for _ in 0..2 {
+        let output = home
+            .tdl(&["get", "--upgrade", "https://tidal.com/browse/track/1002"])
+            .await;
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    assert!(server.requests_to("/media/").is_empty());
+    assert!(files_with_extension(&home.music(), "flac").is_empty());
+    assert!(file.exists());
+}
+
+#[tokio::test]
+async fn prints_progress_as_json() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home
+        .tdl(&[
+            "get",
+            "--progress",
+            "json",
+            "https://tidal.com/browse/track/1002",
+        ])
+        .await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let events: Vec<serde_json::Value> = stdout(&output)
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("every line is an event"))
+        .collect();
+    let kinds: Vec<&str> = events.iter().filter_map(|e| e["event"].as_str()).collect();
+    assert_eq!(kinds.first(), Some(&"queued"), "{kinds:?}");
+    assert!(kinds.contains(&"started"), "{kinds:?}");
+    assert_eq!(kinds.last(), Some(&"completed"), "{kinds:?}");
+    assert!(events.iter().all(|e| e["track_id"] == 1002), "{events:?}");
+}
+
+#[tokio::test]
+async fn downloads_an_artist() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home
+        .tdl(&["get", "https://tidal.com/browse/artist/10"])
+        .await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    // albums and singles are listed separately
+    let lists = server.requests_to("/v1/artists/10/albums");
+    assert_eq!(lists.len(), 2);
+    assert!(lists
+        .iter()
+        .any(|req| req.query.get("filter").map(String::as_str) == Some("EPSANDSINGLES")));
+    assert_eq!(files_with_extension(&home.music(), "flac").len(), 3);
+}
+
+#[tokio::test]
+async fn reports_missing_tracks() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home
+        .tdl(&["get", "https://tidal.com/browse/track/999"])
+        .await;
+
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Not found on TIDAL"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(files_with_extension(&home.music(), "flac").is_empty());
+}
+
+#[tokio::test]
+async fn reports_region_locked_tracks_without_refreshing() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let url = format!("https://tidal.com/browse/track/{REGION_LOCKED_TRACK}");
+    let output = home.tdl(&["get", &url]).await;
+
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Not available in the country"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(server.requests_to("/v1/oauth2/token").is_empty());
+}
+
+#[tokio::test]
+async fn requires_a_login() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    // a login TIDAL no longer accepts, and can't be refreshed
+    let login = std::fs::read_to_string(home.credentials())
+        .unwrap()
+        .replace("mock-access-token", "revoked")
+        .replace("mock-refresh-token", "revoked");
+    std::fs::write(home.credentials(), login).unwrap();
+
+    let output = home.tdl(&["search", "-f", "album", "mock"]).await;
+
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    assert!(stderr(&output).contains("tdl login"), "{}", stderr(&output));
+}
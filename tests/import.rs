@@ -0,0 +1,51 @@
+mod common;
+
+use common::{m4a, stderr, stdout, Home, MockTidal};
+use std::path::Path;
+
+/// Writes an M4A tagged with `isrc` and `title`
+fn write_m4a(path: &Path, isrc: &str, title: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, m4a()).unwrap();
+    let mut tag = mp4ameta::Tag::read_from_path(path).unwrap();
+    tag.set_isrc(isrc);
+    tag.set_title(title);
+    tag.write_to_path(path).unwrap();
+}
+
+#[tokio::test]
+async fn matches_m4a_files_by_isrc() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let folder = home.music().join("Mock Artist").join("Mock Album");
+    write_m4a(
+        &folder.join("02 Second Song.m4a"),
+        "USMCK2000002",
+        "Second Song",
+    );
+    // the only release of the ISRC has another title, so it may not be this file's
+    write_m4a(
+        &folder.join("03 Remix.m4a"),
+        "USMCK2000003",
+        "Third Song (Remix)",
+    );
+    let music = home.music().display().to_string();
+
+    let output = home.tdl(&["import", &music]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("Second Song.m4a -> ") && stdout(&output).contains("(by isrc)"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        stderr(&output).contains("Unmatched") && stderr(&output).contains("03 Remix.m4a"),
+        "{}",
+        stderr(&output)
+    );
+    let archive = std::fs::read_to_string(home.config_dir().join("archive.jsonl")).unwrap();
+    assert_eq!(archive.lines().count(), 1);
+    assert!(archive.contains("\"track_id\":1002"), "{archive}");
+}
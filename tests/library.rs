@@ -0,0 +1,158 @@
+mod common;
+
+use common::{files_with_extension, Home, MockTidal, ACCESS_TOKEN, REFRESH_TOKEN, USER_ID};
+use tdl::api::models::Album;
+use tdl::credentials::{CredentialStore, FileStore};
+use tdl::login::LoginStatus;
+use tdl::Tdl;
+
+#[tokio::test]
+async fn downloads_through_the_library() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    let tdl = home.client();
+
+    assert!(tdl.login().await.is_err());
+    let status = tdl.login_refresh_token(REFRESH_TOKEN).await.unwrap();
+    assert!(matches!(
+        status,
+        LoginStatus::LoggedIn {
+            user_id: Some(USER_ID),
+            ..
+        }
+    ));
+    let stored = FileStore::new(home.credentials()).load().unwrap().unwrap();
+    assert_eq!(stored.access_token.as_deref(), Some(ACCESS_TOKEN));
+
+    let albums = tdl
+        .client()
+        .search
+        .search::<Album>("albums", "mock", None)
+        .await
+        .unwrap();
+    assert_eq!(albums.len(), 3);
+
+    let urls = vec!["https://tidal.com/browse/album/100".to_string()];
+    let summary = tdl.download(urls.clone(), false).await.unwrap();
+    assert!(summary.failed.is_empty(), "{:?}", summary.failed);
+    assert_eq!(summary.downloaded, 3);
+    assert_eq!(files_with_extension(&home.music(), "flac").len(), 3);
+
+    let again = tdl.download(urls, false).await.unwrap();
+    assert_eq!((again.downloaded, again.skipped), (0, 3));
+}
+
+#[tokio::test]
+async fn reports_failures_without_exiting() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let tdl = home.client();
+
+    let summary = tdl
+        .download(
+            vec!["https://tidal.com/browse/track/9999".to_string()],
+            false,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 0);
+    assert_eq!(summary.failed.len(), 1);
+}
+
+#[tokio::test]
+async fn waits_for_a_device_login() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    let tdl = home.client();
+
+    let device = tdl.device_login().await.unwrap();
+    assert_eq!(device.user_code, "MOCKA");
+    assert_eq!(
+        device.verification_uri_complete,
+        "https://link.tidal.com/MOCKA"
+    );
+    let status = tdl.wait_for_login(&device).await.unwrap();
+
+    assert!(matches!(status, LoginStatus::LoggedIn { .. }));
+    assert_eq!(
+        tdl.login_key().await.access_token.as_deref(),
+        Some(ACCESS_TOKEN)
+    );
+    assert!(home.credentials().exists());
+}
+
+#[tokio::test]
+async fn keeps_the_login_of_explicit_settings_in_memory() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    let tdl = Tdl::builder().settings(home.settings()).build().unwrap();
+
+    let status = tdl.login_non_interactive().await.unwrap();
+    assert!(matches!(status, LoginStatus::AuthorizationRequired { .. }));
+    // approved on the second poll, found through the pending login kept in memory
+    assert!(matches!(
+        tdl.login_non_interactive().await.unwrap(),
+        LoginStatus::AuthorizationRequired { .. }
+    ));
+    let status = tdl.login_non_interactive().await.unwrap();
+
+    assert!(matches!(status, LoginStatus::LoggedIn { .. }), "{status:?}");
+    assert_eq!(
+        tdl.login_key().await.access_token.as_deref(),
+        Some(ACCESS_TOKEN)
+    );
+    assert!(!home.config_dir().exists());
+    assert!(!home.path().join("login-pending.json").exists());
+}
+
+#[tokio::test]
+async fn serves_metrics() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let tdl = home.client();
+
+    let urls = vec![
+        "https://tidal.com/browse/album/100".to_string(),
+        "https://tidal.com/browse/track/9999".to_string(),
+    ];
+    let summary = tdl.download(urls, false).await.unwrap();
+    assert_eq!(summary.downloaded, 3);
+
+    let status = tdl.metrics().status();
+    assert_eq!(status.tracks_downloaded, 3);
+    assert!(status.downloaded_bytes > 0);
+    assert_eq!(status.failures.get("not_found"), Some(&1));
+    assert!(status.api_requests > 0);
+    assert_eq!(status.queues.values().sum::<usize>(), 0);
+
+    // a free port, for the server to bind to
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let metrics = tdl::metrics::serve(tdl.metrics(), addr).unwrap();
+    let body = reqwest::get(format!("http://{addr}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    metrics.abort();
+
+    assert!(body.contains("tdl_tracks_downloaded_total 3"), "{body}");
+    assert!(
+        body.contains("tdl_failures_total{kind=\"not_found\"} 1"),
+        "{body}"
+    );
+    assert!(
+        body.contains("# TYPE tdl_api_request_duration_seconds histogram"),
+        "{body}"
+    );
+    assert!(
+        body.contains("tdl_queue_depth{queue=\"downloads\"} 0"),
+        "{body}"
+    );
+}
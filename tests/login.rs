@@ -0,0 +1,122 @@
+mod common;
+
+use common::{stderr, stdout, Home, MockTidal, ACCESS_TOKEN, REFRESH_TOKEN, USER_ID};
+use serde_json::Value;
+
+fn json(output: &std::process::Output) -> Value {
+    serde_json::from_str(stdout(output).trim()).unwrap_or_else(|e| {
+        panic!("{e}: {}{}", stdout(output), stderr(output));
+    })
+}
+
+#[tokio::test]
+async fn logs_in_without_a_terminal() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    let args = ["login", "--no-interactive", "--format", "json"];
+
+    let started = home.tdl(&args).await;
+    assert_eq!(started.status.code(), Some(3), "{}", stderr(&started));
+    let status = json(&started);
+    assert_eq!(status["status"], "authorization_required");
+    assert_eq!(status["user_code"], "MOCKA");
+    assert_eq!(
+        status["verification_uri_complete"],
+        "https://link.tidal.com/MOCKA"
+    );
+
+    // the device code hasn't been approved yet
+    let pending = home.tdl(&args).await;
+    assert_eq!(pending.status.code(), Some(3), "{}", stderr(&pending));
+    assert_eq!(json(&pending)["user_code"], "MOCKA");
+
+    let approved = home.tdl(&args).await;
+    assert!(approved.status.success(), "{}", stderr(&approved));
+    let status = json(&approved);
+    assert_eq!(status["status"], "logged_in");
+    assert_eq!(status["user_id"], USER_ID);
+    assert_eq!(status["country_code"], "US");
+
+    // only one device code was requested
+    assert_eq!(
+        server.requests_to("/v1/oauth2/device_authorization").len(),
+        1
+    );
+    let credentials = std::fs::read_to_string(home.credentials()).unwrap();
+    assert!(credentials.contains(ACCESS_TOKEN), "{credentials}");
+    assert!(credentials.contains(REFRESH_TOKEN), "{credentials}");
+}
+
+#[tokio::test]
+async fn logs_in_with_a_refresh_token() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+
+    let output = home.tdl(&["login", "--refresh-token", REFRESH_TOKEN]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains(&USER_ID.to_string()));
+    let credentials = std::fs::read_to_string(home.credentials()).unwrap();
+    assert!(credentials.contains(ACCESS_TOKEN), "{credentials}");
+
+    let whoami = home.tdl(&["whoami", "--format", "json"]).await;
+    assert!(whoami.status.success(), "{}", stderr(&whoami));
+    let account = json(&whoami);
+    assert_eq!(account["status"], "logged_in");
+    assert_eq!(account["subscription"], "HIFI");
+    assert_eq!(account["highest_quality"], "LOSSLESS");
+}
+
+#[tokio::test]
+async fn rejects_an_invalid_refresh_token() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+
+    let output = home
+        .tdl(&["login", "--refresh-token", "invalid", "--format", "json"])
+        .await;
+
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    assert_eq!(json(&output)["status"], "error");
+    assert!(!home.credentials().exists());
+}
+
+#[tokio::test]
+async fn refreshes_an_expired_login() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let login = std::fs::read_to_string(home.credentials()).unwrap();
+    let expired: String = login
+        .lines()
+        .map(|line| match line.starts_with("expires_after") {
+            true => "expires_after = 1".to_string(),
+            false => line.replace(ACCESS_TOKEN, "expired"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(home.credentials(), expired).unwrap();
+
+    let output = home.tdl(&["search", "-f", "artist", "mock"]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_to("/v1/oauth2/token").len(), 1);
+    let credentials = std::fs::read_to_string(home.credentials()).unwrap();
+    assert!(credentials.contains(ACCESS_TOKEN), "{credentials}");
+}
+
+#[tokio::test]
+async fn logs_out() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home.tdl(&["logout"]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_to("/v1/logout").len(), 1);
+    assert!(!home.credentials().exists());
+
+    let whoami = home.tdl(&["whoami"]).await;
+    assert_eq!(whoami.status.code(), Some(3), "{}", stderr(&whoami));
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use common::{files_with_extension, stderr, stdout, Home, MockTidal};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tdl::download::read_track_id;
+
+const ALBUM_URL: &str = "https://tidal.com/browse/album/100";
+
+/// The path of each track in the archive
+fn archived_paths(home: &Home) -> HashMap<usize, PathBuf> {
+    let archive = std::fs::read_to_string(home.config_dir().join("archive.jsonl")).unwrap();
+    archive
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|entry| {
+            let id = entry["track_id"].as_u64().unwrap() as usize;
+            (id, PathBuf::from(entry["path"].as_str().unwrap()))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn moves_archive_entries_with_the_files() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let get = home.tdl(&["get", ALBUM_URL]).await;
+    assert!(get.status.success(), "{}", stderr(&get));
+    let before = archived_paths(&home);
+    std::fs::write(
+        home.config_dir().join("config.toml"),
+        "[download_paths]\ntrack = \"{track_name}\"\n",
+    )
+    .unwrap();
+    let music = home.music().display().to_string();
+
+    let output = home.tdl(&["relayout", &music]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("Moved 3 tracks"),
+        "{}",
+        stdout(&output)
+    );
+    let after = archived_paths(&home);
+    assert_eq!(after.len(), 3);
+    for (id, path) in &after {
+        assert_ne!(path, &before[id]);
+        assert_eq!(read_track_id(path).await, Some(*id), "{}", path.display());
+    }
+
+    let journal = std::fs::read_dir(home.music())
+        .unwrap()
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.to_string_lossy().ends_with(".jsonl"))
+        .expect("a journal is written");
+    let rollback = home
+        .tdl(&["relayout", "--rollback", &journal.display().to_string()])
+        .await;
+
+    assert!(rollback.status.success(), "{}", stderr(&rollback));
+    assert_eq!(archived_paths(&home), before);
+}
+
+#[tokio::test]
+async fn swaps_files_that_take_each_others_paths() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let get = home.tdl(&["get", ALBUM_URL]).await;
+    assert!(get.status.success(), "{}", stderr(&get));
+    let mut files = files_with_extension(&home.music(), "flac");
+    files.sort();
+    // each file is where the other one belongs
+    let aside = files[0].with_extension("swap");
+    std::fs::rename(&files[0], &aside).unwrap();
+    std::fs::rename(&files[1], &files[0]).unwrap();
+    std::fs::rename(&aside, &files[1]).unwrap();
+    let first = read_track_id(&files[1]).await;
+    let music = home.music().display().to_string();
+
+    let output = home.tdl(&["relayout", &music]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("Skipped"), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("Moved 2 tracks"),
+        "{}",
+        stdout(&output)
+    );
+    assert_eq!(read_track_id(&files[0]).await, first);
+    assert_eq!(files_with_extension(&home.music(), "flac").len(), 3);
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use common::{Home, MockTidal, IMAGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tdl::api::models::CoverSize;
+use tdl::api::scheduler::{RequestScheduler, MAX_RATE_LIMITED_RETRIES};
+use tokio::time::{Duration, Instant};
+
+/// Answers the first `limited` requests with a 429, and the rest with a 200.
+/// Records when each request arrived.
+struct MockServer {
+    addr: SocketAddr,
+    hits: Arc<Mutex<Vec<Instant>>>,
+}
+
+impl MockServer {
+    fn start(limited: usize, retry_after: Option<u64>) -> Self {
+        let hits: Arc<Mutex<Vec<Instant>>> = Arc::default();
+        let recorded = hits.clone();
+        let make_service = make_service_fn(move |_| {
+            let hits = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                    let hits = hits.clone();
+                    async move {
+                        let count = {
+                            let mut hits = hits.lock().unwrap();
+                            hits.push(Instant::now());
+                            hits.len()
+                        };
+                        let mut res = Response::builder();
+                        if count <= limited {
+                            res = res.status(StatusCode::TOO_MANY_REQUESTS);
+                            if let Some(retry_after) = retry_after {
+                                res = res.header("Retry-After", retry_after.to_string());
+                            }
+                        }
+                        Ok::<_, Infallible>(res.body(Body::from("{}")).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Self { addr, hits }
+    }
+
+    fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    fn hits(&self) -> Vec<Instant> {
+        self.hits.lock().unwrap().clone()
+    }
+}
+
+async fn send(
+    scheduler: &RequestScheduler,
+    client: &reqwest::Client,
+    url: &str,
+) -> reqwest::Response {
+    scheduler
+        .send(|| client.get(url).send())
+        .await
+        .expect("request failed")
+}
+
+#[tokio::test]
+async fn honors_retry_after() {
+    let server = MockServer::start(1, Some(1));
+    let scheduler = RequestScheduler::new();
+    let client = reqwest::Client::new();
+
+    let res = send(&scheduler, &client, &server.url()).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let hits = server.hits();
+    assert_eq!(hits.len(), 2);
+    assert!(hits[1] - hits[0] >= Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn backs_off_every_request() {
+    let server = MockServer::start(1, Some(1));
+    let scheduler = Arc::new(RequestScheduler::new());
+    let client = reqwest::Client::new();
+
+    let first = {
+        let (scheduler, client, url) = (scheduler.clone(), client.clone(), server.url());
+        tokio::spawn(async move { send(&scheduler, &client, &url).await.status() })
+    };
+    // wait for the first request to be limited, then start another
+    while server.hits().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let second = send(&scheduler, &client, &server.url()).await;
+
+    assert_eq!(first.await.unwrap(), StatusCode::OK);
+    assert_eq!(second.status(), StatusCode::OK);
+    let hits = server.hits();
+    assert_eq!(hits.len(), 3);
+    // the second request waited for the pause, even though it was never limited itself
+    for hit in &hits[1..] {
+        assert!(*hit - hits[0] >= Duration::from_secs(1));
+    }
+}
+
+#[tokio::test]
+async fn returns_429_after_retries() {
+    let server = MockServer::start(usize::MAX, Some(0));
+    let scheduler = RequestScheduler::new();
+    let client = reqwest::Client::new();
+
+    let res = send(&scheduler, &client, &server.url()).await;
+
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(server.hits().len(), MAX_RATE_LIMITED_RETRIES as usize + 1);
+}
+
+#[tokio::test]
+async fn reports_throttling() {
+    let server = MockServer::start(1, Some(1));
+    let scheduler = RequestScheduler::new();
+    let client = reqwest::Client::new();
+    let reported: Arc<Mutex<Vec<Duration>>> = Arc::default();
+    let report = reported.clone();
+    let _listener = scheduler.on_throttle(move |wait| report.lock().unwrap().push(wait));
+
+    send(&scheduler, &client, &server.url()).await;
+
+    assert_eq!(*reported.lock().unwrap(), vec![Duration::from_secs(1)]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn reports_throttling_to_every_listener() {
+    let scheduler = RequestScheduler::new();
+    let first: Arc<Mutex<Vec<Duration>>> = Arc::default();
+    let second: Arc<Mutex<Vec<Duration>>> = Arc::default();
+    let report = first.clone();
+    let _first = scheduler.on_throttle(move |wait| report.lock().unwrap().push(wait));
+    let report = second.clone();
+    let listener = scheduler.on_throttle(move |wait| report.lock().unwrap().push(wait));
+
+    scheduler.rate_limited(Some(Duration::from_secs(1)));
+    scheduler.ready().await;
+    // stops listening after the first pause
+    drop(listener);
+    scheduler.rate_limited(Some(Duration::from_secs(2)));
+
+    assert_eq!(
+        *first.lock().unwrap(),
+        vec![Duration::from_secs(1), Duration::from_secs(2)]
+    );
+    assert_eq!(*second.lock().unwrap(), vec![Duration::from_secs(1)]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn doubles_backoff_without_retry_after() {
+    let scheduler = RequestScheduler::new();
+
+    assert_eq!(scheduler.rate_limited(None), Duration::from_secs(1));
+    // limited again while paused, by a request that was already sent
+    assert_eq!(scheduler.rate_limited(None), Duration::from_secs(1));
+    scheduler.ready().await;
+    assert_eq!(scheduler.rate_limited(None), Duration::from_secs(2));
+    scheduler.ready().await;
+    assert_eq!(scheduler.rate_limited(None), Duration::from_secs(4));
+}
+
+#[tokio::test]
+async fn retries_rate_limited_images() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    server.rate_limit_images(1);
+    let tdl = home.client();
+    let throttled: Arc<Mutex<Vec<Duration>>> = Arc::default();
+    let report = throttled.clone();
+    let _listener = tdl
+        .client()
+        .media
+        .scheduler()
+        .on_throttle(move |wait| report.lock().unwrap().push(wait));
+
+    let cover = tdl
+        .client()
+        .media
+        .get_cover_data("aaaa-bbbb", CoverSize::Px640)
+        .await
+        .unwrap();
+
+    assert_eq!(cover.data, IMAGE);
+    assert_eq!(server.requests_to("/resources").len(), 2);
+    // the other requests waited too
+    assert_eq!(*throttled.lock().unwrap(), vec![Duration::ZERO]);
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::{stderr, stdout, Home, MockTidal};
+
+#[tokio::test]
+async fn searches_every_page() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home.tdl(&["search", "-f", "album", "mock"]).await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let table = stdout(&output);
+    for title in ["Mock Album", "Mock Single", "Mock Live"] {
+        assert!(table.contains(title), "{table}");
+    }
+    let pages = server.requests_to("/v1/search/albums");
+    assert_eq!(pages.len(), 2);
+    assert_eq!(
+        pages[0].query.get("query").map(String::as_str),
+        Some("mock")
+    );
+}
+
+#[tokio::test]
+async fn stops_at_max() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let output = home
+        .tdl(&["search", "-f", "album", "--max", "1", "mock"])
+        .await;
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let table = stdout(&output);
+    assert!(table.contains("Mock Album"), "{table}");
+    assert!(!table.contains("Mock Single"), "{table}");
+    assert_eq!(server.requests_to("/v1/search/albums").len(), 1);
+}
+
+#[tokio::test]
+async fn searches_tracks_and_artists() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+
+    let tracks = home.tdl(&["search", "-f", "track", "second"]).await;
+    let artists = home.tdl(&["search", "-f", "artist", "mock"]).await;
+
+    assert!(tracks.status.success(), "{}", stderr(&tracks));
+    assert!(
+        stdout(&tracks).contains("Second Song"),
+        "{}",
+        stdout(&tracks)
+    );
+    assert!(
+        !stdout(&tracks).contains("First Song"),
+        "{}",
+        stdout(&tracks)
+    );
+    assert!(artists.status.success(), "{}", stderr(&artists));
+    assert!(
+        stdout(&artists).contains("Mock Artist"),
+        "{}",
+        stdout(&artists)
+    );
+}
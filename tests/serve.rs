@@ -0,0 +1,234 @@
+mod common;
+
+use common::{files_with_extension, Home, MockTidal};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tdl::client::Tdl;
+use tdl::serve::{exposure, serve, JobQueue, JobState, MAX_FINISHED_JOBS};
+use tokio::task::JoinHandle;
+
+const ALBUM_URL: &str = "https://tidal.com/browse/album/100";
+
+/// Serves the jobs saved in `home` on a free port
+fn start(home: &Home) -> (SocketAddr, JoinHandle<()>) {
+    start_with(home, home.client())
+}
+
+fn start_with(home: &Home, tdl: Tdl) -> (SocketAddr, JoinHandle<()>) {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let jobs = JobQueue::open(&home.path().join("jobs.json")).unwrap();
+    let server = serve(Arc::new(tdl), Arc::new(jobs), addr).unwrap();
+    (addr, server)
+}
+
+/// Polls a job until it's no longer queued or running
+async fn finished(addr: SocketAddr, id: u64) -> Value {
+    for _ in 0..100 {
+        let job: Value = reqwest::get(format!("http://{addr}/jobs/{id}"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if job["state"] != "queued" && job["state"] != "running" {
+            return job;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("job {id} didn't finish");
+}
+
+#[tokio::test]
+async fn downloads_submitted_jobs() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let (addr, serving) = start(&home);
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(format!("http://{addr}/jobs"))
+        .json(&json!({ "urls": [ALBUM_URL] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let job: Value = res.json().await.unwrap();
+    assert_eq!(job["id"], 1);
+
+    let job = finished(addr, 1).await;
+    assert_eq!(job["state"], "completed", "{job}");
+    assert_eq!(job["tracks"], 3, "{job}");
+    assert_eq!(job["downloaded"], 3, "{job}");
+    assert_eq!(files_with_extension(&home.music(), "flac").len(), 3);
+
+    let jobs: Value = reqwest::get(format!("http://{addr}/jobs"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(jobs.as_array().map(Vec::len), Some(1));
+    let page = reqwest::get(format!("http://{addr}/")).await.unwrap();
+    assert!(page.text().await.unwrap().contains("<form"));
+    let metrics = reqwest::get(format!("http://{addr}/metrics"))
+        .await
+        .unwrap();
+    assert!(metrics
+        .text()
+        .await
+        .unwrap()
+        .contains("tdl_tracks_downloaded_total 3"));
+    serving.abort();
+}
+
+#[tokio::test]
+async fn rejects_invalid_jobs() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let (addr, serving) = start(&home);
+    let client = reqwest::Client::new();
+
+    for body in [
+        json!({ "urls": [] }),
+        json!({ "urls": ["not a url"] }),
+        json!({ "url": ALBUM_URL }),
+    ] {
+        let res = client
+            .post(format!("http://{addr}/jobs"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 400, "{body}");
+        let error: Value = res.json().await.unwrap();
+        assert!(error["error"].is_string(), "{error}");
+    }
+    let form = client
+        .post(format!("http://{addr}/jobs"))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(json!({ "urls": [ALBUM_URL] }).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(form.status(), 415);
+    let missing = reqwest::get(format!("http://{addr}/jobs/9")).await.unwrap();
+    assert_eq!(missing.status(), 404);
+    serving.abort();
+}
+
+#[tokio::test]
+async fn requires_the_token() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    let mut settings = home.settings();
+    settings.serve_token = Some("secret".to_string());
+    let (addr, serving) = start_with(&home, home.client_with(settings));
+    let client = reqwest::Client::new();
+
+    let page = client.get(format!("http://{addr}/")).send().await.unwrap();
+    assert_eq!(page.status(), 200);
+    for token in [None, Some("wrong"), Some("secre")] {
+        let mut req = client
+            .post(format!("http://{addr}/jobs"))
+            .json(&json!({ "urls": [ALBUM_URL] }));
+        if let Some(token) = token {
+            req = req.bearer_auth(token);
+        }
+        let res = req.send().await.unwrap();
+        assert_eq!(res.status(), 401, "{token:?}");
+    }
+    let metrics = client
+        .get(format!("http://{addr}/metrics"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(metrics.status(), 401);
+
+    let res = client
+        .post(format!("http://{addr}/jobs"))
+        .bearer_auth("secret")
+        .json(&json!({ "urls": [ALBUM_URL] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    serving.abort();
+}
+
+#[test]
+fn warns_about_other_machines() {
+    let local: SocketAddr = "127.0.0.1:8686".parse().unwrap();
+    let network: SocketAddr = "0.0.0.0:8686".parse().unwrap();
+    assert_eq!(exposure(local, None), None);
+    assert!(exposure(network, None).unwrap().contains("Anyone"));
+    assert!(exposure(network, Some("secret")).is_some());
+}
+
+#[tokio::test]
+async fn cancels_jobs() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    let path = home.path().join("jobs.json");
+    // nothing downloads the jobs, so they stay queued
+    let jobs = JobQueue::open(&path).unwrap();
+    let job = jobs.submit(vec![ALBUM_URL.to_string()], false).unwrap();
+
+    let cancelled = jobs.cancel(job.id).unwrap();
+
+    assert_eq!(cancelled.state, JobState::Cancelled);
+    assert!(jobs.cancel(job.id).is_err());
+    let saved = JobQueue::open(&path).unwrap();
+    assert_eq!(saved.job(job.id).unwrap().state, JobState::Cancelled);
+}
+
+#[tokio::test]
+async fn keeps_the_last_finished_jobs() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    let path = home.path().join("jobs.json");
+    let jobs = JobQueue::open(&path).unwrap();
+    for _ in 0..MAX_FINISHED_JOBS + 2 {
+        let job = jobs.submit(vec![ALBUM_URL.to_string()], false).unwrap();
+        jobs.cancel(job.id).unwrap();
+    }
+    let queued = jobs.submit(vec![ALBUM_URL.to_string()], false).unwrap();
+
+    let saved = JobQueue::open(&path).unwrap().jobs();
+    assert_eq!(saved.len(), MAX_FINISHED_JOBS + 1);
+    assert_eq!(saved[0].id, 3);
+    assert_eq!(saved.last().map(|job| job.id), Some(queued.id));
+}
+
+#[tokio::test]
+async fn resumes_jobs_after_a_restart() {
+    let server = MockTidal::start();
+    let home = Home::new(&server);
+    home.login();
+    // a job tdl was stopped in the middle of
+    let saved = json!([{
+        "id": 4,
+        "urls": [ALBUM_URL],
+        "state": "running",
+        "submitted_at": 0,
+        "started_at": 0,
+        "finished_at": null,
+        "tracks": 3,
+        "downloaded": 1,
+    }]);
+    std::fs::write(home.path().join("jobs.json"), saved.to_string()).unwrap();
+
+    let (addr, serving) = start(&home);
+
+    let job = finished(addr, 4).await;
+    assert_eq!(job["state"], "completed", "{job}");
+    assert_eq!(job["downloaded"], 3, "{job}");
+    serving.abort();
+}